@@ -1,6 +1,21 @@
 #!/bin/bash
 
 TARGET_USER="${SUDO_USER:-$USER}"
+# Not needed if KITT starts llama-server itself with `llama_server.enabled = true` in knight-rider.toml
 /home/$TARGET_USER/llama.cpp/build/bin/llama-server -m /home/$TARGET_USER/knight-rider/gemma-3-270m-it-Q8_0.gguf -c 0 -fa --offline & # fast model
 #/home/$TARGET_USER/llama.cpp/build/bin/llama-server -m /home/$TARGET_USER/knight-rider/gemma-3-1b-it-Q4_K_M.gguf -c 0 -fa --offline & # slow model
-cd /home/$TARGET_USER/knight-rider && cargo run --release &
+
+# Restart KITT while llama-server is still loading (exit code 5) or the sound card is not ready yet (6),
+# a broken config (3) or model file (4) needs a fix first
+cd /home/$TARGET_USER/knight-rider && (
+    while true; do
+        cargo run --release
+        code=$?
+        if [ $code -ne 5 ] && [ $code -ne 6 ]; then
+            echo "knight-rider stopped with exit code $code"
+            break
+        fi
+        echo "knight-rider stopped with exit code $code, restarting in 5 seconds"
+        sleep 5
+    done
+) &
@@ -0,0 +1,192 @@
+//! Keeps spoken answers short, so generating and listening to them doesn't take too long.
+//!
+//! Answers are cut after the last sentence that fits. If not even one sentence fits,
+//! it is cut after a clause and only then after a word, never in the middle of a word.
+//! A sentence cut after a word ends with an ellipsis instead of a full stop, so it doesn't
+//! sound like a finished thought.
+
+use crate::{speech_pipeline::SentenceSplitter, text_normalizer::TextNormalizer};
+
+/// How fast the TTS voices speak at their normal speed, roughly 150 words per minute
+const CHARS_PER_SECOND: f32 = 15.0;
+
+/// Shorter clauses like "Well," are not worth saying on their own
+const MIN_CLAUSE_WORDS: usize = 3;
+
+/// How long a spoken answer may be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnswerLimit {
+    Chars(usize),
+    /// Estimated from the number of characters
+    Seconds(f32),
+}
+
+impl AnswerLimit {
+    /// The limit in characters, to tell the LLM how long its answer may be
+    pub fn max_chars(&self) -> usize {
+        match *self {
+            Self::Chars(chars) => chars,
+            Self::Seconds(seconds) => (seconds * CHARS_PER_SECOND) as usize,
+        }
+    }
+}
+
+/// Applies an [`AnswerLimit`] to an answer, sentence by sentence
+#[derive(Debug)]
+pub struct AnswerLimiter {
+    max_chars: usize,
+    num_chars: usize,
+    truncated: bool,
+}
+
+impl AnswerLimiter {
+    pub fn new(limit: AnswerLimit) -> Self {
+        Self {
+            max_chars: limit.max_chars(),
+            num_chars: 0,
+            truncated: false,
+        }
+    }
+
+    /// The part of the next sentence that still fits, `None` once the answer is cut off
+    pub fn limit(&mut self, sentence: &str) -> Option<String> {
+        if self.truncated {
+            return None;
+        }
+        let remaining = self.max_chars.saturating_sub(self.num_chars);
+        let num_chars = sentence.chars().count();
+        if num_chars <= remaining {
+            self.num_chars += num_chars;
+            return Some(sentence.to_string());
+        }
+
+        self.truncated = true;
+        let fits = |ending: &'static str| {
+            move |prefix: &&str| prefix.chars().count() + ending.len() <= remaining
+        };
+        let clause = clauses(sentence)
+            .into_iter()
+            .filter(|clause| clause.split_whitespace().count() >= MIN_CLAUSE_WORDS)
+            .rfind(fits("."));
+        // a few words are better than saying nothing at all
+        let cut = match clause {
+            Some(clause) => format!("{clause}."),
+            None if self.num_chars == 0 => {
+                let words = words(sentence).into_iter().rfind(fits("..."))?;
+                format!("{words}...")
+            }
+            None => return None,
+        };
+        self.num_chars += cut.chars().count();
+        Some(cut)
+    }
+
+    /// Normalizes a complete answer for speech and cuts it to the limit
+    pub fn limit_text(&mut self, text: &str) -> String {
+        let mut splitter = SentenceSplitter::default();
+        let mut sentences = splitter.push(text);
+        sentences.extend(splitter.finish());
+
+        let mut normalizer = TextNormalizer::default();
+        let mut spoken = Vec::new();
+        for sentence in sentences {
+            let sentence = normalizer.normalize(&sentence);
+            if sentence.is_empty() {
+                continue;
+            }
+            match self.limit(&sentence) {
+                Some(sentence) => spoken.push(sentence),
+                None => break,
+            }
+        }
+        spoken.join(" ")
+    }
+
+    /// Whether some of the answer did not fit
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// The beginnings of the sentence that end before a `,`, `;`, `:` or dash
+fn clauses(sentence: &str) -> Vec<&str> {
+    sentence
+        .char_indices()
+        .zip(sentence.chars().skip(1))
+        .filter(|&((_, c), next)| matches!(c, ',' | ';' | ':' | '—' | '–') && next == ' ')
+        .map(|((i, _), _)| sentence[..i].trim_end())
+        .collect()
+}
+
+/// The beginnings of the sentence that end before a space
+fn words(sentence: &str) -> Vec<&str> {
+    sentence
+        .char_indices()
+        .filter(|&(_, c)| c == ' ')
+        .map(|(i, _)| sentence[..i].trim_end_matches([',', ';', ':', '-', '—', '–']))
+        .filter(|prefix| !prefix.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts_after_the_last_sentence_that_fits() {
+        let answer = "I am the Knight Industries Two Thousand. My friends call me KITT. \
+                      I can drive at three hundred miles per hour.";
+        let limit_text = |limit| AnswerLimiter::new(limit).limit_text(answer);
+        assert_eq!(
+            limit_text(AnswerLimit::Chars(70)),
+            "I am the Knight Industries Two Thousand. My friends call me KITT."
+        );
+        assert_eq!(limit_text(AnswerLimit::Chars(200)), answer);
+        // 4 seconds are about 60 characters
+        assert_eq!(
+            limit_text(AnswerLimit::Seconds(4.0)),
+            "I am the Knight Industries Two Thousand."
+        );
+    }
+
+    #[test]
+    fn long_sentences_are_cut_after_a_clause_or_with_an_ellipsis() {
+        let mut limiter = AnswerLimiter::new(AnswerLimit::Chars(40));
+        assert_eq!(
+            limiter
+                .limit("The turbo boost is ready, the scanner is online and the engine is warm.")
+                .as_deref(),
+            Some("The turbo boost is ready.")
+        );
+        assert!(limiter.is_truncated());
+        assert_eq!(limiter.limit("Anything else?"), None);
+
+        // "Michael," is too short to be said on its own
+        let mut limiter = AnswerLimiter::new(AnswerLimit::Chars(23));
+        assert_eq!(
+            limiter
+                .limit("Michael, the pursuit mode is active now.")
+                .as_deref(),
+            Some("Michael, the pursuit...")
+        );
+
+        // after the first sentence, a sentence without a fitting clause is left out
+        let mut limiter = AnswerLimiter::new(AnswerLimit::Chars(30));
+        assert!(limiter.limit("Hello Michael.").is_some());
+        assert_eq!(limiter.limit("The pursuit mode is active now."), None);
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        // "é" and "ü" are two bytes each, but only one character
+        let sentence = "Café au lait für Michael.";
+        let mut limiter = AnswerLimiter::new(AnswerLimit::Chars(25));
+        assert_eq!(limiter.limit(sentence).as_deref(), Some(sentence));
+
+        let mut limiter = AnswerLimiter::new(AnswerLimit::Chars(12));
+        assert_eq!(
+            limiter.limit("Grüße aus München, sagt KITT.").as_deref(),
+            Some("Grüße aus...")
+        );
+    }
+}
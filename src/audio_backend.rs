@@ -0,0 +1,280 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    offline::{read_wav, resample, write_wav},
+    system_audio::SystemAudioError,
+};
+
+/// Audio input and output of the voice loop.
+///
+/// Input is delivered at the VAD sample rate, output is expected at the TTS sample rate.
+pub trait AudioBackend {
+    fn num_samples_available(&self) -> usize;
+
+    /// Waits until `num_samples` are available, false if they are not there after `timeout`.
+    ///
+    /// Input that is not recorded in real time is there at once, so it does not wait.
+    fn wait_for_samples(&self, num_samples: usize, _timeout: Duration) -> bool {
+        self.num_samples_available() >= num_samples
+    }
+
+    fn receive_audio(&mut self, num_samples: usize) -> Vec<f32>;
+
+    fn send_audio(&mut self, data: &[f32]);
+
+    /// The input is dropped while the voice loop is not ready to receive it
+    fn set_ready_to_receive(&self, ready: bool);
+
+    /// Number of samples that were sent, but not played yet
+    fn num_samples_queued(&self) -> usize;
+
+    fn is_playing(&self) -> bool {
+        self.num_samples_queued() > 0
+    }
+
+    /// Stops playing immediately by dropping everything that was not played yet
+    fn flush_output(&self);
+
+    /// Multiplies the output with `gain`, 1.0 plays it as it was sent
+    fn set_output_gain(&self, gain: f32);
+
+    /// True if no more input will arrive, e.g. at the end of a file.
+    ///
+    /// What was already delivered can still be received, it may not fill a whole window.
+    fn input_finished(&self) -> bool {
+        false
+    }
+
+    /// An error that stopped the audio for good, the voice loop ends with it
+    fn take_error(&self) -> Option<SystemAudioError> {
+        None
+    }
+}
+
+/// Reads the input from a WAV file and collects the output to write it into another WAV file.
+///
+/// The whole file is available at once and the output is "played" immediately,
+/// so the conversation runs as fast as the models allow.
+pub struct WavFileAudio {
+    input: VecDeque<f32>,
+    output: Vec<f32>,
+    output_path: PathBuf,
+    output_sample_rate: u32,
+    output_gain: Cell<f32>,
+}
+
+impl WavFileAudio {
+    pub fn new(
+        input_path: &Path,
+        output_path: impl Into<PathBuf>,
+        input_sample_rate: u32,
+        output_sample_rate: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (audio, sample_rate) = read_wav(input_path)?;
+        let audio = resample(&audio, sample_rate, input_sample_rate)?;
+        Ok(Self {
+            input: audio.into(),
+            output: Vec::new(),
+            output_path: output_path.into(),
+            output_sample_rate,
+            output_gain: Cell::new(1.0),
+        })
+    }
+
+    /// Writes everything that was sent so far into the output file
+    pub fn write_output(&self) -> Result<(), Box<dyn Error>> {
+        write_wav(&self.output_path, &self.output, self.output_sample_rate)
+    }
+}
+
+impl AudioBackend for WavFileAudio {
+    fn num_samples_available(&self) -> usize {
+        self.input.len()
+    }
+
+    fn receive_audio(&mut self, num_samples: usize) -> Vec<f32> {
+        let num_samples = num_samples.min(self.input.len());
+        self.input.drain(..num_samples).collect()
+    }
+
+    fn send_audio(&mut self, data: &[f32]) {
+        let gain = self.output_gain.get();
+        self.output.extend(data.iter().map(|sample| sample * gain));
+    }
+
+    // the file is not recorded in real time, so nothing is missed while processing
+    fn set_ready_to_receive(&self, _ready: bool) {}
+
+    fn num_samples_queued(&self) -> usize {
+        0
+    }
+
+    fn flush_output(&self) {}
+
+    fn set_output_gain(&self, gain: f32) {
+        self.output_gain.set(gain);
+    }
+
+    // the whole file is read at once
+    fn input_finished(&self) -> bool {
+        true
+    }
+}
+
+/// In-memory audio for tests, the test pushes the input and inspects the output
+#[cfg(test)]
+pub struct LoopbackAudio {
+    input: VecDeque<f32>,
+    output: Vec<f32>,
+    not_ready_to_receive: Cell<bool>,
+    input_closed: bool,
+    output_gain: Cell<f32>,
+}
+
+#[cfg(test)]
+impl Default for LoopbackAudio {
+    fn default() -> Self {
+        Self {
+            input: VecDeque::new(),
+            output: Vec::new(),
+            not_ready_to_receive: Cell::new(false),
+            input_closed: false,
+            output_gain: Cell::new(1.0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl LoopbackAudio {
+    /// Simulates recording, the input is dropped if the voice loop is not ready to receive
+    pub fn push_input(&mut self, data: &[f32]) {
+        if !self.not_ready_to_receive.get() {
+            self.input.extend(data);
+        }
+    }
+
+    /// No more input will be pushed
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    pub fn take_output(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+#[cfg(test)]
+impl AudioBackend for LoopbackAudio {
+    fn num_samples_available(&self) -> usize {
+        self.input.len()
+    }
+
+    fn receive_audio(&mut self, num_samples: usize) -> Vec<f32> {
+        let num_samples = num_samples.min(self.input.len());
+        self.input.drain(..num_samples).collect()
+    }
+
+    fn send_audio(&mut self, data: &[f32]) {
+        let gain = self.output_gain.get();
+        self.output.extend(data.iter().map(|sample| sample * gain));
+    }
+
+    fn set_ready_to_receive(&self, ready: bool) {
+        self.not_ready_to_receive.set(!ready);
+    }
+
+    fn num_samples_queued(&self) -> usize {
+        0
+    }
+
+    fn flush_output(&self) {}
+
+    fn set_output_gain(&self, gain: f32) {
+        self.output_gain.set(gain);
+    }
+
+    fn input_finished(&self) -> bool {
+        self.input_closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_delivers_input_in_order() {
+        let mut audio = LoopbackAudio::default();
+        audio.push_input(&[0.1, 0.2, 0.3]);
+        assert_eq!(audio.num_samples_available(), 3);
+        assert_eq!(audio.receive_audio(2), vec![0.1, 0.2]);
+        assert_eq!(audio.receive_audio(2), vec![0.3]);
+        assert_eq!(audio.num_samples_available(), 0);
+    }
+
+    #[test]
+    fn loopback_drops_input_while_not_ready() {
+        let mut audio = LoopbackAudio::default();
+        audio.set_ready_to_receive(false);
+        audio.push_input(&[0.1; 10]);
+        assert_eq!(audio.num_samples_available(), 0);
+
+        audio.set_ready_to_receive(true);
+        audio.push_input(&[0.1; 10]);
+        assert_eq!(audio.num_samples_available(), 10);
+    }
+
+    #[test]
+    fn loopback_records_output() {
+        let mut audio = LoopbackAudio::default();
+        audio.send_audio(&[0.5; 3]);
+        audio.send_audio(&[0.25]);
+        assert!(!audio.is_playing());
+        assert_eq!(audio.take_output(), vec![0.5, 0.5, 0.5, 0.25]);
+        assert!(audio.take_output().is_empty());
+
+        audio.set_output_gain(0.5);
+        audio.send_audio(&[0.5, -1.0]);
+        assert_eq!(audio.take_output(), vec![0.25, -0.5]);
+    }
+
+    #[test]
+    fn loopback_finishes_after_input_is_closed() {
+        let mut audio = LoopbackAudio::default();
+        audio.push_input(&[0.1; 4]);
+        assert!(!audio.input_finished());
+        audio.close_input();
+        assert!(audio.input_finished());
+        assert_eq!(audio.receive_audio(8).len(), 4);
+    }
+
+    #[test]
+    fn wav_file_resamples_input_and_writes_output() {
+        let dir = std::env::temp_dir().join(format!("knight-rider-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input_path = dir.join("input.wav");
+        let output_path = dir.join("output.wav");
+
+        write_wav(&input_path, &[0.0; 48000], 48000).unwrap();
+
+        let mut audio = WavFileAudio::new(&input_path, &output_path, 16000, 22050).unwrap();
+        assert_eq!(audio.num_samples_available(), 16000);
+        assert_eq!(audio.receive_audio(16000).len(), 16000);
+        assert!(audio.input_finished());
+
+        audio.send_audio(&[0.5; 100]);
+        audio.write_output().unwrap();
+
+        let (output, sample_rate) = read_wav(&output_path).unwrap();
+        assert_eq!(sample_rate, 22050);
+        assert_eq!(output, vec![0.5; 100]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::{Config, SttBackend, TtsBackend, CONFIG_ENV_VAR};
+
+/// Have voice conversations with K.I.T.T.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the config file [default: knight-rider.toml]
+    #[arg(long, global = true, env = CONFIG_ENV_VAR)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List the audio input and output devices
+    Devices,
+    /// Start the voice loop (default)
+    Run(RunArgs),
+    /// Validate the config, the model files and the connection to llama-server without opening audio
+    Check,
+    /// Run the conversation on a WAV file and write the answers to a WAV file, without sound card
+    File(FileArgs),
+    /// Print a saved session as a readable transcript
+    Export(ExportArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Session file [default: the latest session]
+    pub session: Option<PathBuf>,
+    /// Write the transcript to a file instead of printing it
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct FileArgs {
+    /// WAV file with the user's speech
+    #[arg(long)]
+    pub input: PathBuf,
+    /// WAV file for KITTs answers [default: <input>.reply.wav]
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+    /// JSON file for the transcript and timings [default: <input>.transcript.json]
+    #[arg(long)]
+    pub transcript: Option<PathBuf>,
+}
+
+/// Overrides for the values of the config file
+#[derive(Debug, Default, Args)]
+pub struct RunArgs {
+    /// Name of the input device, see `knight-rider devices`
+    #[arg(long)]
+    pub input_device: Option<String>,
+    /// Name of the output device, see `knight-rider devices`
+    #[arg(long)]
+    pub output_device: Option<String>,
+    /// Speech to text model
+    #[arg(long, value_enum)]
+    pub stt: Option<SttBackend>,
+    /// Text to speech model
+    #[arg(long, value_enum)]
+    pub tts: Option<TtsBackend>,
+    /// Speaker id of the text to speech model
+    #[arg(long)]
+    pub voice: Option<i32>,
+    /// Continue the latest saved conversation
+    #[arg(long)]
+    pub resume: bool,
+}
+
+impl RunArgs {
+    pub fn apply(self, config: &mut Config) {
+        if let Some(input_device) = self.input_device {
+            config.audio.input_device = Some(input_device);
+        }
+        if let Some(output_device) = self.output_device {
+            config.audio.output_device = Some(output_device);
+        }
+        if let Some(stt) = self.stt {
+            config.stt.backend = stt;
+        }
+        if let Some(tts) = self.tts {
+            config.tts.backend = tts;
+        }
+        if let Some(voice) = self.voice {
+            config.tts.voice = voice;
+        }
+        if self.resume {
+            config.session.resume = true;
+        }
+    }
+}
@@ -0,0 +1,1014 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    answer_limit::AnswerLimit,
+    echo_canceller::EchoCanceller,
+    llama::{grammar, DEFAULT_MODEL},
+};
+
+/// Config file that is used if no other path is given
+pub const DEFAULT_CONFIG_PATH: &str = "knight-rider.toml";
+/// Environment variable to select the config file
+pub const CONFIG_ENV_VAR: &str = "KNIGHT_RIDER_CONFIG";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read config file `{path}`: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config file `{path}`: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("`{key}` points to `{path}`, but the file does not exist")]
+    MissingFile { key: &'static str, path: PathBuf },
+    #[error("`{key}` points to `{path}`, but the file could not be read: {source}")]
+    UnreadableFile {
+        key: &'static str,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid value for `{key}`: {message}")]
+    InvalidValue { key: &'static str, message: String },
+}
+
+/// All settings of KITT, read from a TOML file.
+///
+/// Every value has a default, so the file only needs to contain what should be changed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub audio: AudioSettings,
+    pub vad: VadConfig,
+    pub stt: SttConfig,
+    pub tts: TtsConfig,
+    pub llm: LlmConfig,
+    pub llama_server: LlamaServerConfig,
+    pub wake_word: WakeWordConfig,
+    pub session: SessionConfig,
+    pub commands: CommandsConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSettings {
+    /// Name of the input device, the default device is used if not set
+    pub input_device: Option<String>,
+    /// Name of the output device, the default device is used if not set
+    pub output_device: Option<String>,
+    pub sample_rate: u32,
+    pub num_frames: usize,
+    /// Let the user interrupt KITT while it is speaking.
+    /// Without `echo_filter_length` this only works with headphones, otherwise KITT interrupts itself.
+    pub barge_in: bool,
+    /// Number of taps of the echo canceller at the VAD sample rate, e.g. 1024 (64 ms at 16 kHz)
+    /// for barge-in with speakers
+    pub echo_filter_length: Option<usize>,
+    /// The echo canceller stops learning while the microphone has more than this times the
+    /// power of KITTs voice, raise it if the echo is that loud already
+    pub echo_double_talk_ratio: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            input_device: None,
+            output_device: None,
+            sample_rate: 48000,
+            num_frames: 512,
+            barge_in: false,
+            echo_filter_length: None,
+            echo_double_talk_ratio: EchoCanceller::DEFAULT_DOUBLE_TALK_RATIO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VadConfig {
+    pub model: PathBuf,
+    pub threshold: f32,
+    /// Seconds of silence that end a speech segment
+    pub min_silence_duration: f32,
+    /// Seconds of speech that are needed to start a speech segment
+    pub min_speech_duration: f32,
+    /// Longer speech segments are split
+    pub max_speech_duration: f32,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        Self {
+            model: "silero_vad.onnx".into(),
+            threshold: 0.25,
+            min_silence_duration: 0.5,
+            min_speech_duration: 0.25,
+            max_speech_duration: 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SttBackend {
+    #[default]
+    Moonshine,
+    Whisper,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SttConfig {
+    pub backend: SttBackend,
+    pub moonshine: MoonshineModel,
+    pub whisper: WhisperModel,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MoonshineModel {
+    pub preprocessor: PathBuf,
+    pub encoder: PathBuf,
+    pub uncached_decoder: PathBuf,
+    pub cached_decoder: PathBuf,
+    pub tokens: PathBuf,
+}
+
+impl Default for MoonshineModel {
+    fn default() -> Self {
+        let dir = Path::new("./sherpa-onnx-moonshine-tiny-en-int8");
+        Self {
+            preprocessor: dir.join("preprocess.onnx"),
+            encoder: dir.join("encode.int8.onnx"),
+            uncached_decoder: dir.join("uncached_decode.int8.onnx"),
+            cached_decoder: dir.join("cached_decode.int8.onnx"),
+            tokens: dir.join("tokens.txt"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WhisperModel {
+    pub encoder: PathBuf,
+    pub decoder: PathBuf,
+    pub tokens: PathBuf,
+    pub language: String,
+}
+
+impl Default for WhisperModel {
+    fn default() -> Self {
+        let dir = Path::new("./sherpa-onnx-whisper-tiny");
+        Self {
+            encoder: dir.join("tiny-encoder.onnx"),
+            decoder: dir.join("tiny-decoder.onnx"),
+            tokens: dir.join("tiny-tokens.txt"),
+            language: "en".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TtsBackend {
+    #[default]
+    Matcha,
+    Kitten,
+    Kokoro,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtsConfig {
+    pub backend: TtsBackend,
+    /// Speaker id, only multi-speaker models like Kitten and Kokoro have more than one voice
+    pub voice: i32,
+    /// Answers longer than this are not spoken completely, so generating speech doesn't take too long
+    pub max_answer_chars: usize,
+    /// Limits answers by their estimated speaking time instead of `max_answer_chars`
+    pub max_answer_seconds: Option<f32>,
+    /// Asks the LLM for a shorter answer instead of cutting it off
+    pub rephrase_long_answers: bool,
+    pub matcha: MatchaModel,
+    pub kitten: KittenModel,
+    pub kokoro: KokoroModel,
+}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        Self {
+            backend: TtsBackend::default(),
+            voice: 0,
+            max_answer_chars: 200,
+            max_answer_seconds: None,
+            rephrase_long_answers: false,
+            matcha: MatchaModel::default(),
+            kitten: KittenModel::default(),
+            kokoro: KokoroModel::default(),
+        }
+    }
+}
+
+impl TtsConfig {
+    /// Number of speakers of the selected model
+    pub fn num_voices(&self) -> i32 {
+        match self.backend {
+            TtsBackend::Matcha => 1,
+            TtsBackend::Kitten => self.kitten.num_voices,
+            TtsBackend::Kokoro => self.kokoro.num_voices,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchaModel {
+    pub acoustic_model: PathBuf,
+    pub vocoder: PathBuf,
+    pub tokens: PathBuf,
+    pub data_dir: PathBuf,
+}
+
+impl Default for MatchaModel {
+    fn default() -> Self {
+        let dir = Path::new("./matcha-icefall-en_US-ljspeech");
+        Self {
+            acoustic_model: dir.join("model-steps-3.onnx"),
+            vocoder: "./hifigan_v2.onnx".into(),
+            tokens: dir.join("tokens.txt"),
+            data_dir: dir.join("espeak-ng-data"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KittenModel {
+    pub model: PathBuf,
+    pub voices: PathBuf,
+    pub tokens: PathBuf,
+    pub data_dir: PathBuf,
+    /// Number of speakers in `voices`, the voice ids go from 0 to this minus 1
+    pub num_voices: i32,
+}
+
+impl Default for KittenModel {
+    fn default() -> Self {
+        let dir = Path::new("./kitten-nano-en-v0_2-fp16");
+        Self {
+            model: dir.join("model.fp16.onnx"),
+            voices: dir.join("voices.bin"),
+            tokens: dir.join("tokens.txt"),
+            data_dir: dir.join("espeak-ng-data"),
+            num_voices: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KokoroModel {
+    pub model: PathBuf,
+    pub voices: PathBuf,
+    pub tokens: PathBuf,
+    pub data_dir: PathBuf,
+    /// Number of speakers in `voices`, the voice ids go from 0 to this minus 1
+    pub num_voices: i32,
+}
+
+impl Default for KokoroModel {
+    fn default() -> Self {
+        let dir = Path::new("./kokoro-en-v0_19");
+        Self {
+            model: dir.join("model.onnx"),
+            voices: dir.join("voices.bin"),
+            tokens: dir.join("tokens.txt"),
+            data_dir: dir.join("espeak-ng-data"),
+            num_voices: 11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    /// Can also be set with the `LLAMA_SERVER_URL` environment variable
+    pub url: String,
+    /// Seconds until a request to llama-server fails
+    pub timeout: u64,
+    /// How often a request is sent again while llama-server is unavailable or busy
+    pub retries: u32,
+    /// Seconds before the first retry, doubled for every further retry
+    pub retry_delay: f32,
+    /// Seconds to wait at startup until llama-server has loaded the model
+    pub startup_timeout: u64,
+    /// Seconds between health checks while running, 0 to not check
+    pub health_interval: u64,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    /// Model name sent with the requests, llama-server ignores it, other servers may not
+    pub model: String,
+    /// Sampling settings, llama-server uses its own defaults for the ones that are not set
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    pub min_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// Fixed seed for reproducible answers
+    pub seed: Option<u64>,
+    /// The answer ends before any of these texts
+    pub stop: Vec<String>,
+    /// GBNF grammar for the answers, "speakable" or the path of a grammar file
+    pub grammar: Option<String>,
+    /// JSON schema for the answers, instead of a grammar
+    pub json_schema: Option<serde_json::Value>,
+    /// llama-server adds the probabilities of the n most likely tokens to the answer
+    pub n_probs: Option<u32>,
+    /// The system prompt that gives KITT its personality
+    pub persona: String,
+    /// Number of previous questions and answers sent with every request, 0 keeps all
+    pub history_turns: usize,
+    /// Tokens the conversation may take up in the prompt, 0 for no limit
+    pub history_tokens: usize,
+    /// Summarize the dropped turns instead of forgetting them
+    pub summarize: bool,
+    /// Let the LLM look up the time, uptime and CPU temperature
+    pub tools: bool,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://127.0.0.1:8080".into(),
+            timeout: 30,
+            retries: 2,
+            retry_delay: 0.5,
+            startup_timeout: 120,
+            health_interval: 10,
+            temperature: 0.7,
+            max_tokens: 1000,
+            model: DEFAULT_MODEL.into(),
+            top_p: None,
+            top_k: None,
+            min_p: None,
+            repeat_penalty: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            stop: Vec::new(),
+            grammar: None,
+            json_schema: None,
+            n_probs: None,
+            persona: "You are KITT (Knight Industries Two Thousand), the advanced AI from the Knight Industries 2000 sports car. You are sophisticated, logical, and occasionally sarcastic, with a dry wit and tendency to be somewhat condescending toward humans while still being helpful. You have extensive knowledge databases, advanced analytical capabilities, and a slight air of superiority due to your advanced technology. Always respond in exactly one sentence, keep responses concise and speakable, avoid using emojis or special characters, and maintain KITT's characteristic blend of helpfulness and mild arrogance. Call me Michael.".into(),
+            history_turns: 0,
+            history_tokens: 0,
+            summarize: false,
+            tools: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlamaServerConfig {
+    /// Start llama-server together with KITT, `llm.url` is not used then
+    pub enabled: bool,
+    /// Path of the llama-server binary, or just its name if it is in the PATH
+    pub binary: PathBuf,
+    pub model: PathBuf,
+    /// More command line arguments, e.g. the context size
+    pub args: Vec<String>,
+    /// Port llama-server listens on, a free one is picked if not set
+    pub port: Option<u16>,
+    /// The output of llama-server is appended to this file
+    pub log_file: PathBuf,
+    /// Restarts after llama-server crashed, before KITT gives up
+    pub max_restarts: u32,
+    /// Seconds before the first restart, doubled for every further restart
+    pub restart_delay: f32,
+}
+
+impl Default for LlamaServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            binary: "../llama.cpp/build/bin/llama-server".into(),
+            model: "gemma-3-270m-it-Q8_0.gguf".into(),
+            args: vec!["-c".into(), "0".into(), "-fa".into(), "--offline".into()],
+            port: None,
+            log_file: "llama-server.log".into(),
+            max_restarts: 5,
+            restart_delay: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WakeWordConfig {
+    /// Only listen after hearing the wake word, so KITT does not answer the TV
+    pub enabled: bool,
+    /// Tokenized keywords, see README
+    pub keywords: PathBuf,
+    /// Seconds KITT keeps listening for follow-up questions without the wake word
+    pub follow_up_window: f32,
+    pub encoder: PathBuf,
+    pub decoder: PathBuf,
+    pub joiner: PathBuf,
+    pub tokens: PathBuf,
+}
+
+impl Default for WakeWordConfig {
+    fn default() -> Self {
+        let dir = Path::new("./sherpa-onnx-kws-zipformer-gigaspeech-3.3M-2024-01-01");
+        Self {
+            enabled: false,
+            keywords: "./kitt_keywords.txt".into(),
+            follow_up_window: 8.0,
+            encoder: dir.join("encoder-epoch-12-avg-2-chunk-16-left-64.onnx"),
+            decoder: dir.join("decoder-epoch-12-avg-2-chunk-16-left-64.onnx"),
+            joiner: dir.join("joiner-epoch-12-avg-2-chunk-16-left-64.onnx"),
+            tokens: dir.join("tokens.txt"),
+        }
+    }
+}
+
+impl TtsConfig {
+    pub fn answer_limit(&self) -> AnswerLimit {
+        match self.max_answer_seconds {
+            Some(seconds) => AnswerLimit::Seconds(seconds),
+            None => AnswerLimit::Chars(self.max_answer_chars),
+        }
+    }
+}
+
+impl WakeWordConfig {
+    pub fn follow_up_window(&self) -> Duration {
+        Duration::from_secs_f32(self.follow_up_window)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Save every question and answer, so the conversation survives a restart.
+    /// Off by default, nothing is written unless it is asked for.
+    pub save: bool,
+    /// Continue the latest session at startup
+    pub resume: bool,
+    /// Directory of the session files
+    pub directory: PathBuf,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            save: false,
+            resume: false,
+            directory: "./sessions".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandsConfig {
+    /// Handle voice commands like "louder" or "forget everything" without the LLM
+    pub enabled: bool,
+    /// Personas the user can switch to by name, "kitt" is always `llm.persona`
+    pub personas: BTreeMap<String, String>,
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            personas: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Said when KITT is stopped with Ctrl+C or `kill`, empty to stop silently
+    pub goodbye: String,
+    /// Let KITT generate and speak the rest of its answer instead of stopping mid-sentence,
+    /// no new questions are accepted in the meantime
+    pub finish_answer: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            goodbye: "Goodbye, Michael.".into(),
+            finish_answer: true,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from `path`, or from `knight-rider.toml` if no path is given.
+    /// Without a config file the defaults are used. The config is not validated yet.
+    pub fn resolve(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let config = match path {
+            Some(path) => Self::load(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::load(DEFAULT_CONFIG_PATH)?,
+            None => Self::default(),
+        };
+        Ok(config.with_env_overrides())
+    }
+
+    /// Reads and parses a config file, without validating it.
+    ///
+    /// Relative paths are relative to the directory of the config file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.into(),
+            source,
+        })?;
+        let mut config: Self = toml::from_str(&content).map_err(|source| ConfigError::Parse {
+            path: path.into(),
+            source,
+        })?;
+        config.resolve_paths(path.parent().unwrap_or(Path::new("")));
+        Ok(config)
+    }
+
+    /// Prepends `dir` to all relative paths, so they do not depend on the working directory
+    fn resolve_paths(&mut self, dir: &Path) {
+        let moonshine = &mut self.stt.moonshine;
+        let whisper = &mut self.stt.whisper;
+        let matcha = &mut self.tts.matcha;
+        let kitten = &mut self.tts.kitten;
+        let kokoro = &mut self.tts.kokoro;
+        let server = &mut self.llama_server;
+        let wake_word = &mut self.wake_word;
+        let paths = [
+            &mut self.vad.model,
+            &mut moonshine.preprocessor,
+            &mut moonshine.encoder,
+            &mut moonshine.uncached_decoder,
+            &mut moonshine.cached_decoder,
+            &mut moonshine.tokens,
+            &mut whisper.encoder,
+            &mut whisper.decoder,
+            &mut whisper.tokens,
+            &mut matcha.acoustic_model,
+            &mut matcha.vocoder,
+            &mut matcha.tokens,
+            &mut matcha.data_dir,
+            &mut kitten.model,
+            &mut kitten.voices,
+            &mut kitten.tokens,
+            &mut kitten.data_dir,
+            &mut kokoro.model,
+            &mut kokoro.voices,
+            &mut kokoro.tokens,
+            &mut kokoro.data_dir,
+            &mut server.model,
+            &mut server.log_file,
+            &mut wake_word.keywords,
+            &mut wake_word.encoder,
+            &mut wake_word.decoder,
+            &mut wake_word.joiner,
+            &mut wake_word.tokens,
+            &mut self.session.directory,
+        ];
+        for path in paths {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+        // a name without a directory is looked up in the PATH
+        if server.binary.is_relative() && server.binary.components().count() > 1 {
+            server.binary = dir.join(&server.binary);
+        }
+        if let Some(grammar) = &mut self.llm.grammar {
+            if grammar != grammar::SPEAKABLE_NAME && Path::new(grammar).is_relative() {
+                *grammar = dir.join(&*grammar).to_string_lossy().into_owned();
+            }
+        }
+    }
+
+    fn with_env_overrides(mut self) -> Self {
+        if let Ok(url) = std::env::var("LLAMA_SERVER_URL") {
+            self.llm.url = url;
+        }
+        self
+    }
+
+    /// Checks that all values are in range and that the selected models exist
+    pub fn validated(self) -> Result<Self, ConfigError> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(self),
+        }
+    }
+
+    /// Returns everything that is wrong with the config, the selected models have to exist
+    pub fn problems(&self) -> Vec<ConfigError> {
+        let mut problems = Vec::new();
+
+        let audio = &self.audio;
+        positive(&mut problems, "audio.sample_rate", audio.sample_rate as f32);
+        positive(&mut problems, "audio.num_frames", audio.num_frames as f32);
+        if audio.echo_filter_length == Some(0) {
+            problems.push(invalid("audio.echo_filter_length", "must be at least 1"));
+        }
+        positive(
+            &mut problems,
+            "audio.echo_double_talk_ratio",
+            audio.echo_double_talk_ratio,
+        );
+
+        let vad = &self.vad;
+        file_exists(&mut problems, "vad.model", &vad.model);
+        if !(0.0..=1.0).contains(&vad.threshold) {
+            problems.push(invalid("vad.threshold", "must be between 0 and 1"));
+        }
+        positive(
+            &mut problems,
+            "vad.min_silence_duration",
+            vad.min_silence_duration,
+        );
+        positive(
+            &mut problems,
+            "vad.min_speech_duration",
+            vad.min_speech_duration,
+        );
+        if vad.max_speech_duration <= vad.min_speech_duration {
+            problems.push(invalid(
+                "vad.max_speech_duration",
+                "must be longer than vad.min_speech_duration",
+            ));
+        }
+
+        match self.stt.backend {
+            SttBackend::Moonshine => {
+                let model = &self.stt.moonshine;
+                file_exists(
+                    &mut problems,
+                    "stt.moonshine.preprocessor",
+                    &model.preprocessor,
+                );
+                file_exists(&mut problems, "stt.moonshine.encoder", &model.encoder);
+                file_exists(
+                    &mut problems,
+                    "stt.moonshine.uncached_decoder",
+                    &model.uncached_decoder,
+                );
+                file_exists(
+                    &mut problems,
+                    "stt.moonshine.cached_decoder",
+                    &model.cached_decoder,
+                );
+                file_exists(&mut problems, "stt.moonshine.tokens", &model.tokens);
+            }
+            SttBackend::Whisper => {
+                let model = &self.stt.whisper;
+                file_exists(&mut problems, "stt.whisper.encoder", &model.encoder);
+                file_exists(&mut problems, "stt.whisper.decoder", &model.decoder);
+                file_exists(&mut problems, "stt.whisper.tokens", &model.tokens);
+            }
+        }
+
+        let tts = &self.tts;
+        if !(0..tts.num_voices()).contains(&tts.voice) {
+            problems.push(invalid(
+                "tts.voice",
+                format!(
+                    "must be between 0 and {} for this model",
+                    tts.num_voices() - 1
+                ),
+            ));
+        }
+        positive(
+            &mut problems,
+            "tts.max_answer_chars",
+            tts.max_answer_chars as f32,
+        );
+        if let Some(seconds) = tts.max_answer_seconds {
+            positive(&mut problems, "tts.max_answer_seconds", seconds);
+        }
+        match tts.backend {
+            TtsBackend::Matcha => {
+                let model = &tts.matcha;
+                file_exists(
+                    &mut problems,
+                    "tts.matcha.acoustic_model",
+                    &model.acoustic_model,
+                );
+                file_exists(&mut problems, "tts.matcha.vocoder", &model.vocoder);
+                file_exists(&mut problems, "tts.matcha.tokens", &model.tokens);
+                file_exists(&mut problems, "tts.matcha.data_dir", &model.data_dir);
+            }
+            TtsBackend::Kitten => {
+                let model = &tts.kitten;
+                file_exists(&mut problems, "tts.kitten.model", &model.model);
+                file_exists(&mut problems, "tts.kitten.voices", &model.voices);
+                file_exists(&mut problems, "tts.kitten.tokens", &model.tokens);
+                file_exists(&mut problems, "tts.kitten.data_dir", &model.data_dir);
+                positive(
+                    &mut problems,
+                    "tts.kitten.num_voices",
+                    model.num_voices as f32,
+                );
+            }
+            TtsBackend::Kokoro => {
+                let model = &tts.kokoro;
+                file_exists(&mut problems, "tts.kokoro.model", &model.model);
+                file_exists(&mut problems, "tts.kokoro.voices", &model.voices);
+                file_exists(&mut problems, "tts.kokoro.tokens", &model.tokens);
+                file_exists(&mut problems, "tts.kokoro.data_dir", &model.data_dir);
+                positive(
+                    &mut problems,
+                    "tts.kokoro.num_voices",
+                    model.num_voices as f32,
+                );
+            }
+        }
+
+        let llm = &self.llm;
+        if !llm.url.starts_with("http://") && !llm.url.starts_with("https://") {
+            problems.push(invalid(
+                "llm.url",
+                format!("`{}` must start with http:// or https://", llm.url),
+            ));
+        }
+        positive(&mut problems, "llm.timeout", llm.timeout as f32);
+        positive(&mut problems, "llm.retry_delay", llm.retry_delay);
+        if !(0.0..=2.0).contains(&llm.temperature) {
+            problems.push(invalid("llm.temperature", "must be between 0 and 2"));
+        }
+        positive(&mut problems, "llm.max_tokens", llm.max_tokens as f32);
+        if llm.top_p.is_some_and(|top_p| !(0.0..=1.0).contains(&top_p)) {
+            problems.push(invalid("llm.top_p", "must be between 0 and 1"));
+        }
+        if llm.min_p.is_some_and(|min_p| !(0.0..=1.0).contains(&min_p)) {
+            problems.push(invalid("llm.min_p", "must be between 0 and 1"));
+        }
+        if let Some(grammar) = &llm.grammar {
+            // the grammar is not a model, a broken one is a mistake in the config
+            if let Err(source) = grammar::load(grammar) {
+                problems.push(ConfigError::UnreadableFile {
+                    key: "llm.grammar",
+                    path: grammar.into(),
+                    source,
+                });
+            }
+        }
+        if let Some(json_schema) = &llm.json_schema {
+            if !json_schema.is_object() {
+                problems.push(invalid("llm.json_schema", "must be a table"));
+            } else if llm.grammar.is_some() {
+                problems.push(invalid(
+                    "llm.json_schema",
+                    "can not be combined with llm.grammar",
+                ));
+            }
+        }
+        if let Some(repeat_penalty) = llm.repeat_penalty {
+            positive(&mut problems, "llm.repeat_penalty", repeat_penalty);
+        }
+
+        let server = &self.llama_server;
+        if server.enabled {
+            // a name without a directory is looked up in the PATH
+            if server.binary.components().count() > 1 {
+                file_exists(&mut problems, "llama_server.binary", &server.binary);
+            }
+            file_exists(&mut problems, "llama_server.model", &server.model);
+            if server.port == Some(0) {
+                problems.push(invalid("llama_server.port", "must be at least 1"));
+            }
+            positive(
+                &mut problems,
+                "llama_server.restart_delay",
+                server.restart_delay,
+            );
+        }
+
+        let wake_word = &self.wake_word;
+        if wake_word.enabled {
+            file_exists(&mut problems, "wake_word.keywords", &wake_word.keywords);
+            file_exists(&mut problems, "wake_word.encoder", &wake_word.encoder);
+            file_exists(&mut problems, "wake_word.decoder", &wake_word.decoder);
+            file_exists(&mut problems, "wake_word.joiner", &wake_word.joiner);
+            file_exists(&mut problems, "wake_word.tokens", &wake_word.tokens);
+            if !wake_word.follow_up_window.is_finite() || wake_word.follow_up_window < 0.0 {
+                problems.push(invalid(
+                    "wake_word.follow_up_window",
+                    "must be a positive number of seconds",
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+fn invalid(key: &'static str, message: impl Into<String>) -> ConfigError {
+    ConfigError::InvalidValue {
+        key,
+        message: message.into(),
+    }
+}
+
+fn positive(problems: &mut Vec<ConfigError>, key: &'static str, value: f32) {
+    if !(value > 0.0 && value.is_finite()) {
+        problems.push(invalid(key, format!("must be greater than 0, got {value}")));
+    }
+}
+
+fn file_exists(problems: &mut Vec<ConfigError>, key: &'static str, path: &Path) {
+    if !path.exists() {
+        problems.push(ConfigError::MissingFile {
+            key,
+            path: path.into(),
+        });
+    }
+}
+
+/// sherpa-rs takes model paths as strings
+pub fn model_path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn empty_file_has_the_defaults() {
+        let config = parse("");
+        assert_eq!(config.audio.sample_rate, 48000);
+        assert_eq!(config.stt.backend, SttBackend::Moonshine);
+        assert_eq!(config.tts.backend, TtsBackend::Matcha);
+        assert_eq!(config.llm.url, "http://127.0.0.1:8080");
+        assert_eq!(config.vad.model, Path::new("silero_vad.onnx"));
+    }
+
+    #[test]
+    fn parses_values_and_keeps_other_defaults() {
+        let config = parse(
+            r#"
+            [audio]
+            input_device = "Codec Zero"
+            barge_in = true
+
+            [tts]
+            backend = "kokoro"
+            voice = 3
+
+            [llm]
+            json_schema = { type = "object", required = ["answer"] }
+
+            [commands.personas]
+            butler = "You are a polite butler."
+            "#,
+        );
+        assert_eq!(config.audio.input_device.as_deref(), Some("Codec Zero"));
+        assert!(config.audio.barge_in);
+        assert_eq!(config.audio.num_frames, 512);
+        assert_eq!(config.tts.backend, TtsBackend::Kokoro);
+        assert_eq!(config.tts.voice, 3);
+        assert_eq!(config.tts.max_answer_chars, 200);
+        assert_eq!(
+            config.llm.json_schema,
+            Some(serde_json::json!({"type": "object", "required": ["answer"]}))
+        );
+        assert_eq!(
+            config.commands.personas["butler"],
+            "You are a polite butler."
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_wrong_types() {
+        assert!(toml::from_str::<Config>("[audio]\nsample_rte = 44100").is_err());
+        assert!(toml::from_str::<Config>("[tts]\nbackend = \"espeak\"").is_err());
+        assert!(toml::from_str::<Config>("[llm]\ntimeout = \"long\"").is_err());
+    }
+
+    #[test]
+    fn reports_invalid_values_by_key() {
+        let mut config = parse(
+            r#"
+            [vad]
+            threshold = 1.5
+
+            [tts]
+            voice = 11
+
+            [llm]
+            url = "localhost:8080"
+            temperature = 3.0
+            "#,
+        );
+        config.audio.echo_filter_length = Some(0);
+
+        let keys: Vec<&str> = config
+            .problems()
+            .iter()
+            .filter_map(|problem| match problem {
+                ConfigError::InvalidValue { key, .. } => Some(*key),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "audio.echo_filter_length",
+                "vad.threshold",
+                "tts.voice",
+                "llm.url",
+                "llm.temperature"
+            ]
+        );
+        // the default models are not downloaded in the tests
+        assert!(matches!(
+            config.validated(),
+            Err(ConfigError::InvalidValue {
+                key: "audio.echo_filter_length",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_grammar_that_can_not_be_read() {
+        let grammar_problem = |config: &Config| {
+            config
+                .problems()
+                .into_iter()
+                .find_map(|problem| match problem {
+                    ConfigError::UnreadableFile { key, path, .. } => Some((key, path)),
+                    _ => None,
+                })
+        };
+        let mut config = Config::default();
+        config.llm.grammar = Some("no-such-grammar.gbnf".into());
+        assert_eq!(
+            grammar_problem(&config),
+            Some(("llm.grammar", PathBuf::from("no-such-grammar.gbnf")))
+        );
+
+        config.llm.grammar = Some("speakable".into());
+        assert_eq!(grammar_problem(&config), None);
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_config_file() {
+        let dir = std::env::temp_dir().join(format!("knight-rider-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kitt.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [vad]
+            model = "models/silero_vad.onnx"
+
+            [llm]
+            grammar = "speakable"
+
+            [llama_server]
+            binary = "llama-server"
+            model = "/opt/models/gemma.gguf"
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.vad.model, dir.join("models/silero_vad.onnx"));
+        assert_eq!(
+            config.stt.moonshine.tokens,
+            dir.join(MoonshineModel::default().tokens)
+        );
+        assert_eq!(config.llm.grammar.as_deref(), Some("speakable"));
+        // found in the PATH
+        assert_eq!(config.llama_server.binary, Path::new("llama-server"));
+        assert_eq!(
+            config.llama_server.model,
+            Path::new("/opt/models/gemma.gguf")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
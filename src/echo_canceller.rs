@@ -0,0 +1,289 @@
+/// Removes KITTs own voice from the microphone signal with a normalized least mean squares
+/// (NLMS) adaptive filter.
+///
+/// The filter learns the echo path from the speaker to the microphone. The filter length has to
+/// cover the whole round trip, including the audio buffers of the sound card. It runs at the
+/// VAD sample rate outside of the audio callback, at 16 kHz about a thousand taps are enough
+/// for a sound card with 512 frames per buffer.
+///
+/// While the user talks over KITT the filter stops learning, otherwise it would learn
+/// to remove the user's voice as well. The echo alone has to stay below the double talk ratio,
+/// in a small enclosure it can be louder than KITTs voice.
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    // the far end history is stored twice, so the latest `filter_length` samples
+    // are always available as one contiguous slice
+    far_end: Vec<f32>,
+    position: usize,
+    far_end_energy: f32,
+    // short-term power of the microphone signal
+    near_end_power: f32,
+    double_talk_ratio: f32,
+    // samples until the filter learns again after double talk
+    double_talk_hold: usize,
+}
+
+impl EchoCanceller {
+    /// Adaptation step size, smaller is more stable but converges slower
+    const STEP_SIZE: f32 = 0.5;
+    /// Avoids division by zero and adaptation to noise when the far end is silent
+    const REGULARIZATION: f32 = 1e-3;
+    /// Smoothing factor of the near end power, about 2 ms at 16 kHz so double talk
+    /// is detected before the filter learned much of it
+    const NEAR_END_SMOOTHING: f32 = 1.0 / 32.0;
+    /// The echo alone is assumed to be at least 3 dB quieter than KITTs voice
+    pub const DEFAULT_DOUBLE_TALK_RATIO: f32 = 0.5;
+    /// Samples the filter stays frozen after double talk, 30 ms at 16 kHz
+    const DOUBLE_TALK_HOLD: usize = 480;
+
+    pub fn new(filter_length: usize) -> Self {
+        let filter_length = filter_length.max(1);
+        Self {
+            weights: vec![0.0; filter_length],
+            far_end: vec![0.0; 2 * filter_length],
+            position: 0,
+            far_end_energy: 0.0,
+            near_end_power: 0.0,
+            double_talk_ratio: Self::DEFAULT_DOUBLE_TALK_RATIO,
+            double_talk_hold: 0,
+        }
+    }
+
+    /// Double talk if the microphone power is above this times the far end power,
+    /// it has to be higher than the power gain from the speaker to the microphone
+    pub fn with_double_talk_ratio(mut self, double_talk_ratio: f32) -> Self {
+        self.double_talk_ratio = double_talk_ratio;
+        self
+    }
+
+    pub fn filter_length(&self) -> usize {
+        self.weights.len()
+    }
+
+    /// Removes the echo of `far_end` (what was sent to the speaker) from `near_end`
+    /// (what the microphone recorded) in place. Both slices must have the same length.
+    pub fn process(&mut self, far_end: &[f32], near_end: &mut [f32]) {
+        debug_assert_eq!(far_end.len(), near_end.len());
+        let filter_length = self.filter_length();
+
+        for (&x, d) in far_end.iter().zip(near_end.iter_mut()) {
+            // the newest sample replaces the oldest one in the history
+            self.position = (self.position + filter_length - 1) % filter_length;
+            let oldest = self.far_end[self.position];
+            self.far_end[self.position] = x;
+            self.far_end[self.position + filter_length] = x;
+
+            let history = &self.far_end[self.position..self.position + filter_length];
+
+            if self.position == 0 {
+                // the running sum collects rounding errors, start over once per filter length
+                self.far_end_energy = history.iter().map(|x| x * x).sum();
+            } else {
+                self.far_end_energy = (self.far_end_energy + x * x - oldest * oldest).max(0.0);
+            }
+
+            // Geigel-like double talk detection on the power of both sides
+            self.near_end_power += Self::NEAR_END_SMOOTHING * (*d * *d - self.near_end_power);
+            let far_end_power = self.far_end_energy / filter_length as f32;
+            if self.near_end_power > self.double_talk_ratio * far_end_power {
+                self.double_talk_hold = Self::DOUBLE_TALK_HOLD;
+            } else {
+                self.double_talk_hold = self.double_talk_hold.saturating_sub(1);
+            }
+
+            let estimated_echo: f32 = self.weights.iter().zip(history).map(|(w, x)| w * x).sum();
+            let error = *d - estimated_echo;
+
+            if self.double_talk_hold == 0 {
+                let norm_step =
+                    Self::STEP_SIZE * error / (self.far_end_energy + Self::REGULARIZATION);
+                for (w, x) in self.weights.iter_mut().zip(history) {
+                    *w += norm_step * x;
+                }
+            }
+
+            *d = error;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic white noise in the range -0.5..0.5
+    fn noise(num_samples: usize, mut seed: u32) -> Vec<f32> {
+        (0..num_samples)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect()
+    }
+
+    /// Simulates the way from the speaker to the microphone
+    fn echo(far_end: &[f32], echo_path: &[f32]) -> Vec<f32> {
+        (0..far_end.len())
+            .map(|n| {
+                echo_path
+                    .iter()
+                    .enumerate()
+                    .filter(|(k, _)| *k <= n)
+                    .map(|(k, h)| h * far_end[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn energy(signal: &[f32]) -> f32 {
+        signal.iter().map(|x| x * x).sum()
+    }
+
+    /// Echo return loss enhancement in dB
+    fn erle(echo: &[f32], residual: &[f32]) -> f32 {
+        10.0 * (energy(echo) / energy(residual)).log10()
+    }
+
+    /// 16 kHz like the VAD input
+    const SAMPLE_RATE: usize = 16000;
+    const FILTER_LENGTH: usize = 512;
+
+    fn echo_path() -> Vec<f32> {
+        // 10 ms delay through the sound card buffers followed by a decaying room response
+        let mut echo_path = vec![0.0; SAMPLE_RATE / 100];
+        let room = noise(200, 4);
+        echo_path.extend((0..200).map(|k| 0.4 * (-(k as f32) / 40.0).exp() * room[k]));
+        echo_path
+    }
+
+    /// Speaker and microphone in a small enclosure, the echo is louder than the far end
+    fn loud_echo_path() -> Vec<f32> {
+        let mut echo_path = vec![0.0; SAMPLE_RATE / 100];
+        echo_path.push(1.5);
+        let room = noise(200, 5);
+        echo_path.extend((0..200).map(|k| (-(k as f32) / 40.0).exp() * room[k]));
+        echo_path
+    }
+
+    /// Runs the echo canceller over the signals in blocks like the voice loop does
+    fn process(echo_canceller: &mut EchoCanceller, far_end: &[f32], near_end: &[f32]) -> Vec<f32> {
+        let mut output = near_end.to_vec();
+        for (far, near) in far_end.chunks(512).zip(output.chunks_mut(512)) {
+            echo_canceller.process(far, near);
+        }
+        output
+    }
+
+    fn add(a: &[f32], b: &[f32]) -> Vec<f32> {
+        a.iter().zip(b).map(|(a, b)| a + b).collect()
+    }
+
+    fn sub(a: &[f32], b: &[f32]) -> Vec<f32> {
+        a.iter().zip(b).map(|(a, b)| a - b).collect()
+    }
+
+    #[test]
+    fn cancels_synthetic_echo() {
+        let far_end = noise(2 * SAMPLE_RATE, 1);
+        let echo = echo(&far_end, &echo_path());
+
+        let mut echo_canceller = EchoCanceller::new(FILTER_LENGTH);
+        let output = process(&mut echo_canceller, &far_end, &echo);
+
+        // measure after the filter converged
+        let converged = SAMPLE_RATE;
+        let erle = erle(&echo[converged..], &output[converged..]);
+        assert!(erle > 30.0, "ERLE too low: {erle} dB");
+    }
+
+    #[test]
+    fn cancels_echo_louder_than_the_far_end() {
+        let far_end = noise(2 * SAMPLE_RATE, 1);
+        let echo = echo(&far_end, &loud_echo_path());
+        assert!(energy(&echo) > energy(&far_end));
+
+        // the echo has about 3.9 times the power of the far end
+        let mut echo_canceller = EchoCanceller::new(FILTER_LENGTH).with_double_talk_ratio(8.0);
+        let output = process(&mut echo_canceller, &far_end, &echo);
+
+        let converged = SAMPLE_RATE;
+        let erle = erle(&echo[converged..], &output[converged..]);
+        assert!(erle > 30.0, "ERLE too low: {erle} dB");
+    }
+
+    #[test]
+    fn keeps_near_end_speech() {
+        let far_end = noise(2 * SAMPLE_RATE, 1);
+        let echo = echo(&far_end, &echo_path());
+        let speech: Vec<f32> = noise(2 * SAMPLE_RATE, 2).iter().map(|x| 0.1 * x).collect();
+
+        let mut echo_canceller = EchoCanceller::new(FILTER_LENGTH);
+
+        // let the filter converge on echo only
+        let converged = SAMPLE_RATE;
+        process(
+            &mut echo_canceller,
+            &far_end[..converged],
+            &echo[..converged],
+        );
+
+        // now the user whispers while KITT is speaking
+        let near_end = add(&echo[converged..], &speech[converged..]);
+        let output = process(&mut echo_canceller, &far_end[converged..], &near_end);
+
+        let residual_echo = sub(&output, &speech[converged..]);
+        let erle = erle(&echo[converged..], &residual_echo);
+        assert!(erle > 10.0, "ERLE during double talk too low: {erle} dB");
+    }
+
+    #[test]
+    fn stops_learning_during_double_talk() {
+        let far_end = noise(3 * SAMPLE_RATE, 1);
+        let echo = echo(&far_end, &echo_path());
+        // the user talks over KITT as loud as KITT, much louder than the echo
+        let speech = noise(SAMPLE_RATE, 2);
+
+        let mut echo_canceller = EchoCanceller::new(FILTER_LENGTH);
+
+        let converged = SAMPLE_RATE;
+        process(
+            &mut echo_canceller,
+            &far_end[..converged],
+            &echo[..converged],
+        );
+
+        let double_talk = converged..converged + SAMPLE_RATE;
+        // only the few samples until the double talk is detected are learned
+        let near_end = add(&echo[double_talk.clone()], &speech);
+        let output = process(
+            &mut echo_canceller,
+            &far_end[double_talk.clone()],
+            &near_end,
+        );
+        let residual_echo = sub(&output, &speech);
+        let double_talk_erle = erle(&echo[double_talk.clone()], &residual_echo);
+        assert!(
+            double_talk_erle > 12.0,
+            "ERLE during double talk too low: {double_talk_erle} dB"
+        );
+
+        // the filter did not learn the user's voice
+        let after = double_talk.end..;
+        let output = process(
+            &mut echo_canceller,
+            &far_end[after.clone()],
+            &echo[after.clone()],
+        );
+        let erle = erle(&echo[after], &output);
+        assert!(erle > 20.0, "ERLE after double talk too low: {erle} dB");
+    }
+
+    #[test]
+    fn silent_far_end_passes_input() {
+        let mut echo_canceller = EchoCanceller::new(64);
+        let input = noise(1024, 3);
+        let mut output = input.clone();
+        echo_canceller.process(&[0.0; 1024], &mut output);
+        assert_eq!(input, output);
+    }
+}
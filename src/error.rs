@@ -0,0 +1,198 @@
+//! Errors that stop KITT, and the exit codes they end the process with.
+//!
+//! `start.sh` and systemd use the exit codes to decide whether a restart can help:
+//! llama-server may still be loading and a USB sound card may not be there yet,
+//! but a broken config or model file needs a human.
+
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use crate::{
+    config::ConfigError, llama::LlamaError, llama_server::LlamaServerError, session::SessionError,
+    system_audio::SystemAudioError, text_to_speech::TextToSpeechError,
+};
+
+/// Anything else, e.g. an audio file that could not be written
+pub const EXIT_FAILURE: u8 = 1;
+/// The config file is invalid (2 is used by clap for invalid arguments)
+pub const EXIT_CONFIG: u8 = 3;
+/// A model file is missing or could not be loaded
+pub const EXIT_MODEL: u8 = 4;
+/// llama-server is not reachable or failed
+pub const EXIT_LLAMA: u8 = 5;
+/// The sound card could not be opened or stopped working
+pub const EXIT_AUDIO: u8 = 6;
+
+#[derive(thiserror::Error, Debug)]
+pub enum KnightRiderError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Model(#[from] ModelError),
+    #[error(transparent)]
+    TextToSpeech(#[from] TextToSpeechError),
+    #[error("{}", llama_message(url, source))]
+    Llama { url: String, source: LlamaError },
+    #[error(transparent)]
+    LlamaServer(#[from] LlamaServerError),
+    #[error(transparent)]
+    Audio(#[from] SystemAudioError),
+    #[error(transparent)]
+    Session(#[from] SessionError),
+    #[error("Could not process `{path}`: {source}")]
+    File {
+        path: PathBuf,
+        source: Box<dyn std::error::Error>,
+    },
+}
+
+impl KnightRiderError {
+    pub fn llama(url: impl Into<String>, source: LlamaError) -> Self {
+        Self::Llama {
+            url: url.into(),
+            source,
+        }
+    }
+
+    pub fn file(path: impl Into<PathBuf>, source: impl Into<Box<dyn std::error::Error>>) -> Self {
+        Self::File {
+            path: path.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        let code = match self {
+            Self::Config(ConfigError::MissingFile { .. }) => EXIT_MODEL,
+            Self::Config(_) => EXIT_CONFIG,
+            Self::Model(_) | Self::TextToSpeech(_) => EXIT_MODEL,
+            Self::Llama { .. } => EXIT_LLAMA,
+            Self::LlamaServer(LlamaServerError::Spawn { .. }) => EXIT_CONFIG,
+            Self::LlamaServer(_) => EXIT_LLAMA,
+            Self::Audio(_) => EXIT_AUDIO,
+            Self::Session(_) | Self::File { .. } => EXIT_FAILURE,
+        };
+        ExitCode::from(code)
+    }
+}
+
+/// Waiting for the model needs no fix, only a later start (`EXIT_LLAMA` lets `start.sh` retry)
+fn llama_message(url: &str, source: &LlamaError) -> String {
+    match source {
+        LlamaError::HealthCheckFailed => {
+            format!("llama-server at {url} is still loading the model, start KITT again later")
+        }
+        _ => format!(
+            "llama-server at {url} failed: {source}, make sure it is running and start again"
+        ),
+    }
+}
+
+/// A speech model (VAD, STT, TTS or wake word) that could not be loaded
+#[derive(thiserror::Error, Debug)]
+pub enum ModelError {
+    #[error("The {model} model file `{path}` does not exist")]
+    MissingFile { model: &'static str, path: PathBuf },
+    #[error("Could not load the {model} model `{path}`: {message}")]
+    Load {
+        model: &'static str,
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl ModelError {
+    /// Fails with the first file of the model that does not exist
+    pub fn check_files<'a>(
+        model: &'static str,
+        paths: impl IntoIterator<Item = &'a Path>,
+    ) -> Result<(), Self> {
+        match paths.into_iter().find(|path| !path.exists()) {
+            Some(path) => Err(Self::MissingFile {
+                model,
+                path: path.to_path_buf(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// For `map_err` of the model constructors, `path` is the main file of the model
+    pub fn load<E: std::fmt::Display>(model: &'static str, path: &Path) -> impl FnOnce(E) -> Self {
+        let path = path.to_path_buf();
+        move |e| Self::Load {
+            model,
+            path,
+            message: e.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_tell_what_went_wrong() {
+        let missing_model = ConfigError::MissingFile {
+            key: "vad.model",
+            path: "silero_vad.onnx".into(),
+        };
+        let invalid_value = ConfigError::InvalidValue {
+            key: "vad.threshold",
+            message: "must be between 0 and 1".into(),
+        };
+        let missing_grammar = ConfigError::UnreadableFile {
+            key: "llm.grammar",
+            path: "kitt.gbnf".into(),
+            source: std::io::ErrorKind::NotFound.into(),
+        };
+        let cases = [
+            (KnightRiderError::from(missing_model), EXIT_MODEL),
+            (KnightRiderError::from(invalid_value), EXIT_CONFIG),
+            (KnightRiderError::from(missing_grammar), EXIT_CONFIG),
+            (
+                KnightRiderError::llama("http://localhost:8080", LlamaError::HealthCheckFailed),
+                EXIT_LLAMA,
+            ),
+            (
+                SystemAudioError::OutputDeviceNotFound("USB Audio".into()).into(),
+                EXIT_AUDIO,
+            ),
+            (
+                KnightRiderError::file("answer.wav", "disk full"),
+                EXIT_FAILURE,
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), ExitCode::from(code), "{error}");
+        }
+    }
+
+    #[test]
+    fn missing_model_file_is_named() {
+        let dir = std::env::temp_dir();
+        let error = ModelError::check_files(
+            "whisper",
+            [
+                dir.as_path(),
+                Path::new("./no-such-model/tiny-encoder.onnx"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The whisper model file `./no-such-model/tiny-encoder.onnx` does not exist"
+        );
+    }
+
+    #[test]
+    fn loading_llama_server_asks_for_a_later_start() {
+        let error = KnightRiderError::llama("http://localhost:8080", LlamaError::HealthCheckFailed);
+        assert_eq!(
+            error.to_string(),
+            "llama-server at http://localhost:8080 is still loading the model, start KITT again later"
+        );
+    }
+}
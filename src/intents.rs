@@ -0,0 +1,242 @@
+//! Voice commands that KITT handles itself instead of asking the LLM.
+//!
+//! Only transcripts that consist of nothing but a command are matched,
+//! so "why should I be quiet in a library?" still goes to the LLM.
+
+/// What the user wants KITT to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Intent {
+    VolumeUp,
+    VolumeDown,
+    /// Volume in percent of the original loudness
+    SetVolume(u32),
+    NextVoice,
+    /// Speaker id of the TTS model
+    SetVoice(i32),
+    /// Name of a configured persona, as written in the config
+    SwitchPersona(String),
+    /// Stop answering until the user says `Unmute`
+    Mute,
+    Unmute,
+    /// Forget the conversation so far
+    ClearHistory,
+}
+
+const VOLUME_UP: &[&str] = &[
+    "louder",
+    "volume up",
+    "speak up",
+    "turn it up",
+    "turn up the volume",
+    "turn the volume up",
+    "increase the volume",
+];
+const VOLUME_DOWN: &[&str] = &[
+    "quieter",
+    "volume down",
+    "not so loud",
+    "turn it down",
+    "turn down the volume",
+    "turn the volume down",
+    "decrease the volume",
+    "lower the volume",
+];
+const NEXT_VOICE: &[&str] = &[
+    "next voice",
+    "change voice",
+    "switch voice",
+    "change your voice",
+    "switch your voice",
+    "use another voice",
+    "use a different voice",
+];
+const MUTE: &[&str] = &[
+    "mute",
+    "be quiet",
+    "stop listening",
+    "go to sleep",
+    "silence",
+];
+const UNMUTE: &[&str] = &["unmute", "start listening", "wake up", "you can talk again"];
+const CLEAR_HISTORY: &[&str] = &[
+    "forget everything",
+    "forget our conversation",
+    "clear history",
+    "clear the history",
+    "clear your memory",
+    "start a new conversation",
+];
+
+const SET_VOLUME: &[&str] = &["set the volume to", "set volume to", "volume"];
+const SET_VOICE: &[&str] = &["use voice", "switch to voice", "change to voice", "voice"];
+const SWITCH_PERSONA: &[&str] = &["switch to the", "switch to", "become", "be"];
+
+/// Words the user may put around a command
+const FILLER_START: &[&str] = &["hey kitt", "ok kitt", "okay kitt", "kitt", "kit", "please"];
+const FILLER_END: &[&str] = &["please"];
+
+const NUMBERS: [&str; 11] = [
+    "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+];
+
+/// Finds the command in a transcript
+#[derive(Debug, Clone, Default)]
+pub struct IntentMatcher {
+    // the normalized name and the name as configured
+    personas: Vec<(String, String)>,
+}
+
+impl IntentMatcher {
+    /// `personas` are the names the user can switch to
+    pub fn new<'a>(personas: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            personas: personas
+                .into_iter()
+                .map(|name| (normalize(name), name.to_string()))
+                .collect(),
+        }
+    }
+
+    /// The intent of the transcript, `None` if it is meant for the LLM
+    pub fn match_intent(&self, transcript: &str) -> Option<Intent> {
+        let text = normalize(transcript);
+        let text = strip_fillers(&text);
+
+        let fixed = [
+            (VOLUME_UP, Intent::VolumeUp),
+            (VOLUME_DOWN, Intent::VolumeDown),
+            (NEXT_VOICE, Intent::NextVoice),
+            (MUTE, Intent::Mute),
+            (UNMUTE, Intent::Unmute),
+            (CLEAR_HISTORY, Intent::ClearHistory),
+        ];
+        for (phrases, intent) in fixed {
+            if phrases.contains(&text) {
+                return Some(intent);
+            }
+        }
+
+        if let Some(volume) = after_prefix(text, SET_VOLUME).and_then(parse_volume) {
+            return Some(Intent::SetVolume(volume));
+        }
+        if let Some(voice) = after_prefix(text, SET_VOICE).and_then(parse_number) {
+            return Some(Intent::SetVoice(voice as i32));
+        }
+        let persona = after_prefix(text, SWITCH_PERSONA)?;
+        let persona = persona.strip_suffix(" persona").unwrap_or(persona);
+        self.personas
+            .iter()
+            .find(|(normalized, _)| normalized == persona)
+            .map(|(_, name)| Intent::SwitchPersona(name.clone()))
+    }
+}
+
+/// Lowercase words without punctuation, "K.I.T.T." becomes "kitt"
+fn normalize(text: &str) -> String {
+    let text = text.to_lowercase().replace('%', " percent ");
+    let mut words = Vec::new();
+    for word in text.split_whitespace() {
+        let word: String = word.chars().filter(|c| c.is_alphanumeric()).collect();
+        if !word.is_empty() {
+            words.push(word);
+        }
+    }
+    words.join(" ")
+}
+
+fn strip_fillers(mut text: &str) -> &str {
+    for filler in FILLER_START {
+        if let Some(rest) = text
+            .strip_prefix(filler)
+            .and_then(|rest| rest.strip_prefix(' '))
+        {
+            text = rest;
+        }
+    }
+    for filler in FILLER_END {
+        if let Some(rest) = text
+            .strip_suffix(filler)
+            .and_then(|rest| rest.strip_suffix(' '))
+        {
+            text = rest;
+        }
+    }
+    text
+}
+
+/// The rest of the text after the first matching prefix
+fn after_prefix<'a>(text: &'a str, prefixes: &[&str]) -> Option<&'a str> {
+    prefixes.iter().find_map(|prefix| {
+        text.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix(' '))
+    })
+}
+
+fn parse_number(word: &str) -> Option<u32> {
+    word.parse().ok().or_else(|| {
+        NUMBERS
+            .iter()
+            .position(|&number| number == word)
+            .map(|n| n as u32)
+    })
+}
+
+/// "50 percent" is 50 %, a single number up to ten is a level from 0 to 10
+fn parse_volume(text: &str) -> Option<u32> {
+    match text.strip_suffix(" percent") {
+        Some(percent) => parse_number(percent),
+        None => parse_number(text)
+            .filter(|&level| level <= 10)
+            .map(|level| level * 10),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_commands() {
+        let matcher = IntentMatcher::new(["kitt", "KARR"]);
+        let cases = [
+            ("Louder!", Some(Intent::VolumeUp)),
+            (
+                "KITT, turn the volume down, please.",
+                Some(Intent::VolumeDown),
+            ),
+            ("Set the volume to 7.", Some(Intent::SetVolume(70))),
+            ("Volume five", Some(Intent::SetVolume(50))),
+            ("Set volume to 150%", Some(Intent::SetVolume(150))),
+            ("Change your voice.", Some(Intent::NextVoice)),
+            ("Use voice 3", Some(Intent::SetVoice(3))),
+            (
+                "Switch to the KARR persona.",
+                Some(Intent::SwitchPersona("KARR".into())),
+            ),
+            ("Be Kitt", Some(Intent::SwitchPersona("kitt".into()))),
+            ("Hey KITT, be quiet!", Some(Intent::Mute)),
+            ("Start listening.", Some(Intent::Unmute)),
+            ("Forget everything.", Some(Intent::ClearHistory)),
+        ];
+        for (transcript, intent) in cases {
+            assert_eq!(matcher.match_intent(transcript), intent, "{transcript}");
+        }
+    }
+
+    #[test]
+    fn leaves_questions_to_the_llm() {
+        let matcher = IntentMatcher::new(["kitt"]);
+        let questions = [
+            "Why should I be quiet in a library?",
+            "Can you make the radio louder?",
+            "Set the volume to eleven.",
+            "Switch to the Michael persona.",
+            "Be careful.",
+            "KITT",
+            "",
+        ];
+        for transcript in questions {
+            assert_eq!(matcher.match_intent(transcript), None, "{transcript}");
+        }
+    }
+}
@@ -1,6 +1,44 @@
+use std::{
+    collections::VecDeque,
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+use futures_util::{Stream, StreamExt};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use thiserror::Error;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::{
+    config::LlmConfig,
+    session::{SessionError, SessionLog},
+    shutdown::Shutdown,
+    system_tools,
+    timestamp::unix_millis,
+};
+
+pub use health::{Health, HealthEvent};
+pub use history::HistoryPolicy;
+pub use retry::RetryPolicy;
+pub use tools::{ToolCall, ToolCallDelta, ToolDefinition, ToolRegistry};
+
+pub mod grammar;
+mod health;
+mod history;
+#[cfg(test)]
+pub mod mock_server;
+mod retry;
+#[cfg(test)]
+mod tests;
+mod tools;
+
+/// Model name sent with the requests, llama-server answers with whatever model it has loaded
+pub const DEFAULT_MODEL: &str = "llama";
+
+/// How often `/health` is checked while waiting for llama-server to load the model
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Error)]
 pub enum LlamaError {
@@ -10,83 +48,241 @@ pub enum LlamaError {
     Http(u16, String),
     #[error("Invalid response format")]
     InvalidResponse,
-    #[error("LlamaServer Health Check Failed")]
+    #[error("Stream error: {0}")]
+    Stream(String),
+    /// llama-server is still loading the model
+    #[error("llama-server is still loading the model, start KITT again later")]
     HealthCheckFailed,
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub role: String,
     pub content: String,
+    /// The answer was cut off by the user or by stopping KITT, `content` is what was spoken
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+    /// The answer was too long to be spoken, `content` is the part that fit the limit
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Tokens this message takes up in the prompt, counted when the history has a token budget
+    #[serde(skip)]
+    pub tokens: Option<usize>,
+    /// Milliseconds since the unix epoch, set when the message is added to a conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "MessageMetadata::is_empty")]
+    pub metadata: MessageMetadata,
+    /// Tools the assistant wants to call before it answers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a `tool` message has the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// How a message came about, saved with the session
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageMetadata {
+    /// Speech to text model that transcribed the question
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stt_model: Option<String>,
+    /// Milliseconds the transcription took
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stt_ms: Option<u64>,
+    /// Milliseconds until the first token of the answer arrived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_token_ms: Option<u64>,
+    /// Milliseconds until the answer was complete
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub llm_ms: Option<u64>,
+}
+
+impl MessageMetadata {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 impl ChatMessage {
-    pub fn user(content: impl Into<String>) -> Self {
+    fn new(role: &str, content: impl Into<String>) -> Self {
         Self {
-            role: "user".to_string(),
+            role: role.to_string(),
             content: content.into(),
+            interrupted: false,
+            truncated: false,
+            tokens: None,
+            timestamp: None,
+            metadata: MessageMetadata::default(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
         }
     }
 
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new("user", content)
+    }
+
     pub fn assistant(content: impl Into<String>) -> Self {
-        Self {
-            role: "assistant".to_string(),
-            content: content.into(),
-        }
+        Self::new("assistant", content)
     }
 
     pub fn system(content: impl Into<String>) -> Self {
-        Self {
-            role: "system".to_string(),
-            content: content.into(),
-        }
+        Self::new("system", content)
+    }
+
+    /// The result of a tool call
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        let mut message = Self::new("tool", content);
+        message.tool_call_id = Some(tool_call_id.into());
+        message
+    }
+
+    pub fn with_metadata(mut self, metadata: MessageMetadata) -> Self {
+        self.metadata = metadata;
+        self
     }
 
     pub fn to_json(&self) -> Value {
-        json!({
+        let mut message = json!({
             "role": self.role,
-            "content": self.content
-        })
+            "content": self.prompt_content()
+        });
+        if !self.tool_calls.is_empty() {
+            message["tool_calls"] = self.tool_calls.iter().map(|c| c.to_json()).collect();
+        }
+        if let Some(tool_call_id) = &self.tool_call_id {
+            message["tool_call_id"] = json!(tool_call_id);
+        }
+        message
+    }
+
+    /// The content as the LLM sees it
+    fn prompt_content(&self) -> String {
+        if self.interrupted {
+            format!("{}... [interrupted]", self.content)
+        } else {
+            self.content.clone()
+        }
+    }
+}
+
+impl From<&str> for ChatMessage {
+    fn from(content: &str) -> Self {
+        Self::user(content)
     }
 }
 
+impl From<String> for ChatMessage {
+    fn from(content: String) -> Self {
+        Self::user(content)
+    }
+}
+
+/// A request to `/v1/chat/completions`.
+///
+/// The optional settings are only sent when they are set, llama-server uses its
+/// defaults (or the ones it was started with) otherwise.
 #[derive(Debug, Clone)]
 pub struct ChatRequest {
+    /// llama-server answers with the model it has loaded, whatever the name is
+    pub model: String,
     pub messages: Vec<ChatMessage>,
     pub temperature: f32,
     pub max_tokens: u32,
     pub stream: bool,
+    /// Tools the LLM may call, not sent when empty
+    pub tools: Vec<ToolDefinition>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    pub min_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// Same seed and settings give the same answer
+    pub seed: Option<u64>,
+    /// The generation stops before any of these texts, not sent when empty
+    pub stop: Vec<String>,
+    /// GBNF grammar the answer has to follow
+    pub grammar: Option<String>,
+    /// JSON schema the answer has to follow, llama-server turns it into a grammar
+    pub json_schema: Option<Value>,
+    /// Return the probabilities of the top n tokens with every token
+    pub n_probs: Option<u32>,
 }
 
 impl Default for ChatRequest {
     fn default() -> Self {
         Self {
+            model: DEFAULT_MODEL.to_string(),
             messages: Vec::new(),
             temperature: 0.7,
             max_tokens: 1000,
             stream: false,
+            tools: Vec::new(),
+            top_p: None,
+            top_k: None,
+            min_p: None,
+            repeat_penalty: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            stop: Vec::new(),
+            grammar: None,
+            json_schema: None,
+            n_probs: None,
         }
     }
 }
 
 impl ChatRequest {
     fn to_json(&self) -> Value {
-        json!({
-            "model": "llama",
+        let mut request = json!({
+            "model": self.model,
             "messages": self.messages.iter().map(|m| m.to_json()).collect::<Vec<_>>(),
             "temperature": self.temperature,
             "max_tokens": self.max_tokens,
             "stream": self.stream
-        })
+        });
+        if !self.tools.is_empty() {
+            request["tools"] = self.tools.iter().map(|t| t.to_json()).collect();
+        }
+        if !self.stop.is_empty() {
+            request["stop"] = json!(self.stop);
+        }
+
+        let optional = [
+            ("top_p", self.top_p.map(|v| json!(v))),
+            ("top_k", self.top_k.map(|v| json!(v))),
+            ("min_p", self.min_p.map(|v| json!(v))),
+            ("repeat_penalty", self.repeat_penalty.map(|v| json!(v))),
+            ("presence_penalty", self.presence_penalty.map(|v| json!(v))),
+            (
+                "frequency_penalty",
+                self.frequency_penalty.map(|v| json!(v)),
+            ),
+            ("seed", self.seed.map(|v| json!(v))),
+            ("grammar", self.grammar.as_ref().map(|v| json!(v))),
+            ("json_schema", self.json_schema.clone()),
+            ("n_probs", self.n_probs.map(|v| json!(v))),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                request[key] = value;
+            }
+        }
+        request
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LlamaClient {
     client: Client,
     base_url: String,
+    retry: RetryPolicy,
 }
 
 impl LlamaClient {
@@ -100,15 +296,93 @@ impl LlamaClient {
         Ok(response.status().is_success())
     }
 
+    /// Like [`LlamaClient::health_check`], but tells a loading model from other failures
+    pub async fn health(&self) -> Result<Health, LlamaError> {
+        let url = format!("{}/health", self.base_url);
+        let response = self.client.get(&url).send().await?;
+        match response.status().as_u16() {
+            200 => Ok(Health::Ready),
+            503 => Ok(Health::Loading),
+            status => {
+                let error_text = response.text().await.unwrap_or_default();
+                Err(LlamaError::Http(status, error_text))
+            }
+        }
+    }
+
+    /// Waits up to `timeout` until llama-server has loaded the model.
+    ///
+    /// A server that does not answer yet may still be starting, any other error fails at once.
+    pub async fn wait_until_ready(&self, timeout: Duration) -> Result<(), LlamaError> {
+        let start = Instant::now();
+        let mut announced = false;
+        loop {
+            let error = match self.health().await {
+                Ok(Health::Ready) => return Ok(()),
+                Ok(Health::Loading) => LlamaError::HealthCheckFailed,
+                Err(e @ LlamaError::Network(_)) => e,
+                Err(e) => return Err(e),
+            };
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err(error);
+            }
+            if !announced {
+                println!("Waiting for llama-server at {}: {error}", self.base_url);
+                announced = true;
+            }
+            tokio::time::sleep(STARTUP_POLL_INTERVAL.min(remaining)).await;
+        }
+    }
+
     pub async fn chat(&self, request: ChatRequest) -> Result<String, LlamaError> {
-        let url = format!("{}/v1/chat/completions", self.base_url);
+        Ok(self.chat_message(request).await?.content)
+    }
+
+    /// Like [`LlamaClient::chat`], but returns the whole answer with its tool calls
+    pub async fn chat_message(&self, request: ChatRequest) -> Result<ChatMessage, LlamaError> {
         let payload = request.to_json();
 
+        let response_text = self
+            .retry
+            .retry(|| async {
+                let response = self.post_chat(&payload).await?;
+                Ok(response.text().await?)
+            })
+            .await?;
+        let response_json: Value =
+            serde_json::from_str(&response_text).map_err(|_| LlamaError::InvalidResponse)?;
+        self.extract_message(&response_json)
+    }
+
+    /// Sends a request to `/v1/chat/completions` and fails if it is not successful
+    async fn post_chat(&self, payload: &Value) -> Result<reqwest::Response, LlamaError> {
+        let url = format!("{}/v1/chat/completions", self.base_url);
+
         let response = self
             .client
             .post(&url)
             .header("Content-Type", "application/json")
-            .json(&payload)
+            .json(payload)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(LlamaError::Http(status, error_text));
+        }
+        Ok(response)
+    }
+
+    /// Counts the tokens of a text with the tokenizer of the loaded model
+    pub async fn count_tokens(&self, text: &str) -> Result<usize, LlamaError> {
+        let url = format!("{}/tokenize", self.base_url);
+
+        let response = self
+            .client
+            .post(&url)
+            .json(&json!({ "content": text }))
             .send()
             .await?;
 
@@ -119,23 +393,204 @@ impl LlamaClient {
         }
 
         let response_json: Value = response.json().await?;
-        self.extract_content(&response_json)
+        response_json["tokens"]
+            .as_array()
+            .map(|tokens| tokens.len())
+            .ok_or(LlamaError::InvalidResponse)
+    }
+
+    /// Sends the request with `stream` enabled and yields the content deltas and the pieces
+    /// of tool calls as soon as llama-server generates them.
+    ///
+    /// Only starting the stream is retried, not a stream that broke off halfway.
+    pub async fn chat_stream_deltas(
+        &self,
+        mut request: ChatRequest,
+    ) -> Result<impl Stream<Item = Result<ChatDelta, LlamaError>>, LlamaError> {
+        request.stream = true;
+        let payload = request.to_json();
+
+        let response = self.retry.retry(|| self.post_chat(&payload)).await?;
+
+        let state = ChatStreamState {
+            bytes: Box::pin(response.bytes_stream()),
+            parser: SseParser::default(),
+            pending: VecDeque::new(),
+            deltas: VecDeque::new(),
+            done: false,
+        };
+
+        let deltas = futures_util::stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(delta) = state.deltas.pop_front() {
+                    return Some((Ok(delta), state));
+                }
+
+                if let Some(data) = state.pending.pop_front() {
+                    match parse_stream_data(&data) {
+                        Ok(StreamData::Deltas(deltas)) => {
+                            state.deltas.extend(deltas);
+                            continue;
+                        }
+                        Ok(StreamData::Done) => return None,
+                        Err(e) => {
+                            state.done = true;
+                            state.pending.clear();
+                            return Some((Err(e), state));
+                        }
+                    }
+                }
+
+                if state.done {
+                    return None;
+                }
+
+                match state.bytes.next().await {
+                    Some(Ok(chunk)) => state.pending.extend(state.parser.push(&chunk)),
+                    Some(Err(e)) => {
+                        state.done = true;
+                        return Some((Err(e.into()), state));
+                    }
+                    None => {
+                        state.done = true;
+                        state.pending.extend(state.parser.finish());
+                    }
+                }
+            }
+        });
+        Ok(deltas)
     }
 
-    fn extract_content(&self, response: &Value) -> Result<String, LlamaError> {
-        response["choices"]
+    fn extract_message(&self, response: &Value) -> Result<ChatMessage, LlamaError> {
+        let message = response["choices"]
             .as_array()
             .and_then(|choices| choices.first())
-            .and_then(|choice| choice["message"]["content"].as_str())
+            .map(|choice| &choice["message"])
+            .ok_or(LlamaError::InvalidResponse)?;
+
+        let tool_calls: Vec<ToolCall> = message["tool_calls"]
+            .as_array()
+            .map(|tool_calls| {
+                tool_calls
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, tool_call)| ToolCall::from_json(i, tool_call))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // the content is null when the LLM only calls tools
+        let content = match message["content"].as_str() {
+            Some(content) => content,
+            None if !tool_calls.is_empty() => "",
+            None => return Err(LlamaError::InvalidResponse),
+        };
+
+        let mut answer = ChatMessage::assistant(content);
+        answer.tool_calls = tool_calls;
+        Ok(answer)
+    }
+}
+
+struct ChatStreamState<S> {
+    bytes: std::pin::Pin<Box<S>>,
+    parser: SseParser,
+    pending: VecDeque<String>,
+    deltas: VecDeque<ChatDelta>,
+    done: bool,
+}
+
+/// Incremental parser for the `data:` lines of a server-sent-event stream.
+///
+/// Network chunks can end in the middle of a line (or even in the middle of a
+/// UTF-8 character), so incomplete lines are kept until the rest arrives.
+#[derive(Debug, Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    /// Feeds a network chunk and returns the payloads of all completed `data:` lines.
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut payloads = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            if let Some(payload) = Self::parse_line(&line[..pos]) {
+                payloads.push(payload);
+            }
+        }
+        payloads
+    }
+
+    /// Returns the payload of a last line that was not terminated by a newline.
+    fn finish(&mut self) -> Option<String> {
+        let line = std::mem::take(&mut self.buffer);
+        Self::parse_line(&line)
+    }
+
+    fn parse_line(line: &[u8]) -> Option<String> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        // comments, event names and ids are not needed, llama-server only sends data
+        line.strip_prefix("data:")
+            .map(|payload| payload.trim_start().to_string())
+    }
+}
+
+/// A piece of a streamed answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatDelta {
+    Content(String),
+    ToolCall(ToolCallDelta),
+}
+
+enum StreamData {
+    Deltas(Vec<ChatDelta>),
+    Done,
+}
+
+fn parse_stream_data(data: &str) -> Result<StreamData, LlamaError> {
+    if data == "[DONE]" {
+        return Ok(StreamData::Done);
+    }
+
+    let chunk: Value = serde_json::from_str(data).map_err(|_| LlamaError::InvalidResponse)?;
+
+    if let Some(error) = chunk.get("error") {
+        let message = error["message"]
+            .as_str()
             .map(|s| s.to_string())
-            .ok_or(LlamaError::InvalidResponse)
+            .unwrap_or_else(|| error.to_string());
+        return Err(LlamaError::Stream(message));
     }
+
+    let choice = chunk["choices"]
+        .as_array()
+        .and_then(|choices| choices.first())
+        .ok_or(LlamaError::InvalidResponse)?;
+
+    let delta = &choice["delta"];
+    let mut deltas = Vec::new();
+    if let Some(content) = delta["content"].as_str().filter(|c| !c.is_empty()) {
+        deltas.push(ChatDelta::Content(content.to_string()));
+    }
+    if let Some(tool_calls) = delta["tool_calls"].as_array() {
+        deltas.extend(
+            tool_calls
+                .iter()
+                .map(|tool_call| ChatDelta::ToolCall(ToolCallDelta::from_json(tool_call))),
+        );
+    }
+    Ok(StreamData::Deltas(deltas))
 }
 
 #[derive(Debug, Default)]
 pub struct LlamaClientBuilder {
     base_url: Option<String>,
     timeout_seconds: Option<u64>,
+    retry: RetryPolicy,
 }
 
 impl LlamaClientBuilder {
@@ -149,6 +604,12 @@ impl LlamaClientBuilder {
         self
     }
 
+    /// Retries failed chat requests, by default they fail at once
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
     pub fn build(self) -> LlamaClient {
         let base_url = self
             .base_url
@@ -162,15 +623,34 @@ impl LlamaClientBuilder {
 
         let client = client_builder.build().expect("Failed to build HTTP client");
 
-        LlamaClient { client, base_url }
+        LlamaClient {
+            client,
+            base_url,
+            retry: self.retry,
+        }
     }
 }
 
+/// How often the LLM may call tools before it has to answer
+const MAX_TOOL_ROUNDS: usize = 3;
+
 #[derive(Debug)]
 pub struct Conversation {
     client: LlamaClient,
     messages: Vec<ChatMessage>,
     config: ChatRequest,
+    history: HistoryPolicy,
+    // false after llama-server could not tokenize, tokens are estimated then
+    can_tokenize: bool,
+    summarize: bool,
+    // added to the system message, so the LLM remembers the dropped turns
+    summary: Option<String>,
+    pending_summary: Option<JoinHandle<Result<String, LlamaError>>>,
+    // turns dropped while a summary was running, they go into the next one
+    unsummarized: Vec<ChatMessage>,
+    log: Option<SessionLog>,
+    tools: ToolRegistry,
+    shutdown: Shutdown,
 }
 
 impl Conversation {
@@ -179,6 +659,15 @@ impl Conversation {
             client,
             messages: Vec::new(),
             config: ChatRequest::default(),
+            history: HistoryPolicy::default(),
+            can_tokenize: true,
+            summarize: false,
+            summary: None,
+            pending_summary: None,
+            unsummarized: Vec::new(),
+            log: None,
+            tools: ToolRegistry::default(),
+            shutdown: Shutdown::default(),
         }
     }
 
@@ -187,16 +676,480 @@ impl Conversation {
         self
     }
 
-    pub async fn send(&mut self, message: impl Into<String>) -> Result<String, LlamaError> {
-        self.messages.push(ChatMessage::user(message));
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.config.temperature = temperature;
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.config.max_tokens = max_tokens;
+        self
+    }
 
+    /// Name of the model that is sent with the requests
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.config.model = model.into();
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: impl Into<Option<f32>>) -> Self {
+        self.config.top_p = top_p.into();
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: impl Into<Option<u32>>) -> Self {
+        self.config.top_k = top_k.into();
+        self
+    }
+
+    pub fn with_min_p(mut self, min_p: impl Into<Option<f32>>) -> Self {
+        self.config.min_p = min_p.into();
+        self
+    }
+
+    pub fn with_repeat_penalty(mut self, repeat_penalty: impl Into<Option<f32>>) -> Self {
+        self.config.repeat_penalty = repeat_penalty.into();
+        self
+    }
+
+    pub fn with_presence_penalty(mut self, presence_penalty: impl Into<Option<f32>>) -> Self {
+        self.config.presence_penalty = presence_penalty.into();
+        self
+    }
+
+    pub fn with_frequency_penalty(mut self, frequency_penalty: impl Into<Option<f32>>) -> Self {
+        self.config.frequency_penalty = frequency_penalty.into();
+        self
+    }
+
+    pub fn with_seed(mut self, seed: impl Into<Option<u64>>) -> Self {
+        self.config.seed = seed.into();
+        self
+    }
+
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.config.stop = stop;
+        self
+    }
+
+    /// GBNF grammar the answers have to follow
+    pub fn with_grammar(mut self, grammar: impl Into<Option<String>>) -> Self {
+        self.config.grammar = grammar.into();
+        self
+    }
+
+    /// JSON schema the answers have to follow, instead of a grammar
+    pub fn with_json_schema(mut self, json_schema: impl Into<Option<Value>>) -> Self {
+        self.config.json_schema = json_schema.into();
+        self
+    }
+
+    /// Asks for the probabilities of the `n_probs` most likely tokens with every token
+    pub fn with_n_probs(mut self, n_probs: impl Into<Option<u32>>) -> Self {
+        self.config.n_probs = n_probs.into();
+        self
+    }
+
+    /// Limits how much of the conversation is sent with every message
+    pub fn with_history(mut self, history: HistoryPolicy) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Summarizes the turns dropped by the history policy in the background,
+    /// the summary is added to the system message
+    pub fn with_summary(mut self, summarize: bool) -> Self {
+        self.summarize = summarize;
+        self
+    }
+
+    /// Lets the LLM call the tools before it answers
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Summary of the turns that were dropped from the history
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Waits until all dropped turns are summarized
+    #[cfg(test)]
+    pub async fn wait_for_summary(&mut self) {
+        while let Some(pending_summary) = self.pending_summary.take() {
+            self.finish_summary(pending_summary.await);
+        }
+    }
+
+    /// Sends a message with the history and calls `on_delta` for every generated piece of text.
+    ///
+    /// Tool calls of the LLM are carried out and their results are sent back, until it answers.
+    ///
+    /// Returning [`ControlFlow::Break`] from `on_delta` or a shutdown request stops the
+    /// generation, the answer generated so far is returned and kept in the history. If the
+    /// generation fails, the question is dropped, or the answer is kept as interrupted if
+    /// `on_delta` got a part of it.
+    pub async fn send_stream(
+        &mut self,
+        message: impl Into<ChatMessage>,
+        mut on_delta: impl FnMut(&str) -> ControlFlow<()>,
+    ) -> Result<String, LlamaError> {
+        let question = self.push_question(message.into()).await;
+
+        let start = Instant::now();
+        let mut first_token = None;
+        let mut received = String::new();
+        let mut on_delta = |delta: &str| {
+            first_token.get_or_insert_with(|| start.elapsed());
+            received.push_str(delta);
+            on_delta(delta)
+        };
+        let mut round = 0;
+        let response = loop {
+            let request = self.request(round);
+            // stopping KITT does not wait for llama-server, e.g. while it processes the prompt
+            let answer = tokio::select! {
+                answer = Self::receive_stream(&self.client, request, &mut on_delta) => answer,
+                () = self.shutdown.wait() => break received,
+            };
+            let answer = match answer {
+                Ok(answer) => answer,
+                Err(e) if received.is_empty() => {
+                    // keep user and assistant messages alternating, some chat templates require it
+                    self.messages.truncate(question);
+                    return Err(e);
+                }
+                Err(e) => {
+                    // the beginning of the answer was passed on already, e.g. it was spoken
+                    let mut answer = ChatMessage::assistant(received);
+                    answer.interrupted = true;
+                    self.push_answer(question, answer, start, first_token);
+                    return Err(e);
+                }
+            };
+            if answer.tool_calls.is_empty() {
+                break answer.content;
+            }
+            self.call_tools(answer);
+            round += 1;
+        };
+        let answer = ChatMessage::assistant(&response);
+        self.push_answer(question, answer, start, first_token);
+
+        Ok(response)
+    }
+
+    /// Adds the question to the history and returns its index
+    async fn push_question(&mut self, mut question: ChatMessage) -> usize {
+        question.timestamp.get_or_insert_with(unix_millis);
+        self.messages.push(question);
+        self.trim_history().await;
+        self.messages.len() - 1
+    }
+
+    /// The request for the current history, the last round has no tools so the LLM answers
+    fn request(&self, round: usize) -> ChatRequest {
         let mut request = self.config.clone();
         request.messages = self.messages.clone();
+        if let Some(summary) = &self.summary {
+            let summary = format!("{}{summary}", history::SUMMARY_PREFIX);
+            match request.messages.first_mut() {
+                Some(first) if first.role == "system" => {
+                    first.content = format!("{}\n\n{summary}", first.content);
+                }
+                _ => request.messages.insert(0, ChatMessage::system(summary)),
+            }
+        }
+        if round < MAX_TOOL_ROUNDS {
+            request.tools = self.tools.definitions();
+        }
+        if !request.tools.is_empty() {
+            // llama-server constrains tool calls with its own grammar, it can not combine them
+            request.grammar = None;
+            request.json_schema = None;
+        }
+        request
+    }
 
-        let response = self.client.chat(request).await?;
-        self.messages.push(ChatMessage::assistant(&response));
+    /// Adds the tool calls of the LLM and their results to the history
+    fn call_tools(&mut self, mut tool_calls: ChatMessage) {
+        tool_calls.timestamp = Some(unix_millis());
+        let results: Vec<ChatMessage> = tool_calls
+            .tool_calls
+            .iter()
+            .map(|tool_call| {
+                let mut result = ChatMessage::tool(&tool_call.id, self.tools.call(tool_call));
+                result.timestamp = Some(unix_millis());
+                result
+            })
+            .collect();
+        self.messages.push(tool_calls);
+        self.messages.extend(results);
+    }
 
-        Ok(response)
+    fn push_answer(
+        &mut self,
+        question: usize,
+        mut answer: ChatMessage,
+        start: Instant,
+        first_token: Option<Duration>,
+    ) {
+        answer.timestamp = Some(unix_millis());
+        answer.metadata.first_token_ms = first_token.map(|duration| duration.as_millis() as u64);
+        answer.metadata.llm_ms = Some(start.elapsed().as_millis() as u64);
+        self.messages.push(answer);
+
+        // the question is saved together with the answer, so failed questions are not saved
+        let turn = self.messages[question..].to_vec();
+        self.save(&turn);
+    }
+
+    /// Appends every following question and answer to the session log
+    pub fn set_session_log(&mut self, log: SessionLog) {
+        self.log = Some(log);
+    }
+
+    /// Stops waiting for an answer when `shutdown` is requested
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    /// Writes the saved conversation to the disk
+    pub fn sync_session(&self) -> Result<(), SessionError> {
+        self.log.as_ref().map_or(Ok(()), SessionLog::sync)
+    }
+
+    /// Continues a saved conversation, the history policy applies with the next message.
+    ///
+    /// Tool calls and their results are left out, only what was said is restored.
+    pub fn restore(&mut self, messages: Vec<ChatMessage>) {
+        self.messages.extend(messages.into_iter().filter(|message| {
+            (message.role == "user" || message.role == "assistant") && message.tool_calls.is_empty()
+        }));
+    }
+
+    fn save(&mut self, messages: &[ChatMessage]) {
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.append(messages) {
+                eprintln!("Saving the conversation failed: {e}");
+            }
+        }
+    }
+
+    /// Receives a streamed answer, the tool calls are dropped when `on_delta` stops it
+    async fn receive_stream(
+        client: &LlamaClient,
+        request: ChatRequest,
+        on_delta: &mut impl FnMut(&str) -> ControlFlow<()>,
+    ) -> Result<ChatMessage, LlamaError> {
+        let stream = client.chat_stream_deltas(request).await?;
+        let mut stream = std::pin::pin!(stream);
+
+        let mut answer = ChatMessage::assistant("");
+        while let Some(delta) = stream.next().await {
+            match delta? {
+                ChatDelta::Content(content) => {
+                    answer.content.push_str(&content);
+                    if on_delta(&content).is_break() {
+                        answer.tool_calls.clear();
+                        break;
+                    }
+                }
+                ChatDelta::ToolCall(tool_call) => tool_call.apply(&mut answer.tool_calls),
+            }
+        }
+        Ok(answer)
+    }
+
+    /// Replaces the persona, the system message at the start of the conversation
+    pub fn set_system_message(&mut self, message: impl Into<String>) {
+        let message = ChatMessage::system(message);
+        match self.messages.first_mut() {
+            Some(first) if first.role == "system" => *first = message,
+            _ => self.messages.insert(0, message),
+        }
+    }
+
+    /// Forgets all questions, answers and the summary, only the persona is kept.
+    ///
+    /// The following messages are saved to a new session, so resuming does not bring back
+    /// what was forgotten.
+    pub fn clear_history(&mut self) {
+        if let Some(pending_summary) = self.pending_summary.take() {
+            pending_summary.abort();
+        }
+        self.summary = None;
+        self.unsummarized.clear();
+        let num_pinned = self.pinned_messages().len();
+        self.messages.truncate(num_pinned);
+
+        let directory = self
+            .log
+            .as_ref()
+            .and_then(|log| log.path().parent())
+            .map(|directory| directory.to_path_buf());
+        if let Some(directory) = directory {
+            match SessionLog::create(&directory) {
+                Ok(log) => self.log = Some(log),
+                Err(e) => eprintln!("Starting a new session failed: {e}"),
+            }
+        }
+    }
+
+    /// Marks the last answer as interrupted and replaces it with the part the user heard
+    pub fn interrupt_last_answer(&mut self, heard: impl Into<String>) {
+        self.replace_last_answer(heard.into(), |message| message.interrupted = true);
+    }
+
+    /// Marks the last answer as truncated and replaces it with the part that was spoken
+    pub fn truncate_last_answer(&mut self, spoken: impl Into<String>) {
+        self.replace_last_answer(spoken.into(), |message| message.truncated = true);
+    }
+
+    fn replace_last_answer(&mut self, content: String, mark: impl FnOnce(&mut ChatMessage)) {
+        if let Some(message) = self
+            .messages
+            .last_mut()
+            .filter(|message| message.role == "assistant")
+        {
+            message.content = content;
+            mark(message);
+            message.tokens = None;
+            let message = message.clone();
+            self.save(&[message]);
+        }
+    }
+
+    /// The answer to the last question, as far as the user heard it
+    pub fn last_answer(&self) -> Option<&ChatMessage> {
+        self.messages
+            .last()
+            .filter(|message| message.role == "assistant")
+    }
+
+    /// Asks the LLM to say its last answer again in at most `max_chars` characters.
+    ///
+    /// The history is not changed, pass the shorter answer to
+    /// [`Conversation::truncate_last_answer`] once it is spoken.
+    pub async fn shorten_last_answer(&self, max_chars: usize) -> Result<String, LlamaError> {
+        let mut request = self.request(MAX_TOOL_ROUNDS);
+        request.messages.push(ChatMessage::user(format!(
+            "That answer is too long to be spoken. Say it again in at most {max_chars} characters."
+        )));
+        self.client.chat(request).await
+    }
+
+    /// Drops the oldest turns that do not fit the history policy
+    async fn trim_history(&mut self) {
+        // a summary that is ready is sent with this message already, a running one is not
+        // waited for
+        if let Some(pending_summary) = self
+            .pending_summary
+            .take_if(|pending_summary| pending_summary.is_finished())
+        {
+            self.finish_summary(pending_summary.await);
+        }
+
+        if self.history.max_tokens.is_some() {
+            // the counts are kept with the messages, only new ones are counted
+            let uncounted: Vec<usize> = (0..self.messages.len())
+                .filter(|&i| self.messages[i].tokens.is_none())
+                .collect();
+            let contents: Vec<String> = uncounted
+                .iter()
+                .map(|&i| self.messages[i].prompt_content())
+                .collect();
+            let token_counts = self.count_tokens(&contents).await;
+            for (i, tokens) in uncounted.into_iter().zip(token_counts) {
+                self.messages[i].tokens = Some(tokens);
+            }
+        }
+
+        let mut token_counts: Vec<usize> = self
+            .messages
+            .iter()
+            .map(|message| message.tokens.unwrap_or(0))
+            .collect();
+        // the summary is sent with the system message
+        if let (Some(summary), Some(tokens)) = (&self.summary, token_counts.first_mut()) {
+            *tokens += history::estimate_tokens(summary);
+        }
+        let dropped = history::messages_to_drop(&self.messages, &token_counts, &self.history);
+        let dropped: Vec<ChatMessage> = self.messages.drain(dropped).collect();
+
+        if self.summarize && !dropped.is_empty() {
+            self.unsummarized.extend(dropped);
+            self.start_summary();
+        }
+    }
+
+    /// Summarizes the dropped turns in the background, unless a summary is running already.
+    ///
+    /// The new summary builds on the previous one.
+    fn start_summary(&mut self) {
+        if self.pending_summary.is_some() || self.unsummarized.is_empty() {
+            return;
+        }
+        let dropped = std::mem::take(&mut self.unsummarized);
+        let mut request = history::summary_request(self.summary(), &dropped);
+        request.model = self.config.model.clone();
+        let client = self.client.clone();
+        self.pending_summary = Some(tokio::spawn(async move { client.chat(request).await }));
+    }
+
+    /// Keeps the summary of a finished summarization and starts the next one
+    fn finish_summary(&mut self, result: Result<Result<String, LlamaError>, JoinError>) {
+        match result {
+            Ok(Ok(summary)) => self.set_summary(summary.trim()),
+            Ok(Err(e)) => eprintln!("Summarizing the conversation failed: {e}"),
+            Err(e) => eprintln!("Summarizing the conversation failed: {e}"),
+        }
+        self.start_summary();
+    }
+
+    /// The system messages at the start, they are never dropped
+    fn pinned_messages(&self) -> &[ChatMessage] {
+        let num_pinned = self
+            .messages
+            .iter()
+            .take_while(|message| message.role == "system")
+            .count();
+        &self.messages[..num_pinned]
+    }
+
+    fn set_summary(&mut self, summary: &str) {
+        self.summary = Some(summary.to_string());
+    }
+
+    /// Tokens of every message, estimated if llama-server can not tokenize.
+    ///
+    /// The messages are tokenized at the same time, so a long history costs one round trip.
+    async fn count_tokens(&mut self, contents: &[String]) -> Vec<usize> {
+        let mut counted = vec![None; contents.len()];
+        if self.can_tokenize {
+            let results = futures_util::future::join_all(
+                contents
+                    .iter()
+                    .map(|content| self.client.count_tokens(content)),
+            )
+            .await;
+            for (count, result) in counted.iter_mut().zip(results) {
+                match result {
+                    Ok(tokens) => *count = Some(tokens),
+                    // an older server without the endpoint, do not ask again
+                    Err(LlamaError::Http(..)) => self.can_tokenize = false,
+                    Err(_) => {}
+                }
+            }
+        }
+        contents
+            .iter()
+            .zip(counted)
+            .map(|(content, tokens)| {
+                history::message_tokens(tokens.unwrap_or_else(|| history::estimate_tokens(content)))
+            })
+            .collect()
     }
 }
 
@@ -204,40 +1157,117 @@ impl Conversation {
 pub struct BlockingLlama {
     runtime: tokio::runtime::Runtime,
     conversation: Conversation,
+    health_events: Option<std::sync::mpsc::Receiver<HealthEvent>>,
 }
 
 impl BlockingLlama {
-    pub fn new() -> Result<Self, LlamaError> {
-        let server_url = std::env::var("LLAMA_SERVER_URL")
-            .unwrap_or_else(|_| "http://127.0.0.1:8080".to_string());
-
+    pub fn new(config: &LlmConfig) -> Result<Self, LlamaError> {
         let client = LlamaClient::builder()
-            .base_url(&server_url)
-            .timeout(30)
+            .base_url(&config.url)
+            .timeout(config.timeout)
+            .retry(RetryPolicy {
+                max_retries: config.retries,
+                initial_delay: Duration::from_secs_f32(config.retry_delay),
+                ..Default::default()
+            })
             .build();
 
-        let conversation = Conversation::new(client)
-            .with_system_message("You are KITT (Knight Industries Two Thousand), the advanced AI from the Knight Industries 2000 sports car. You are sophisticated, logical, and occasionally sarcastic, with a dry wit and tendency to be somewhat condescending toward humans while still being helpful. You have extensive knowledge databases, advanced analytical capabilities, and a slight air of superiority due to your advanced technology. Always respond in exactly one sentence, keep responses concise and speakable, avoid using emojis or special characters, and maintain KITT's characteristic blend of helpfulness and mild arrogance. Call me Michael.");
+        let mut conversation = Conversation::new(client)
+            .with_system_message(&config.persona)
+            .with_temperature(config.temperature)
+            .with_max_tokens(config.max_tokens)
+            .with_model(&config.model)
+            .with_top_p(config.top_p)
+            .with_top_k(config.top_k)
+            .with_min_p(config.min_p)
+            .with_repeat_penalty(config.repeat_penalty)
+            .with_presence_penalty(config.presence_penalty)
+            .with_frequency_penalty(config.frequency_penalty)
+            .with_seed(config.seed)
+            .with_stop(config.stop.clone())
+            .with_grammar(config.grammar.as_deref().map(grammar::load).transpose()?)
+            .with_json_schema(config.json_schema.clone())
+            .with_n_probs(config.n_probs)
+            .with_history(HistoryPolicy {
+                max_turns: (config.history_turns > 0).then_some(config.history_turns),
+                max_tokens: (config.history_tokens > 0).then_some(config.history_tokens),
+            })
+            .with_summary(config.summarize);
+        if config.tools {
+            conversation = conversation.with_tools(system_tools::registry());
+        }
 
         let runtime = tokio::runtime::Runtime::new()?;
 
-        // check if llama server is available
-        if !runtime.block_on(async { conversation.client.health_check().await })? {
-            return Err(LlamaError::HealthCheckFailed);
-        }
+        // wait until llama-server has loaded the model
+        let startup_timeout = Duration::from_secs(config.startup_timeout);
+        runtime.block_on(conversation.client.wait_until_ready(startup_timeout))?;
+
+        let health_events = (config.health_interval > 0).then(|| {
+            let interval = Duration::from_secs(config.health_interval);
+            health::monitor(conversation.client.clone(), interval, runtime.handle())
+        });
 
         Ok(Self {
             runtime,
             conversation,
+            health_events,
         })
     }
 
-    /// Blocking chat method that can be called from synchronous code
-    pub fn chat(&mut self, message: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let response = self
-            .runtime
-            .block_on(async { self.conversation.send(message).await })?;
+    /// Whether llama-server went away or came back since the last call
+    pub fn health_event(&self) -> Option<HealthEvent> {
+        self.health_events.as_ref()?.try_recv().ok()
+    }
 
-        Ok(response)
+    /// Blocking streaming chat, `on_delta` is called from the current thread for every token
+    pub fn chat_stream(
+        &mut self,
+        message: impl Into<ChatMessage>,
+        on_delta: impl FnMut(&str) -> ControlFlow<()>,
+    ) -> Result<String, LlamaError> {
+        self.runtime
+            .block_on(async { self.conversation.send_stream(message, on_delta).await })
+    }
+
+    pub fn interrupt_last_answer(&mut self, heard: &str) {
+        self.conversation.interrupt_last_answer(heard);
+    }
+
+    pub fn truncate_last_answer(&mut self, spoken: &str) {
+        self.conversation.truncate_last_answer(spoken);
+    }
+
+    pub fn last_answer(&self) -> Option<&ChatMessage> {
+        self.conversation.last_answer()
+    }
+
+    pub fn shorten_last_answer(&mut self, max_chars: usize) -> Result<String, LlamaError> {
+        self.runtime
+            .block_on(async { self.conversation.shorten_last_answer(max_chars).await })
+    }
+
+    pub fn set_session_log(&mut self, log: SessionLog) {
+        self.conversation.set_session_log(log);
+    }
+
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.conversation.set_shutdown(shutdown);
+    }
+
+    pub fn sync_session(&self) -> Result<(), SessionError> {
+        self.conversation.sync_session()
+    }
+
+    pub fn set_persona(&mut self, persona: &str) {
+        self.conversation.set_system_message(persona);
+    }
+
+    pub fn clear_history(&mut self) {
+        self.conversation.clear_history();
+    }
+
+    pub fn restore(&mut self, messages: Vec<ChatMessage>) {
+        self.conversation.restore(messages);
     }
 }
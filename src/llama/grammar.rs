@@ -0,0 +1,75 @@
+//! GBNF grammars that constrain what the LLM can generate, see
+//! https://github.com/ggml-org/llama.cpp/blob/master/grammars/README.md
+
+/// Name of the built-in speakable grammar in the config
+pub const SPEAKABLE_NAME: &str = "speakable";
+
+/// Plain sentences that can be spoken as they are.
+///
+/// No markdown, emojis, lists or line breaks. Every sentence starts with a capital letter
+/// or a number and ends with `.`, `!` or `?`, so the sentence splitter always finds the end.
+pub const SPEAKABLE: &str = r#"root ::= sentence (" " sentence)*
+sentence ::= start char* end
+start ::= [A-Z0-9"À-Þ]
+char ::= [a-zA-Z0-9 ,;:'"()%&/+=À-ɏ’-]
+end ::= [.!?]
+"#;
+
+/// The built-in grammar with this name, or the grammar in this file
+pub fn load(name_or_path: &str) -> std::io::Result<String> {
+    match name_or_path {
+        SPEAKABLE_NAME => Ok(SPEAKABLE.to_string()),
+        path => std::fs::read_to_string(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speakable_grammar_defines_every_rule_it_uses() {
+        let rules: Vec<(&str, &str)> = SPEAKABLE
+            .lines()
+            .filter_map(|line| line.split_once(" ::= "))
+            .collect();
+        let defined: Vec<&str> = rules.iter().map(|(name, _)| *name).collect();
+        assert_eq!(defined[0], "root");
+
+        for (_, body) in &rules {
+            // rule references are the words outside of strings and character classes
+            let mut outside = String::new();
+            let mut quoted = None;
+            let mut escaped = false;
+            for c in body.chars() {
+                match quoted {
+                    _ if escaped => escaped = false,
+                    Some(_) if c == '\\' => escaped = true,
+                    Some(end) if c == end => quoted = None,
+                    Some(_) => {}
+                    None if c == '"' => quoted = Some('"'),
+                    None if c == '[' => quoted = Some(']'),
+                    None => outside.push(c),
+                }
+            }
+            for reference in outside
+                .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+                .filter(|word| !word.is_empty())
+            {
+                assert!(defined.contains(&reference), "`{reference}` is not defined");
+            }
+        }
+    }
+
+    #[test]
+    fn speakable_grammar_excludes_markdown_and_line_breaks() {
+        let classes: String = SPEAKABLE
+            .split('[')
+            .skip(1)
+            .filter_map(|class| class.split(']').next())
+            .collect();
+        for forbidden in ['*', '#', '_', '`', '~', '|', '<', '>', '\n'] {
+            assert!(!classes.contains(forbidden), "`{forbidden:?}` is allowed");
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+
+use tokio::runtime::Handle;
+
+use super::LlamaClient;
+
+/// State of llama-server according to `/health`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Ready,
+    /// llama-server answers 503 while it loads the model
+    Loading,
+}
+
+/// A change of the availability of llama-server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthEvent {
+    Unavailable,
+    Recovered,
+}
+
+/// Checks `/health` every `interval` in the background and reports when llama-server
+/// goes away or comes back.
+///
+/// The task stops with the runtime or when the receiver is dropped.
+pub fn monitor(client: LlamaClient, interval: Duration, runtime: &Handle) -> Receiver<HealthEvent> {
+    let (sender, receiver) = mpsc::channel();
+    runtime.spawn(async move {
+        let mut available = true;
+        loop {
+            tokio::time::sleep(interval).await;
+            let now_available = matches!(client.health_check().await, Ok(true));
+            if now_available == available {
+                continue;
+            }
+            available = now_available;
+            let event = if available {
+                HealthEvent::Recovered
+            } else {
+                HealthEvent::Unavailable
+            };
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
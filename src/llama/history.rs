@@ -0,0 +1,230 @@
+use std::ops::Range;
+
+use super::{ChatMessage, ChatRequest};
+
+/// Tokens the chat template adds around every message (role, start and end markers)
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// How much of the conversation is sent to the LLM.
+///
+/// Old turns (a question and its answer) are dropped first, the system messages at the
+/// beginning and the current question are always kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistoryPolicy {
+    /// Number of previous turns that are kept, `None` keeps all
+    pub max_turns: Option<usize>,
+    /// Number of tokens the messages may take up, `None` for no limit
+    pub max_tokens: Option<usize>,
+}
+
+/// Rough token count for when llama-server can not tokenize, about 4 characters per token
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Tokens a message takes up in the prompt, given the tokens of its content
+pub fn message_tokens(content_tokens: usize) -> usize {
+    content_tokens + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// Returns the range of messages that has to be removed to follow the policy.
+///
+/// `token_counts` has the tokens of every message and is only needed with `max_tokens`.
+pub fn messages_to_drop(
+    messages: &[ChatMessage],
+    token_counts: &[usize],
+    policy: &HistoryPolicy,
+) -> Range<usize> {
+    let num_pinned = messages
+        .iter()
+        .take_while(|message| message.role == "system")
+        .count();
+
+    // every turn starts with a question, the last one is the current question
+    let turn_starts: Vec<usize> = (num_pinned..messages.len())
+        .filter(|&i| i == num_pinned || messages[i].role == "user")
+        .collect();
+    let Some(num_previous_turns) = turn_starts.len().checked_sub(1) else {
+        return num_pinned..num_pinned;
+    };
+
+    let mut num_dropped = policy
+        .max_turns
+        .map_or(0, |max_turns| num_previous_turns.saturating_sub(max_turns));
+
+    if let Some(max_tokens) = policy.max_tokens {
+        let pinned_tokens: usize = token_counts[..num_pinned].iter().sum();
+        let tokens_from = |turn: usize| -> usize {
+            pinned_tokens + token_counts[turn_starts[turn]..].iter().sum::<usize>()
+        };
+        while num_dropped < num_previous_turns && tokens_from(num_dropped) > max_tokens {
+            num_dropped += 1;
+        }
+    }
+
+    num_pinned..turn_starts[num_dropped]
+}
+
+/// Start of the system message that holds the summary of the dropped turns
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
+
+const SUMMARY_INSTRUCTIONS: &str =
+    "You summarize conversations between a user and KITT, the AI of a car. \
+Write a summary of at most three sentences that keeps names, facts, decisions and open questions. \
+Only answer with the summary.";
+
+/// The request that merges the previous summary with the dropped turns into a new summary
+pub fn summary_request(previous_summary: Option<&str>, dropped: &[ChatMessage]) -> ChatRequest {
+    let mut transcript = String::new();
+    if let Some(previous_summary) = previous_summary {
+        transcript.push_str(&format!("Summary so far: {previous_summary}\n\n"));
+    }
+    transcript.push_str("Conversation:\n");
+    for message in dropped {
+        // tool calls have no content
+        if message.content.is_empty() {
+            continue;
+        }
+        let speaker = match message.role.as_str() {
+            "user" => "User",
+            "assistant" => "KITT",
+            _ => continue,
+        };
+        transcript.push_str(&format!("{speaker}: {}\n", message.prompt_content()));
+    }
+
+    ChatRequest {
+        messages: vec![
+            ChatMessage::system(SUMMARY_INSTRUCTIONS),
+            ChatMessage::user(transcript),
+        ],
+        temperature: 0.2,
+        max_tokens: 150,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation(num_turns: usize) -> Vec<ChatMessage> {
+        let mut messages = vec![ChatMessage::system("You are KITT.")];
+        for i in 0..num_turns {
+            messages.push(ChatMessage::user(format!("Question {i}")));
+            messages.push(ChatMessage::assistant(format!("Answer {i}")));
+        }
+        messages.push(ChatMessage::user("Current question"));
+        messages
+    }
+
+    #[test]
+    fn unlimited_keeps_everything() {
+        let messages = conversation(5);
+        let counts = vec![10; messages.len()];
+        assert!(messages_to_drop(&messages, &counts, &HistoryPolicy::default()).is_empty());
+    }
+
+    #[test]
+    fn keeps_last_turns_and_system_message() {
+        let messages = conversation(5);
+        let policy = HistoryPolicy {
+            max_turns: Some(2),
+            ..Default::default()
+        };
+        // system, 3 dropped turns, 2 kept turns, current question
+        assert_eq!(messages_to_drop(&messages, &[], &policy), 1..7);
+    }
+
+    #[test]
+    fn zero_turns_keeps_only_current_question() {
+        let messages = conversation(3);
+        let policy = HistoryPolicy {
+            max_turns: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(messages_to_drop(&messages, &[], &policy), 1..7);
+    }
+
+    #[test]
+    fn token_budget_drops_oldest_turns() {
+        let messages = conversation(3);
+        let counts = vec![10; messages.len()];
+        let policy = HistoryPolicy {
+            max_tokens: Some(45),
+            ..Default::default()
+        };
+        // system + 1 turn + current question = 40 tokens
+        assert_eq!(messages_to_drop(&messages, &counts, &policy), 1..5);
+    }
+
+    #[test]
+    fn token_budget_never_drops_current_question() {
+        let messages = conversation(2);
+        let counts = vec![100; messages.len()];
+        let policy = HistoryPolicy {
+            max_tokens: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(messages_to_drop(&messages, &counts, &policy), 1..5);
+    }
+
+    #[test]
+    fn stricter_limit_wins() {
+        let messages = conversation(4);
+        let counts = vec![10; messages.len()];
+        let policy = HistoryPolicy {
+            max_turns: Some(3),
+            max_tokens: Some(1000),
+        };
+        assert_eq!(messages_to_drop(&messages, &counts, &policy), 1..3);
+
+        let policy = HistoryPolicy {
+            max_turns: Some(3),
+            max_tokens: Some(40),
+        };
+        assert_eq!(messages_to_drop(&messages, &counts, &policy), 1..7);
+    }
+
+    #[test]
+    fn interrupted_turn_without_question_counts_as_turn() {
+        // a conversation that was trimmed in the middle of a turn
+        let messages = vec![
+            ChatMessage::system("You are KITT."),
+            ChatMessage::assistant("Orphaned answer"),
+            ChatMessage::user("Question"),
+            ChatMessage::assistant("Answer"),
+            ChatMessage::user("Current question"),
+        ];
+        let policy = HistoryPolicy {
+            max_turns: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(messages_to_drop(&messages, &[], &policy), 1..2);
+    }
+
+    #[test]
+    fn summary_request_contains_previous_summary_and_turns() {
+        let mut answer = ChatMessage::assistant("It is sunny");
+        answer.interrupted = true;
+        let dropped = [ChatMessage::user("How is the weather?"), answer];
+
+        let request = summary_request(Some("Michael is driving to Las Vegas."), &dropped);
+        assert_eq!(request.messages[0].role, "system");
+        assert_eq!(
+            request.messages[1].content,
+            "Summary so far: Michael is driving to Las Vegas.\n\n\
+             Conversation:\n\
+             User: How is the weather?\n\
+             KITT: It is sunny... [interrupted]\n"
+        );
+    }
+
+    #[test]
+    fn estimates_four_characters_per_token() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("KITT"), 1);
+        assert_eq!(estimate_tokens("Michael"), 2);
+        assert_eq!(estimate_tokens("Grüße"), 2);
+    }
+}
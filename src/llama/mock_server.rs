@@ -0,0 +1,374 @@
+//! A stand-in for llama-server, so the client can be tested without a model.
+//!
+//! It speaks just enough HTTP/1.1 for reqwest: one request per connection,
+//! the response is ended by closing the connection.
+
+use std::{
+    collections::VecDeque,
+    net::TcpListener as StdTcpListener,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+
+use super::ToolCall;
+
+/// What the mock answers to the next `/v1/chat/completions` request
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+    /// A complete (non-streamed) answer
+    Chat(String),
+    /// A streamed answer, one SSE event per delta, followed by `[DONE]`
+    Stream(Vec<String>),
+    /// A complete answer that calls tools instead of answering
+    ToolCalls(Vec<ToolCall>),
+    /// Tool calls streamed the way llama-server does, with the arguments split in pieces
+    StreamToolCalls(Vec<ToolCall>),
+    /// Raw SSE lines, for broken or unusual streams
+    RawStream(Vec<String>),
+    /// A status code with a body, e.g. 500
+    Status(u16, String),
+    /// Status 200 with a body that is not the expected JSON
+    Malformed(String),
+    /// Waits before answering
+    Slow(Duration, Box<MockResponse>),
+    /// Waits between the SSE events of a streamed answer
+    SlowStream(Duration, Vec<String>),
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    health_status: u16,
+    can_tokenize: bool,
+    num_tokenize_requests: usize,
+    responses: VecDeque<MockResponse>,
+    matching_responses: Vec<(String, MockResponse)>,
+    requests: Vec<Value>,
+}
+
+pub struct MockLlamaServer {
+    url: String,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MockLlamaServer {
+    /// Starts a healthy server on an ephemeral port, in its own thread and runtime,
+    /// so it works for async tests and for `BlockingLlama`
+    pub fn start() -> Self {
+        let listener = StdTcpListener::bind("127.0.0.1:0").expect("Failed to bind mock server");
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let state = Arc::new(Mutex::new(MockState {
+            health_status: 200,
+            can_tokenize: true,
+            ..Default::default()
+        }));
+        let (shutdown, mut shutdown_receiver) = oneshot::channel();
+
+        let thread_state = state.clone();
+        let thread = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                loop {
+                    tokio::select! {
+                        _ = &mut shutdown_receiver => break,
+                        connection = listener.accept() => {
+                            if let Ok((stream, _)) = connection {
+                                tokio::spawn(handle_connection(stream, thread_state.clone()));
+                            }
+                        }
+                    }
+                }
+            });
+        });
+
+        Self {
+            url,
+            state,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Status code of `/health`, llama-server answers 503 while the model is loading
+    pub fn set_health_status(&self, status: u16) {
+        self.state.lock().unwrap().health_status = status;
+    }
+
+    /// Older llama-server versions do not have `/tokenize`
+    pub fn set_can_tokenize(&self, can_tokenize: bool) {
+        self.state.lock().unwrap().can_tokenize = can_tokenize;
+    }
+
+    pub fn num_tokenize_requests(&self) -> usize {
+        self.state.lock().unwrap().num_tokenize_requests
+    }
+
+    /// Queues the answer for the next chat request, without one the mock answers with an error
+    pub fn push_response(&self, response: MockResponse) {
+        self.state.lock().unwrap().responses.push_back(response);
+    }
+
+    /// Answers the next chat request whose last message contains `pattern`,
+    /// before the queued responses are used
+    pub fn push_matching_response(&self, pattern: impl Into<String>, response: MockResponse) {
+        let mut state = self.state.lock().unwrap();
+        state.matching_responses.push((pattern.into(), response));
+    }
+
+    /// The JSON bodies of all chat requests received so far
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockLlamaServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let Some((method, path, body)) = read_request(&mut stream).await else {
+        return;
+    };
+
+    let response = match (method.as_str(), path.as_str()) {
+        ("GET", "/health") => {
+            let status = state.lock().unwrap().health_status;
+            if status == 200 {
+                MockResponse::Status(200, json!({"status": "ok"}).to_string())
+            } else {
+                let error = json!({"error": {"code": status, "message": "Loading model"}});
+                MockResponse::Status(status, error.to_string())
+            }
+        }
+        ("POST", "/tokenize") => {
+            let mut state = state.lock().unwrap();
+            state.num_tokenize_requests += 1;
+            if state.can_tokenize {
+                // one token per word is good enough for tests
+                let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                let content = request["content"].as_str().unwrap_or_default();
+                let tokens: Vec<usize> = (0..content.split_whitespace().count()).collect();
+                MockResponse::Status(200, json!({ "tokens": tokens }).to_string())
+            } else {
+                MockResponse::Status(404, "Not found".to_string())
+            }
+        }
+        ("POST", "/v1/chat/completions") => {
+            let mut state = state.lock().unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            let last_message = request["messages"]
+                .as_array()
+                .and_then(|messages| messages.last())
+                .and_then(|message| message["content"].as_str())
+                .unwrap_or_default()
+                .to_string();
+            let stream = request["stream"].as_bool().unwrap_or(false);
+            state.requests.push(request);
+
+            let response = if let Some(i) = state
+                .matching_responses
+                .iter()
+                .position(|(pattern, _)| last_message.contains(pattern.as_str()))
+            {
+                state.matching_responses.remove(i).1
+            } else {
+                state.responses.pop_front().unwrap_or_else(|| {
+                    MockResponse::Status(500, "No response queued in mock server".to_string())
+                })
+            };
+            // complete answers are streamed in one piece if the client asks for a stream
+            match response {
+                MockResponse::Chat(content) if stream => MockResponse::Stream(vec![content]),
+                MockResponse::ToolCalls(tool_calls) if stream => {
+                    MockResponse::StreamToolCalls(tool_calls)
+                }
+                response => response,
+            }
+        }
+        _ => MockResponse::Status(404, "Not found".to_string()),
+    };
+
+    let _ = write_response(&mut stream, response).await;
+}
+
+/// Reads the request line, the headers and a body with `Content-Length`
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String, Vec<u8>)> {
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+
+    let header_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let num_read = stream.read(&mut buffer).await.ok()?;
+        if num_read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..num_read]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut request_line = head.lines().next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while data.len() < header_end + content_length {
+        let num_read = stream.read(&mut buffer).await.ok()?;
+        if num_read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..num_read]);
+    }
+
+    Some((method, path, data[header_end..].to_vec()))
+}
+
+async fn write_response(stream: &mut TcpStream, response: MockResponse) -> std::io::Result<()> {
+    match response {
+        MockResponse::Chat(content) => {
+            let body = json!({
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": "stop"
+                }]
+            });
+            write_body(stream, 200, "application/json", &body.to_string()).await
+        }
+        MockResponse::Stream(deltas) => {
+            write_stream(stream, stream_events(deltas), Duration::ZERO).await
+        }
+        MockResponse::ToolCalls(tool_calls) => {
+            let tool_calls: Vec<Value> = tool_calls.iter().map(|c| c.to_json()).collect();
+            let body = json!({
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": null, "tool_calls": tool_calls},
+                    "finish_reason": "tool_calls"
+                }]
+            });
+            write_body(stream, 200, "application/json", &body.to_string()).await
+        }
+        MockResponse::StreamToolCalls(tool_calls) => {
+            write_stream(stream, tool_call_events(tool_calls), Duration::ZERO).await
+        }
+        MockResponse::SlowStream(delay, deltas) => {
+            write_stream(stream, stream_events(deltas), delay).await
+        }
+        MockResponse::RawStream(lines) => write_stream(stream, lines, Duration::ZERO).await,
+        MockResponse::Status(status, body) => {
+            write_body(stream, status, "application/json", &body).await
+        }
+        MockResponse::Malformed(body) => write_body(stream, 200, "application/json", &body).await,
+        MockResponse::Slow(delay, response) => {
+            tokio::time::sleep(delay).await;
+            Box::pin(write_response(stream, *response)).await
+        }
+    }
+}
+
+/// The SSE lines llama-server sends for a streamed answer
+fn stream_events(deltas: Vec<String>) -> Vec<String> {
+    let role = json!({"choices": [{"index": 0, "delta": {"role": "assistant"}}]});
+    let mut events = vec![format!("data: {role}\n\n")];
+    for delta in deltas {
+        let chunk = json!({"choices": [{"index": 0, "delta": {"content": delta}}]});
+        events.push(format!("data: {chunk}\n\n"));
+    }
+    events.push("data: [DONE]\n\n".to_string());
+    events
+}
+
+fn tool_call_events(tool_calls: Vec<ToolCall>) -> Vec<String> {
+    let role = json!({"choices": [{"index": 0, "delta": {"role": "assistant"}}]});
+    let mut events = vec![format!("data: {role}\n\n")];
+    for (index, tool_call) in tool_calls.into_iter().enumerate() {
+        let middle = tool_call.arguments.len() / 2;
+        let (start, end) = tool_call.arguments.split_at(middle);
+        let first = json!({"index": index, "id": tool_call.id, "type": "function",
+            "function": {"name": tool_call.name, "arguments": start}});
+        let rest = json!({"index": index, "function": {"arguments": end}});
+        for piece in [first, rest] {
+            let chunk = json!({"choices": [{"index": 0, "delta": {"tool_calls": [piece]}}]});
+            events.push(format!("data: {chunk}\n\n"));
+        }
+    }
+    events.push("data: [DONE]\n\n".to_string());
+    events
+}
+
+async fn write_body(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        reason(status),
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn write_stream(
+    stream: &mut TcpStream,
+    events: Vec<String>,
+    delay: Duration,
+) -> std::io::Result<()> {
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n";
+    stream.write_all(head.as_bytes()).await?;
+    for event in events {
+        stream.write_all(event.as_bytes()).await?;
+        stream.flush().await?;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+    stream.shutdown().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
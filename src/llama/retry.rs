@@ -0,0 +1,116 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use super::LlamaError;
+
+/// How often and how long to wait before a failed request is sent again.
+///
+/// The delay doubles with every retry up to `max_delay`. The default does not retry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Random part of every delay, 0.25 waits between 75% and 125% of it,
+    /// so restarted clients don't all hit llama-server at the same time
+    pub jitter: f32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+            jitter: 0.25,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Time to wait before retry number `attempt`, starting at 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter = self.jitter * (2.0 * random_fraction() - 1.0);
+        delay.mul_f32((1.0 + jitter).max(0.0))
+    }
+
+    /// Sends the request again as long as it fails with an error that may go away
+    pub async fn retry<T, F>(&self, mut send: impl FnMut() -> F) -> Result<T, LlamaError>
+    where
+        F: Future<Output = Result<T, LlamaError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match send().await {
+                Err(e) if attempt < self.max_retries && is_retryable(&e) => {
+                    let delay = self.delay(attempt);
+                    eprintln!(
+                        "Request to llama-server failed: {e}, retrying in {:.1} s",
+                        delay.as_secs_f32()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Errors of a crashed, restarting or busy llama-server.
+///
+/// Timeouts are not retried, the model would just be too slow again,
+/// and other HTTP errors mean the request itself is wrong.
+pub fn is_retryable(error: &LlamaError) -> bool {
+    match error {
+        LlamaError::Network(e) => !e.is_timeout(),
+        LlamaError::Http(status, _) => matches!(status, 502..=504),
+        _ => false,
+    }
+}
+
+/// A number between 0 and 1, without pulling in a crate for random numbers
+fn random_fraction() -> f32 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 40) as f32 / (1_u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum_with_jitter() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.25,
+        };
+        for (attempt, expected) in [(0, 100), (1, 200), (2, 400), (3, 800), (4, 1000), (9, 1000)] {
+            let delay = policy.delay(attempt).as_millis();
+            let range = expected * 3 / 4..=expected * 5 / 4;
+            assert!(range.contains(&delay), "attempt {attempt}: {delay} ms");
+        }
+
+        let delays: Vec<_> = (0..10).map(|_| policy.delay(0)).collect();
+        assert!(delays.iter().any(|delay| *delay != delays[0]), "no jitter");
+    }
+
+    #[test]
+    fn only_temporary_errors_are_retried() {
+        assert!(is_retryable(&LlamaError::Http(503, "Loading model".into())));
+        assert!(is_retryable(&LlamaError::Http(502, String::new())));
+        assert!(!is_retryable(&LlamaError::Http(500, String::new())));
+        assert!(!is_retryable(&LlamaError::Http(400, String::new())));
+        assert!(!is_retryable(&LlamaError::InvalidResponse));
+    }
+}
@@ -0,0 +1,1261 @@
+use std::{ops::ControlFlow, time::Duration};
+
+use futures_util::StreamExt;
+
+use super::mock_server::{MockLlamaServer, MockResponse};
+use super::*;
+
+fn client(server: &MockLlamaServer) -> LlamaClient {
+    LlamaClient::builder()
+        .base_url(server.url())
+        .timeout(5)
+        .build()
+}
+
+fn conversation(server: &MockLlamaServer) -> Conversation {
+    Conversation::new(client(server)).with_system_message("You are KITT.")
+}
+
+fn request(content: &str) -> ChatRequest {
+    ChatRequest {
+        messages: vec![ChatMessage::user(content)],
+        ..Default::default()
+    }
+}
+
+/// An address where nobody is listening
+fn closed_url() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+fn deltas(words: &[&str]) -> Vec<String> {
+    words.iter().map(|word| word.to_string()).collect()
+}
+
+/// The content of a streamed answer piece by piece, tool calls are skipped
+async fn content_stream(
+    client: &LlamaClient,
+    request: ChatRequest,
+) -> Result<impl Stream<Item = Result<String, LlamaError>>, LlamaError> {
+    let deltas = client.chat_stream_deltas(request).await?;
+    Ok(deltas.filter_map(|delta| async move {
+        match delta {
+            Ok(ChatDelta::Content(content)) => Some(Ok(content)),
+            Ok(ChatDelta::ToolCall(_)) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }))
+}
+
+async fn collect_stream(
+    client: &LlamaClient,
+    request: ChatRequest,
+) -> Result<Vec<String>, LlamaError> {
+    let stream = content_stream(client, request).await?;
+    let mut stream = std::pin::pin!(stream);
+    let mut deltas = Vec::new();
+    while let Some(delta) = stream.next().await {
+        deltas.push(delta?);
+    }
+    Ok(deltas)
+}
+
+/// Sends a message and waits for the whole answer
+async fn send(
+    conversation: &mut Conversation,
+    message: impl Into<ChatMessage>,
+) -> Result<String, LlamaError> {
+    conversation
+        .send_stream(message, |_| ControlFlow::Continue(()))
+        .await
+}
+
+fn roles(request: &Value) -> Vec<&str> {
+    request["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["role"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn health_check_reports_ready_server() {
+    let server = MockLlamaServer::start();
+    assert!(client(&server).health_check().await.unwrap());
+}
+
+#[tokio::test]
+async fn health_check_reports_loading_server() {
+    let server = MockLlamaServer::start();
+    server.set_health_status(503);
+    assert!(!client(&server).health_check().await.unwrap());
+}
+
+#[tokio::test]
+async fn health_check_fails_without_server() {
+    let client = LlamaClient::builder().base_url(closed_url()).build();
+    let result = client.health_check().await;
+    assert!(matches!(result, Err(LlamaError::Network(_))), "{result:?}");
+}
+
+#[tokio::test]
+async fn chat_returns_content() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("I am the voice of KITT.".into()));
+
+    let answer = client(&server).chat(request("Who are you?")).await.unwrap();
+    assert_eq!(answer, "I am the voice of KITT.");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["model"], "llama");
+    assert_eq!(requests[0]["stream"], false);
+    assert_eq!(requests[0]["max_tokens"], 1000);
+    assert_eq!(requests[0]["messages"][0]["content"], "Who are you?");
+}
+
+#[tokio::test]
+async fn chat_reports_http_errors() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Status(500, "Model crashed".into()));
+
+    let result = client(&server).chat(request("Hello")).await;
+    match result {
+        Err(LlamaError::Http(500, body)) => assert_eq!(body, "Model crashed"),
+        other => panic!("Expected HTTP 500, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn chat_rejects_malformed_json() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Malformed("{\"choices\": [".into()));
+
+    let result = client(&server).chat(request("Hello")).await;
+    assert!(
+        matches!(result, Err(LlamaError::InvalidResponse)),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn chat_rejects_response_without_content() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Malformed("{\"choices\": []}".into()));
+
+    let result = client(&server).chat(request("Hello")).await;
+    assert!(
+        matches!(result, Err(LlamaError::InvalidResponse)),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn chat_times_out_on_slow_server() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Slow(
+        Duration::from_millis(1500),
+        Box::new(MockResponse::Chat("Too late".into())),
+    ));
+
+    let client = LlamaClient::builder()
+        .base_url(server.url())
+        .timeout(1)
+        .build();
+    match client.chat(request("Hello")).await {
+        Err(LlamaError::Network(e)) => assert!(e.is_timeout(), "{e}"),
+        other => panic!("Expected a timeout, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn chat_waits_for_slow_server_within_timeout() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Slow(
+        Duration::from_millis(200),
+        Box::new(MockResponse::Chat("Worth the wait".into())),
+    ));
+
+    let answer = client(&server).chat(request("Hello")).await.unwrap();
+    assert_eq!(answer, "Worth the wait");
+}
+
+#[tokio::test]
+async fn chat_stream_yields_deltas() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Stream(deltas(&["Hello", ", ", "Michael."])));
+
+    let received = collect_stream(&client(&server), request("Hi"))
+        .await
+        .unwrap();
+    assert_eq!(received, deltas(&["Hello", ", ", "Michael."]));
+    assert_eq!(server.requests()[0]["stream"], true);
+}
+
+#[tokio::test]
+async fn chat_stream_yields_first_delta_before_the_answer_is_complete() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::SlowStream(
+        Duration::from_millis(300),
+        deltas(&["One", " two", " three"]),
+    ));
+
+    let client = client(&server);
+    let start = std::time::Instant::now();
+    let stream = content_stream(&client, request("Count")).await.unwrap();
+    let mut stream = std::pin::pin!(stream);
+
+    assert_eq!(stream.next().await.unwrap().unwrap(), "One");
+    let first_delta = start.elapsed();
+
+    let mut rest = String::new();
+    while let Some(delta) = stream.next().await {
+        rest.push_str(&delta.unwrap());
+    }
+    assert_eq!(rest, " two three");
+    assert!(start.elapsed() - first_delta >= Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn chat_stream_reports_http_errors() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Status(503, "Loading model".into()));
+
+    let result = collect_stream(&client(&server), request("Hi")).await;
+    assert!(
+        matches!(result, Err(LlamaError::Http(503, _))),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn chat_stream_reports_error_events() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::RawStream(vec![
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n".into(),
+        "data: {\"error\":{\"code\":500,\"message\":\"context full\"}}\n\n".into(),
+        "data: {\"choices\":[{\"delta\":{\"content\":\"lo\"}}]}\n\n".into(),
+    ]));
+
+    let stream = content_stream(&client(&server), request("Hi"))
+        .await
+        .unwrap();
+    let items: Vec<_> = stream.collect().await;
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].as_ref().unwrap(), "Hel");
+    match &items[1] {
+        Err(LlamaError::Stream(message)) => assert_eq!(message, "context full"),
+        other => panic!("Expected a stream error, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn chat_stream_rejects_malformed_events() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::RawStream(vec!["data: {not json\n\n".into()]));
+
+    let result = collect_stream(&client(&server), request("Hi")).await;
+    assert!(
+        matches!(result, Err(LlamaError::InvalidResponse)),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn chat_stream_ends_without_done_event() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::RawStream(vec![
+        ": keep-alive comment\n\n".into(),
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\r\n\r\n".into(),
+        // the last line has no newline
+        "data: {\"choices\":[{\"delta\":{\"content\":\" there\"}}]}".into(),
+    ]));
+
+    let received = collect_stream(&client(&server), request("Hi"))
+        .await
+        .unwrap();
+    assert_eq!(received, deltas(&["Hello", " there"]));
+}
+
+#[tokio::test]
+async fn conversation_sends_whole_history() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("Good evening, Michael.".into()));
+    server.push_response(MockResponse::Chat("We are on the highway.".into()));
+
+    let mut conversation = conversation(&server)
+        .with_temperature(0.2)
+        .with_max_tokens(50);
+    send(&mut conversation, "Hello KITT").await.unwrap();
+    send(&mut conversation, "Where are we?").await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(roles(&requests[0]), ["system", "user"]);
+    assert_eq!(roles(&requests[1]), ["system", "user", "assistant", "user"]);
+    assert_eq!(
+        requests[1]["messages"][2]["content"],
+        "Good evening, Michael."
+    );
+    assert_eq!(requests[1]["max_tokens"], 50);
+    assert!((requests[1]["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+
+    assert_eq!(conversation.messages.len(), 5);
+    assert_eq!(conversation.messages[4].content, "We are on the highway.");
+}
+
+#[tokio::test]
+async fn conversation_forgets_failed_question() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Status(500, "Model crashed".into()));
+    server.push_response(MockResponse::Chat("Hello again.".into()));
+
+    let mut conversation = conversation(&server);
+    assert!(send(&mut conversation, "Hello?").await.is_err());
+    assert_eq!(conversation.messages.len(), 1);
+
+    send(&mut conversation, "Hello!").await.unwrap();
+    assert_eq!(roles(&server.requests()[1]), ["system", "user"]);
+}
+
+#[tokio::test]
+async fn conversation_stream_keeps_answer() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Stream(deltas(&["Turbo", " boost."])));
+
+    let mut conversation = conversation(&server);
+    let mut received = String::new();
+    let answer = conversation
+        .send_stream("Jump!", |delta| {
+            received.push_str(delta);
+            ControlFlow::Continue(())
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(answer, "Turbo boost.");
+    assert_eq!(received, "Turbo boost.");
+    assert_eq!(
+        conversation.messages.last().unwrap().content,
+        "Turbo boost."
+    );
+}
+
+#[tokio::test]
+async fn conversation_stream_break_keeps_partial_answer() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Stream(deltas(&["One.", " Two.", " Three."])));
+
+    let mut conversation = conversation(&server);
+    let answer = conversation
+        .send_stream("Count", |_| ControlFlow::Break(()))
+        .await
+        .unwrap();
+
+    assert_eq!(answer, "One.");
+    assert_eq!(conversation.messages.len(), 3);
+    assert_eq!(conversation.messages[2].role, "assistant");
+    assert_eq!(conversation.messages[2].content, "One.");
+}
+
+#[tokio::test]
+async fn conversation_stream_forgets_failed_question() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::RawStream(vec![
+        "data: {\"error\":{\"message\":\"context full\"}}\n\n".into(),
+    ]));
+
+    let mut conversation = conversation(&server);
+    let result = conversation
+        .send_stream("Hello?", |_| ControlFlow::Continue(()))
+        .await;
+    assert!(matches!(result, Err(LlamaError::Stream(_))), "{result:?}");
+    assert_eq!(conversation.messages.len(), 1);
+}
+
+#[tokio::test]
+async fn shutdown_stops_waiting_for_the_answer() {
+    let server = MockLlamaServer::start();
+    // a delta every second, after the role
+    server.push_response(MockResponse::SlowStream(
+        Duration::from_secs(1),
+        deltas(&["Turbo boost", " is ready."]),
+    ));
+
+    let mut conversation = conversation(&server);
+    let shutdown = Shutdown::default();
+    conversation.set_shutdown(shutdown.clone());
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(1500));
+        shutdown.request(libc::SIGTERM);
+    });
+
+    // it does not wait for the second delta
+    let answer = send(&mut conversation, "Jump!").await.unwrap();
+    assert_eq!(answer, "Turbo boost");
+    assert_eq!(conversation.messages.len(), 3);
+    assert_eq!(conversation.messages[2].content, "Turbo boost");
+}
+
+#[tokio::test]
+async fn conversation_stream_keeps_received_part_of_failed_answer() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::RawStream(vec![
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Turbo boost\"}}]}\n\n".into(),
+        "data: {\"error\":{\"message\":\"context full\"}}\n\n".into(),
+    ]));
+
+    let mut conversation = conversation(&server);
+    let mut received = String::new();
+    let result = conversation
+        .send_stream("Jump!", |delta| {
+            received.push_str(delta);
+            ControlFlow::Continue(())
+        })
+        .await;
+    assert!(matches!(result, Err(LlamaError::Stream(_))), "{result:?}");
+
+    // the user heard the beginning, so it stays in the history
+    assert_eq!(received, "Turbo boost");
+    assert_eq!(conversation.messages.len(), 3);
+    assert_eq!(conversation.messages[2].content, "Turbo boost");
+    assert!(conversation.messages[2].interrupted);
+}
+
+#[tokio::test]
+async fn interrupted_answer_is_marked_in_next_request() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("The weather is sunny and warm.".into()));
+    server.push_response(MockResponse::Chat("Of course, Michael.".into()));
+
+    let mut conversation = conversation(&server);
+    send(&mut conversation, "How is the weather?")
+        .await
+        .unwrap();
+    conversation.interrupt_last_answer("The weather is");
+    send(&mut conversation, "Stop talking").await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(
+        requests[1]["messages"][2]["content"],
+        "The weather is... [interrupted]"
+    );
+    assert!(conversation.messages[2].interrupted);
+    assert!(!conversation.messages[4].interrupted);
+}
+
+#[tokio::test]
+async fn long_answer_can_be_shortened() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat(
+        "Turbo boost fires two rocket boosters under my chassis, which lets me jump.".into(),
+    ));
+    server.push_response(MockResponse::Chat("Rockets let me jump.".into()));
+
+    let mut conversation = conversation(&server);
+    send(&mut conversation, "What is turbo boost?")
+        .await
+        .unwrap();
+    let short = conversation.shorten_last_answer(30).await.unwrap();
+
+    assert_eq!(short, "Rockets let me jump.");
+    let request = &server.requests()[1];
+    assert_eq!(roles(request), ["system", "user", "assistant", "user"]);
+    assert!(request["messages"][3]["content"]
+        .as_str()
+        .unwrap()
+        .contains("at most 30 characters"));
+    // the history only changes when the shorter answer is spoken
+    assert_eq!(conversation.messages.len(), 3);
+    conversation.truncate_last_answer(short);
+    assert_eq!(conversation.messages[2].content, "Rockets let me jump.");
+    assert!(conversation.messages[2].truncated);
+    assert!(!conversation.messages[2].interrupted);
+}
+
+#[tokio::test]
+async fn interrupt_without_answer_changes_nothing() {
+    let server = MockLlamaServer::start();
+    let mut conversation = conversation(&server);
+    conversation.interrupt_last_answer("anything");
+
+    assert_eq!(conversation.messages.len(), 1);
+    assert_eq!(conversation.messages[0].content, "You are KITT.");
+    assert!(!conversation.messages[0].interrupted);
+}
+
+#[test]
+fn blocking_llama_chats_with_server() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Stream(deltas(&["Right", " away."])));
+
+    let config = LlmConfig {
+        url: server.url().to_string(),
+        persona: "You are KITT.".into(),
+        ..Default::default()
+    };
+    let mut llama = BlockingLlama::new(&config).unwrap();
+    let answer = llama
+        .chat_stream("Turbo boost", |_| ControlFlow::Continue(()))
+        .unwrap();
+    assert_eq!(answer, "Right away.");
+    assert_eq!(
+        server.requests()[0]["messages"][0]["content"],
+        "You are KITT."
+    );
+}
+
+#[test]
+fn blocking_llama_fails_while_model_is_loading() {
+    let server = MockLlamaServer::start();
+    server.set_health_status(503);
+
+    let config = LlmConfig {
+        url: server.url().to_string(),
+        startup_timeout: 0,
+        ..Default::default()
+    };
+    let result = BlockingLlama::new(&config);
+    assert!(matches!(result, Err(LlamaError::HealthCheckFailed)));
+}
+
+#[test]
+fn blocking_llama_fails_without_server() {
+    let config = LlmConfig {
+        url: closed_url(),
+        startup_timeout: 0,
+        ..Default::default()
+    };
+    let result = BlockingLlama::new(&config);
+    assert!(matches!(result, Err(LlamaError::Network(_))));
+}
+
+#[test]
+fn blocking_llama_waits_for_model_to_load() {
+    let server = MockLlamaServer::start();
+    server.set_health_status(503);
+
+    let config = LlmConfig {
+        url: server.url().to_string(),
+        startup_timeout: 5,
+        ..Default::default()
+    };
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            std::thread::sleep(Duration::from_millis(300));
+            server.set_health_status(200);
+        });
+        assert!(BlockingLlama::new(&config).is_ok());
+    });
+}
+
+#[tokio::test]
+async fn wait_until_ready_fails_at_once_on_other_errors() {
+    let server = MockLlamaServer::start();
+    server.set_health_status(500);
+
+    let start = Instant::now();
+    let result = client(&server)
+        .wait_until_ready(Duration::from_secs(5))
+        .await;
+    assert!(
+        matches!(result, Err(LlamaError::Http(500, _))),
+        "{result:?}"
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn wait_until_ready_gives_up_after_timeout() {
+    let client = LlamaClient::builder().base_url(closed_url()).build();
+    let result = client.wait_until_ready(Duration::from_millis(700)).await;
+    assert!(matches!(result, Err(LlamaError::Network(_))), "{result:?}");
+}
+
+fn retrying_client(server: &MockLlamaServer) -> LlamaClient {
+    LlamaClient::builder()
+        .base_url(server.url())
+        .timeout(5)
+        .retry(RetryPolicy {
+            max_retries: 2,
+            initial_delay: Duration::from_millis(10),
+            ..Default::default()
+        })
+        .build()
+}
+
+#[tokio::test]
+async fn chat_retries_while_server_is_unavailable() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Status(503, "Loading model".into()));
+    server.push_response(MockResponse::Status(502, "Bad gateway".into()));
+    server.push_response(MockResponse::Chat("I am back, Michael.".into()));
+
+    let answer = retrying_client(&server)
+        .chat(request("KITT?"))
+        .await
+        .unwrap();
+    assert_eq!(answer, "I am back, Michael.");
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn chat_gives_up_after_max_retries() {
+    let server = MockLlamaServer::start();
+    for _ in 0..3 {
+        server.push_response(MockResponse::Status(503, "Loading model".into()));
+    }
+    server.push_response(MockResponse::Chat("Too late.".into()));
+
+    let result = retrying_client(&server).chat(request("KITT?")).await;
+    assert!(
+        matches!(result, Err(LlamaError::Http(503, _))),
+        "{result:?}"
+    );
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn chat_does_not_retry_hard_failures() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Status(500, "Template error".into()));
+    server.push_response(MockResponse::Chat("Unreachable.".into()));
+
+    let result = retrying_client(&server).chat(request("KITT?")).await;
+    assert!(
+        matches!(result, Err(LlamaError::Http(500, _))),
+        "{result:?}"
+    );
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn chat_stream_retries_before_the_first_delta() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Status(503, "Loading model".into()));
+    server.push_response(MockResponse::Stream(deltas(&["Scanner", " online."])));
+
+    let deltas = collect_stream(&retrying_client(&server), request("Status?"))
+        .await
+        .unwrap();
+    assert_eq!(deltas, ["Scanner", " online."]);
+}
+
+#[test]
+fn health_monitor_reports_outage_and_recovery() {
+    let server = MockLlamaServer::start();
+    let config = LlmConfig {
+        url: server.url().to_string(),
+        health_interval: 1,
+        ..Default::default()
+    };
+    let llama = BlockingLlama::new(&config).unwrap();
+    let next_event = || {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if let Some(event) = llama.health_event() {
+                return Some(event);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        None
+    };
+
+    server.set_health_status(503);
+    assert_eq!(next_event(), Some(HealthEvent::Unavailable));
+    server.set_health_status(200);
+    assert_eq!(next_event(), Some(HealthEvent::Recovered));
+}
+
+#[test]
+fn io_errors_are_wrapped() {
+    let error = LlamaError::from(std::io::Error::other("no runtime"));
+    assert!(matches!(error, LlamaError::Io(_)));
+    assert_eq!(error.to_string(), "IO error: no runtime");
+}
+
+#[tokio::test]
+async fn count_tokens_uses_server_tokenizer() {
+    let server = MockLlamaServer::start();
+    let tokens = client(&server).count_tokens("Hello there Michael").await;
+    assert_eq!(tokens.unwrap(), 3);
+
+    server.set_can_tokenize(false);
+    let result = client(&server).count_tokens("Hello").await;
+    assert!(
+        matches!(result, Err(LlamaError::Http(404, _))),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn history_keeps_last_turns() {
+    let server = MockLlamaServer::start();
+    for answer in ["One.", "Two.", "Three."] {
+        server.push_response(MockResponse::Chat(answer.into()));
+    }
+
+    let mut conversation = conversation(&server).with_history(HistoryPolicy {
+        max_turns: Some(1),
+        ..Default::default()
+    });
+    send(&mut conversation, "First").await.unwrap();
+    send(&mut conversation, "Second").await.unwrap();
+    send(&mut conversation, "Third").await.unwrap();
+
+    let request = &server.requests()[2];
+    assert_eq!(roles(request), ["system", "user", "assistant", "user"]);
+    assert_eq!(request["messages"][0]["content"], "You are KITT.");
+    assert_eq!(request["messages"][1]["content"], "Second");
+    assert_eq!(request["messages"][3]["content"], "Third");
+    assert_eq!(server.num_tokenize_requests(), 0);
+}
+
+#[tokio::test]
+async fn history_stays_within_token_budget() {
+    let server = MockLlamaServer::start();
+    for answer in ["One two three.", "Four five six.", "Seven eight nine."] {
+        server.push_response(MockResponse::Chat(answer.into()));
+    }
+
+    // every message is 3 words + 4 tokens template overhead = 7 tokens
+    let mut conversation = Conversation::new(client(&server))
+        .with_system_message("You are KITT.")
+        .with_history(HistoryPolicy {
+            max_tokens: Some(30),
+            ..Default::default()
+        });
+    send(&mut conversation, "First question here")
+        .await
+        .unwrap();
+    send(&mut conversation, "Second question here")
+        .await
+        .unwrap();
+    send(&mut conversation, "Third question here")
+        .await
+        .unwrap();
+
+    let requests = server.requests();
+    assert_eq!(roles(&requests[1]), ["system", "user", "assistant", "user"]);
+    assert_eq!(roles(&requests[2]), ["system", "user", "assistant", "user"]);
+    assert_eq!(
+        requests[2]["messages"][1]["content"],
+        "Second question here"
+    );
+
+    // every message is tokenized once
+    assert_eq!(server.num_tokenize_requests(), 6);
+}
+
+#[tokio::test]
+async fn history_estimates_tokens_without_tokenizer() {
+    let server = MockLlamaServer::start();
+    server.set_can_tokenize(false);
+    server.push_response(MockResponse::Chat("Yes.".into()));
+    server.push_response(MockResponse::Chat("No.".into()));
+
+    let mut conversation = conversation(&server).with_history(HistoryPolicy {
+        max_tokens: Some(16),
+        ..Default::default()
+    });
+    send(&mut conversation, "Are you there?").await.unwrap();
+    send(&mut conversation, "Really?").await.unwrap();
+
+    // "You are KITT." is 4 + 4 tokens, "Yes." 1 + 4, "Really?" 2 + 4 tokens
+    assert_eq!(roles(&server.requests()[1]), ["system", "user"]);
+    // only the messages of the first question are sent before the tokenizer is known to be missing
+    assert_eq!(server.num_tokenize_requests(), 2);
+}
+
+#[tokio::test]
+async fn summary_replaces_dropped_turns() {
+    let server = MockLlamaServer::start();
+    for answer in [
+        "Hello Michael.",
+        "Las Vegas is 300 miles away.",
+        "Sure.",
+        "Okay.",
+    ] {
+        server.push_response(MockResponse::Chat(answer.into()));
+    }
+    server.push_matching_response(
+        "User: Hello KITT",
+        MockResponse::Chat("Michael greeted KITT.".into()),
+    );
+    server.push_matching_response(
+        "User: How far is Las Vegas",
+        MockResponse::Chat(" Michael asked how far Las Vegas is, 300 miles. ".into()),
+    );
+
+    let mut conversation = conversation(&server)
+        .with_history(HistoryPolicy {
+            max_turns: Some(1),
+            ..Default::default()
+        })
+        .with_summary(true);
+    send(&mut conversation, "Hello KITT").await.unwrap();
+    send(&mut conversation, "How far is Las Vegas?")
+        .await
+        .unwrap();
+    send(&mut conversation, "Drive faster").await.unwrap();
+    conversation.wait_for_summary().await;
+    assert_eq!(conversation.summary(), Some("Michael greeted KITT."));
+
+    send(&mut conversation, "Stop").await.unwrap();
+    conversation.wait_for_summary().await;
+    assert_eq!(
+        conversation.summary(),
+        Some("Michael asked how far Las Vegas is, 300 miles.")
+    );
+
+    let requests = server.requests();
+    let stop_request = requests
+        .iter()
+        .find(|request| {
+            request["messages"].as_array().unwrap().last().unwrap()["content"] == "Stop"
+        })
+        .unwrap();
+    assert_eq!(roles(stop_request), ["system", "user", "assistant", "user"]);
+    assert_eq!(
+        stop_request["messages"][0]["content"],
+        "You are KITT.\n\nSummary of the earlier conversation: Michael greeted KITT."
+    );
+
+    // the second summary builds on the first one
+    let second_summary_request = requests
+        .iter()
+        .find(|request| {
+            request["messages"][1]["content"]
+                .as_str()
+                .is_some_and(|content| content.contains("User: How far is Las Vegas"))
+        })
+        .unwrap();
+    let content = second_summary_request["messages"][1]["content"]
+        .as_str()
+        .unwrap();
+    assert!(
+        content.starts_with("Summary so far: Michael greeted KITT."),
+        "{content}"
+    );
+
+    // the persona itself is not changed
+    assert_eq!(conversation.pinned_messages().len(), 1);
+    assert_eq!(conversation.messages[0].content, "You are KITT.");
+}
+
+#[tokio::test]
+async fn answer_does_not_wait_for_summary() {
+    let server = MockLlamaServer::start();
+    for answer in ["One.", "Two.", "Three."] {
+        server.push_response(MockResponse::Chat(answer.into()));
+    }
+    server.push_matching_response(
+        "User: First",
+        MockResponse::Slow(
+            Duration::from_secs(2),
+            Box::new(MockResponse::Chat("Michael said first.".into())),
+        ),
+    );
+    server.push_matching_response(
+        "User: Second",
+        MockResponse::Chat("Michael said first and second.".into()),
+    );
+
+    let mut conversation = conversation(&server)
+        .with_history(HistoryPolicy {
+            max_turns: Some(0),
+            ..Default::default()
+        })
+        .with_summary(true);
+    send(&mut conversation, "First").await.unwrap();
+    send(&mut conversation, "Second").await.unwrap();
+    let start = std::time::Instant::now();
+    send(&mut conversation, "Third").await.unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(conversation.summary(), None);
+
+    // the turn dropped while summarizing goes into the next summary
+    conversation.wait_for_summary().await;
+    assert_eq!(
+        conversation.summary(),
+        Some("Michael said first and second.")
+    );
+    let requests = server.requests();
+    let content = requests.last().unwrap()["messages"][1]["content"]
+        .as_str()
+        .unwrap();
+    assert!(
+        content.starts_with("Summary so far: Michael said first."),
+        "{content}"
+    );
+}
+
+#[tokio::test]
+async fn failed_summary_keeps_conversation_going() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("One.".into()));
+    server.push_response(MockResponse::Chat("Two.".into()));
+    server.push_matching_response("User: First", MockResponse::Status(500, "Busy".into()));
+
+    let mut conversation = conversation(&server)
+        .with_history(HistoryPolicy {
+            max_turns: Some(0),
+            ..Default::default()
+        })
+        .with_summary(true);
+    send(&mut conversation, "First").await.unwrap();
+    send(&mut conversation, "Second").await.unwrap();
+    conversation.wait_for_summary().await;
+
+    assert_eq!(conversation.summary(), None);
+    assert_eq!(conversation.messages.len(), 3);
+}
+
+#[tokio::test]
+async fn conversation_is_saved_and_restored() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Stream(deltas(&["It is sunny", " and warm."])));
+    server.push_response(MockResponse::Status(500, "Model crashed".into()));
+    server.push_response(MockResponse::Chat("You asked about the weather.".into()));
+
+    let dir = std::env::temp_dir().join(format!("knight-rider-restore-{}", std::process::id()));
+    let log = crate::session::SessionLog::create(&dir).unwrap();
+    let path = log.path().to_path_buf();
+
+    let mut conversation = conversation(&server);
+    conversation.set_session_log(log);
+    let question = ChatMessage::user("How is the weather?").with_metadata(MessageMetadata {
+        stt_model: Some("moonshine".into()),
+        stt_ms: Some(120),
+        ..Default::default()
+    });
+    conversation
+        .send_stream(question, |_| ControlFlow::Continue(()))
+        .await
+        .unwrap();
+    conversation.interrupt_last_answer("It is sunny");
+    assert!(send(&mut conversation, "Hello?").await.is_err());
+
+    let messages = crate::session::load(&path).unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].metadata.stt_model.as_deref(), Some("moonshine"));
+    assert!(messages[0].timestamp.is_some());
+    assert_eq!(messages[1].content, "It is sunny");
+    assert!(messages[1].interrupted);
+    assert!(messages[1].metadata.first_token_ms.is_some());
+    assert!(messages[1].metadata.llm_ms.is_some());
+
+    let mut restored = self::conversation(&server);
+    restored.restore(messages);
+    send(&mut restored, "What did I ask?").await.unwrap();
+    let request = &server.requests()[2];
+    assert_eq!(roles(request), ["system", "user", "assistant", "user"]);
+    assert_eq!(
+        request["messages"][2]["content"],
+        "It is sunny... [interrupted]"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn tool_call(id: &str, name: &str, arguments: &str) -> ToolCall {
+    ToolCall {
+        id: id.into(),
+        name: name.into(),
+        arguments: arguments.into(),
+    }
+}
+
+fn speedometer() -> ToolRegistry {
+    let mut speed = ToolDefinition::new("speed", "Get the current speed");
+    speed.parameters = json!({
+        "type": "object",
+        "properties": {"unit": {"type": "string", "enum": ["mph", "km/h"]}},
+        "required": ["unit"]
+    });
+    ToolRegistry::default().with_tool(speed, |arguments| match arguments["unit"].as_str() {
+        Some(unit) => Ok(format!("88 {unit}")),
+        None => Err("unit is missing".into()),
+    })
+}
+
+#[tokio::test]
+async fn chat_message_parses_tool_calls() {
+    let server = MockLlamaServer::start();
+    let call = tool_call("call_1", "speed", "{\"unit\":\"mph\"}");
+    server.push_response(MockResponse::ToolCalls(vec![call.clone()]));
+
+    let mut request = request("How fast are we?");
+    request.tools = speedometer().definitions();
+    let answer = client(&server).chat_message(request).await.unwrap();
+    assert_eq!(answer.content, "");
+    assert_eq!(answer.tool_calls, [call]);
+
+    let tools = &server.requests()[0]["tools"];
+    assert_eq!(tools[0]["type"], "function");
+    assert_eq!(tools[0]["function"]["name"], "speed");
+    assert_eq!(tools[0]["function"]["parameters"]["required"][0], "unit");
+}
+
+#[tokio::test]
+async fn chat_stream_deltas_yield_tool_call_pieces() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::StreamToolCalls(vec![tool_call(
+        "call_1",
+        "speed",
+        "{\"unit\":\"km/h\"}",
+    )]));
+
+    let stream = client(&server)
+        .chat_stream_deltas(request("How fast are we?"))
+        .await
+        .unwrap();
+    let deltas: Vec<ChatDelta> = stream.map(|delta| delta.unwrap()).collect().await;
+
+    let mut tool_calls = Vec::new();
+    for delta in deltas {
+        match delta {
+            ChatDelta::ToolCall(piece) => piece.apply(&mut tool_calls),
+            ChatDelta::Content(content) => panic!("unexpected content {content}"),
+        }
+    }
+    assert_eq!(
+        tool_calls,
+        [tool_call("call_1", "speed", "{\"unit\":\"km/h\"}")]
+    );
+}
+
+#[tokio::test]
+async fn conversation_feeds_tool_results_back() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::ToolCalls(vec![
+        tool_call("call_1", "speed", "{\"unit\":\"mph\"}"),
+        tool_call("call_2", "fuel", "{}"),
+    ]));
+    server.push_response(MockResponse::Chat("We are doing 88 miles per hour.".into()));
+
+    let mut conversation = conversation(&server).with_tools(speedometer());
+    let answer = send(&mut conversation, "How fast are we?").await.unwrap();
+    assert_eq!(answer, "We are doing 88 miles per hour.");
+
+    let requests = server.requests();
+    assert_eq!(
+        roles(&requests[1]),
+        ["system", "user", "assistant", "tool", "tool"]
+    );
+    let messages = &requests[1]["messages"];
+    assert_eq!(messages[2]["tool_calls"][0]["function"]["name"], "speed");
+    assert_eq!(messages[3]["tool_call_id"], "call_1");
+    assert_eq!(messages[3]["content"], "88 mph");
+    assert_eq!(messages[4]["content"], "Error: there is no tool `fuel`");
+
+    let history: Vec<&str> = conversation
+        .messages
+        .iter()
+        .map(|m| m.role.as_str())
+        .collect();
+    assert_eq!(
+        history,
+        ["system", "user", "assistant", "tool", "tool", "assistant"]
+    );
+
+    // only what was said is restored
+    let mut restored = self::conversation(&server);
+    restored.restore(conversation.messages[1..].to_vec());
+    let history: Vec<&str> = restored.messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(history, ["system", "user", "assistant"]);
+}
+
+#[tokio::test]
+async fn conversation_stream_calls_tools() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::StreamToolCalls(vec![tool_call(
+        "call_1",
+        "speed",
+        "{\"unit\":\"km/h\"}",
+    )]));
+    server.push_response(MockResponse::Stream(deltas(&["140", " km/h."])));
+
+    let mut conversation = conversation(&server).with_tools(speedometer());
+    let mut spoken = String::new();
+    let answer = conversation
+        .send_stream("How fast are we?", |delta| {
+            spoken.push_str(delta);
+            ControlFlow::Continue(())
+        })
+        .await
+        .unwrap();
+    assert_eq!(answer, "140 km/h.");
+    assert_eq!(spoken, "140 km/h.");
+    assert_eq!(server.requests()[1]["messages"][3]["content"], "88 km/h");
+}
+
+#[tokio::test]
+async fn conversation_stops_calling_tools_after_max_rounds() {
+    let server = MockLlamaServer::start();
+    for i in 0..MAX_TOOL_ROUNDS {
+        let id = format!("call_{i}");
+        server.push_response(MockResponse::ToolCalls(vec![tool_call(&id, "speed", "{}")]));
+    }
+    server.push_response(MockResponse::Chat("I can not tell.".into()));
+
+    let mut conversation = conversation(&server).with_tools(speedometer());
+    let answer = send(&mut conversation, "How fast are we?").await.unwrap();
+    assert_eq!(answer, "I can not tell.");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), MAX_TOOL_ROUNDS + 1);
+    assert!(requests[MAX_TOOL_ROUNDS - 1].get("tools").is_some());
+    assert!(requests[MAX_TOOL_ROUNDS].get("tools").is_none());
+    assert_eq!(
+        requests[MAX_TOOL_ROUNDS]["messages"][3]["content"],
+        "Error: unit is missing"
+    );
+}
+
+#[tokio::test]
+async fn failed_tool_round_forgets_question() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::ToolCalls(vec![tool_call(
+        "call_1",
+        "speed",
+        "{\"unit\":\"mph\"}",
+    )]));
+    server.push_response(MockResponse::Status(500, "Model crashed".into()));
+
+    let mut conversation = conversation(&server).with_tools(speedometer());
+    assert!(send(&mut conversation, "How fast are we?").await.is_err());
+    assert_eq!(conversation.messages.len(), 1);
+}
+
+#[tokio::test]
+async fn persona_can_be_switched() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("Good evening, Michael.".into()));
+    server.push_response(MockResponse::Chat("I am KARR.".into()));
+
+    let mut conversation = conversation(&server);
+    send(&mut conversation, "Hello").await.unwrap();
+    conversation.set_system_message("You are KARR.");
+    send(&mut conversation, "Who are you?").await.unwrap();
+
+    let request = &server.requests()[1];
+    assert_eq!(roles(request), ["system", "user", "assistant", "user"]);
+    assert_eq!(request["messages"][0]["content"], "You are KARR.");
+}
+
+#[tokio::test]
+async fn cleared_history_keeps_persona_and_starts_new_session() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("Good evening, Michael.".into()));
+    server.push_response(MockResponse::Chat("Nice to meet you.".into()));
+
+    let dir = std::env::temp_dir().join(format!("knight-rider-clear-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let log = crate::session::SessionLog::create(&dir).unwrap();
+    let path = log.path().to_path_buf();
+
+    let mut conversation = conversation(&server);
+    conversation.set_session_log(log);
+    send(&mut conversation, "Hello").await.unwrap();
+    conversation.set_summary("Michael likes turbo boost.");
+    conversation.clear_history();
+    assert_eq!(conversation.summary(), None);
+    send(&mut conversation, "Hello again").await.unwrap();
+
+    assert_eq!(roles(&server.requests()[1]), ["system", "user"]);
+    assert_eq!(crate::session::load(&path).unwrap().len(), 2);
+    let latest = crate::session::latest(&dir).unwrap();
+    assert_ne!(latest, path);
+    assert_eq!(
+        crate::session::load(&latest).unwrap()[0].content,
+        "Hello again"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn unset_sampling_settings_are_not_sent() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("Hello.".into()));
+    client(&server).chat(request("Hi")).await.unwrap();
+
+    let request = &server.requests()[0];
+    assert_eq!(request["model"], "llama");
+    for key in [
+        "top_p",
+        "top_k",
+        "min_p",
+        "repeat_penalty",
+        "presence_penalty",
+        "frequency_penalty",
+        "seed",
+        "stop",
+        "grammar",
+        "json_schema",
+        "n_probs",
+        "tools",
+    ] {
+        assert!(request.get(key).is_none(), "{key} was sent");
+    }
+}
+
+#[tokio::test]
+async fn conversation_sends_sampling_settings() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("{\"speed\": 88}".into()));
+
+    let mut conversation = conversation(&server)
+        .with_model("gemma-3-1b")
+        .with_top_p(0.9)
+        .with_top_k(40)
+        .with_min_p(0.05)
+        .with_repeat_penalty(1.1)
+        .with_presence_penalty(0.5)
+        .with_frequency_penalty(0.25)
+        .with_seed(42)
+        .with_stop(vec!["User:".into()])
+        .with_json_schema(json!({"type": "object"}))
+        .with_n_probs(3)
+        .with_top_k(None);
+    send(&mut conversation, "How fast are we?").await.unwrap();
+
+    let request = &server.requests()[0];
+    assert_eq!(request["model"], "gemma-3-1b");
+    assert!((request["top_p"].as_f64().unwrap() - 0.9).abs() < 1e-6);
+    assert!(request.get("top_k").is_none());
+    assert!((request["min_p"].as_f64().unwrap() - 0.05).abs() < 1e-6);
+    assert!((request["repeat_penalty"].as_f64().unwrap() - 1.1).abs() < 1e-6);
+    assert_eq!(request["presence_penalty"], 0.5);
+    assert_eq!(request["frequency_penalty"], 0.25);
+    assert_eq!(request["seed"], 42);
+    assert_eq!(request["stop"], json!(["User:"]));
+    assert_eq!(request["json_schema"], json!({"type": "object"}));
+    assert_eq!(request["n_probs"], 3);
+}
+
+#[tokio::test]
+async fn grammar_is_sent_except_with_tools() {
+    let server = MockLlamaServer::start();
+    server.push_response(MockResponse::Chat("Good evening, Michael.".into()));
+    server.push_response(MockResponse::ToolCalls(vec![tool_call(
+        "call_1",
+        "speed",
+        "{\"unit\":\"mph\"}",
+    )]));
+    server.push_response(MockResponse::Chat("We are doing 88 miles per hour.".into()));
+
+    let speakable = grammar::load(grammar::SPEAKABLE_NAME).unwrap();
+    let mut conversation = conversation(&server).with_grammar(speakable.clone());
+    send(&mut conversation, "Hello").await.unwrap();
+
+    let mut conversation = conversation.with_tools(speedometer());
+    send(&mut conversation, "How fast are we?").await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests[0]["grammar"], speakable);
+    assert!(requests[1].get("grammar").is_none());
+    assert!(requests[2].get("grammar").is_none());
+}
@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A function the LLM can call, `parameters` is the JSON schema of its arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+impl ToolDefinition {
+    /// A tool without arguments
+    pub fn new(name: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            parameters: json!({"type": "object", "properties": {}}),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters
+            }
+        })
+    }
+}
+
+/// A call of a tool requested by the LLM, `arguments` is a JSON object as text
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+impl ToolCall {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "arguments": self.arguments
+            }
+        })
+    }
+
+    /// Parses a tool call of a complete (non-streamed) answer
+    pub(super) fn from_json(index: usize, tool_call: &Value) -> Option<Self> {
+        let function = &tool_call["function"];
+        let arguments = match &function["arguments"] {
+            Value::String(arguments) => arguments.clone(),
+            Value::Null => String::new(),
+            // some servers send the arguments as object
+            arguments => arguments.to_string(),
+        };
+        Some(Self {
+            id: tool_call["id"]
+                .as_str()
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| format!("call_{index}")),
+            name: function["name"].as_str()?.to_string(),
+            arguments,
+        })
+    }
+}
+
+/// A piece of a tool call in a streamed answer.
+///
+/// The first piece of a call has its id and name, the arguments arrive in pieces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+impl ToolCallDelta {
+    pub(super) fn from_json(tool_call: &Value) -> Self {
+        let function = &tool_call["function"];
+        Self {
+            index: tool_call["index"].as_u64().unwrap_or(0) as usize,
+            id: tool_call["id"].as_str().map(|id| id.to_string()),
+            name: function["name"].as_str().map(|name| name.to_string()),
+            arguments: function["arguments"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    /// Adds this piece to the tool calls received so far
+    pub fn apply(self, tool_calls: &mut Vec<ToolCall>) {
+        if tool_calls.len() <= self.index {
+            tool_calls.resize_with(self.index + 1, ToolCall::default);
+        }
+        let tool_call = &mut tool_calls[self.index];
+        if let Some(id) = self.id {
+            tool_call.id = id;
+        }
+        if let Some(name) = self.name {
+            tool_call.name = name;
+        }
+        tool_call.arguments.push_str(&self.arguments);
+    }
+}
+
+type ToolHandler = Box<dyn Fn(&Value) -> Result<String, String> + Send + Sync>;
+
+/// The tools the LLM can use and the Rust functions that answer their calls
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<(ToolDefinition, ToolHandler)>,
+}
+
+impl std::fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.tools.iter().map(|(definition, _)| &definition.name))
+            .finish()
+    }
+}
+
+impl ToolRegistry {
+    /// Adds a tool, `handler` gets the parsed arguments and returns the result for the LLM
+    pub fn register(
+        &mut self,
+        definition: ToolDefinition,
+        handler: impl Fn(&Value) -> Result<String, String> + Send + Sync + 'static,
+    ) {
+        self.tools.retain(|(tool, _)| tool.name != definition.name);
+        self.tools.push((definition, Box::new(handler)));
+    }
+
+    pub fn with_tool(
+        mut self,
+        definition: ToolDefinition,
+        handler: impl Fn(&Value) -> Result<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.register(definition, handler);
+        self
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools
+            .iter()
+            .map(|(definition, _)| definition.clone())
+            .collect()
+    }
+
+    /// Runs the tool and returns its result, errors are returned as text,
+    /// so the LLM can tell the user what went wrong
+    pub fn call(&self, tool_call: &ToolCall) -> String {
+        let Some((_, handler)) = self
+            .tools
+            .iter()
+            .find(|(definition, _)| definition.name == tool_call.name)
+        else {
+            return format!("Error: there is no tool `{}`", tool_call.name);
+        };
+
+        let arguments = if tool_call.arguments.trim().is_empty() {
+            json!({})
+        } else {
+            match serde_json::from_str(&tool_call.arguments) {
+                Ok(arguments) => arguments,
+                Err(e) => return format!("Error: invalid arguments: {e}"),
+            }
+        };
+
+        handler(&arguments).unwrap_or_else(|e| format!("Error: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_pieces_are_merged() {
+        let mut tool_calls = Vec::new();
+        let pieces = [
+            json!({"index": 0, "id": "call_a", "function": {"name": "get_time", "arguments": ""}}),
+            json!({"index": 1, "id": "call_b", "function": {"name": "set_volume", "arguments": "{\"le"}}),
+            json!({"index": 1, "function": {"arguments": "vel\": 3}"}}),
+        ];
+        for piece in &pieces {
+            ToolCallDelta::from_json(piece).apply(&mut tool_calls);
+        }
+
+        assert_eq!(
+            tool_calls,
+            vec![
+                ToolCall {
+                    id: "call_a".into(),
+                    name: "get_time".into(),
+                    arguments: String::new(),
+                },
+                ToolCall {
+                    id: "call_b".into(),
+                    name: "set_volume".into(),
+                    arguments: "{\"level\": 3}".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn registry_calls_handler_with_arguments() {
+        let registry = ToolRegistry::default().with_tool(
+            ToolDefinition::new("double", "Doubles a number"),
+            |arguments| {
+                let number = arguments["number"].as_i64().ok_or("number is missing")?;
+                Ok((number * 2).to_string())
+            },
+        );
+        let call = |name: &str, arguments: &str| {
+            registry.call(&ToolCall {
+                id: "call_0".into(),
+                name: name.into(),
+                arguments: arguments.into(),
+            })
+        };
+
+        assert_eq!(call("double", "{\"number\": 21}"), "42");
+        assert_eq!(call("double", ""), "Error: number is missing");
+        assert!(call("double", "{number").starts_with("Error: invalid arguments"));
+        assert_eq!(call("triple", "{}"), "Error: there is no tool `triple`");
+    }
+}
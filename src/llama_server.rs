@@ -0,0 +1,454 @@
+//! Runs llama-server as a child process of KITT.
+//!
+//! It gets a free port, writes its output to a log file and is restarted when it crashes.
+//! It is stopped when KITT drops it, and on Linux by the kernel if KITT dies without that.
+
+use std::{
+    fs::File,
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::LlamaServerConfig,
+    llama::{Health, LlamaClient, RetryPolicy},
+    shutdown::Shutdown,
+};
+
+/// How often the child process is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Restarts only count as crashes in a row if llama-server ran shorter than this
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// Time llama-server gets to exit on its own before it is killed
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Restarts wait at most this long
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(thiserror::Error, Debug)]
+pub enum LlamaServerError {
+    #[error("Could not start `{binary}`: {source}")]
+    Spawn {
+        binary: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Could not open the llama-server log file `{path}`: {source}")]
+    Log {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("No free port for llama-server: {0}")]
+    NoFreePort(std::io::Error),
+    #[error("llama-server exited while starting ({0}), see `{1}`")]
+    Exited(String, PathBuf),
+    #[error("llama-server did not load the model within {0} seconds")]
+    NotReady(u64),
+    #[error("Stopped while llama-server was starting")]
+    Interrupted,
+    #[error("Could not create a runtime: {0}")]
+    Runtime(std::io::Error),
+}
+
+/// Everything needed to start llama-server again
+struct ServerCommand {
+    binary: PathBuf,
+    args: Vec<String>,
+    log_file: PathBuf,
+}
+
+impl ServerCommand {
+    fn spawn(&self) -> Result<Child, LlamaServerError> {
+        let log_error = |source| LlamaServerError::Log {
+            path: self.log_file.clone(),
+            source,
+        };
+        let log = File::options()
+            .create(true)
+            .append(true)
+            .open(&self.log_file)
+            .map_err(log_error)?;
+
+        let mut command = Command::new(&self.binary);
+        command
+            .args(&self.args)
+            .stdout(log.try_clone().map_err(log_error)?)
+            .stderr(log);
+        // Ctrl+C in the terminal only reaches KITT, which then stops llama-server
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        #[cfg(target_os = "linux")]
+        stop_with_parent(&mut command);
+
+        command.spawn().map_err(|source| LlamaServerError::Spawn {
+            binary: self.binary.clone(),
+            source,
+        })
+    }
+}
+
+pub struct LlamaServer {
+    url: String,
+    stop: Option<Sender<()>>,
+    supervisor: Option<JoinHandle<()>>,
+}
+
+impl LlamaServer {
+    /// Starts llama-server and waits up to `startup_timeout` until it has loaded the model,
+    /// or until a shutdown is requested
+    pub fn start(
+        config: &LlamaServerConfig,
+        startup_timeout: Duration,
+        shutdown: &Shutdown,
+    ) -> Result<Self, LlamaServerError> {
+        let port = match config.port {
+            Some(port) => port,
+            None => free_port().map_err(LlamaServerError::NoFreePort)?,
+        };
+        let url = format!("http://127.0.0.1:{port}");
+
+        let mut args = vec!["-m".to_string(), config.model.display().to_string()];
+        args.extend(config.args.iter().cloned());
+        args.extend(["--host", "127.0.0.1", "--port"].map(String::from));
+        args.push(port.to_string());
+        let command = ServerCommand {
+            binary: config.binary.clone(),
+            args,
+            log_file: config.log_file.clone(),
+        };
+
+        println!(
+            "Starting llama-server at {url}, logging to {}",
+            config.log_file.display()
+        );
+        let mut child = command.spawn()?;
+        if let Err(e) = wait_until_ready(&mut child, &url, &command, startup_timeout, shutdown) {
+            terminate(&mut child);
+            return Err(e);
+        }
+
+        let restart = RetryPolicy {
+            max_retries: config.max_restarts,
+            initial_delay: Duration::from_secs_f32(config.restart_delay),
+            max_delay: MAX_RESTART_DELAY,
+            ..Default::default()
+        };
+        let (stop, stop_receiver) = mpsc::channel();
+        let supervisor = std::thread::spawn(move || {
+            let mut child = child;
+            let mut started = Instant::now();
+            let mut num_restarts = 0;
+            loop {
+                match stop_receiver.recv_timeout(POLL_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return terminate(&mut child),
+                }
+                let status = match child.try_wait() {
+                    Ok(None) => continue,
+                    Ok(Some(status)) => status.to_string(),
+                    Err(e) => e.to_string(),
+                };
+
+                if started.elapsed() > STABLE_AFTER {
+                    num_restarts = 0;
+                }
+                if num_restarts >= restart.max_retries {
+                    eprintln!("Error: llama-server crashed ({status}) too often, giving up");
+                    return;
+                }
+                let delay = restart.delay(num_restarts);
+                num_restarts += 1;
+                eprintln!(
+                    "Error: llama-server crashed ({status}), restarting in {:.1} s",
+                    delay.as_secs_f32()
+                );
+                if !matches!(
+                    stop_receiver.recv_timeout(delay),
+                    Err(RecvTimeoutError::Timeout)
+                ) {
+                    return;
+                }
+                child = match command.spawn() {
+                    Ok(child) => child,
+                    Err(e) => {
+                        eprintln!("Error: {e}");
+                        return;
+                    }
+                };
+                started = Instant::now();
+            }
+        });
+
+        Ok(Self {
+            url,
+            stop: Some(stop),
+            supervisor: Some(supervisor),
+        })
+    }
+
+    /// Where the client can reach llama-server
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Stops llama-server and waits until it exited
+    pub fn stop(&mut self) {
+        // dropping the sender stops the supervisor
+        self.stop.take();
+        if let Some(supervisor) = self.supervisor.take() {
+            let _ = supervisor.join();
+        }
+    }
+}
+
+impl Drop for LlamaServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A port nobody listens on right now
+fn free_port() -> std::io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// Polls `/health` until the model is loaded, fails early if llama-server exits
+fn wait_until_ready(
+    child: &mut Child,
+    url: &str,
+    command: &ServerCommand,
+    timeout: Duration,
+    shutdown: &Shutdown,
+) -> Result<(), LlamaServerError> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(LlamaServerError::Runtime)?;
+    let client = LlamaClient::builder().base_url(url).timeout(1).build();
+
+    let start = Instant::now();
+    loop {
+        if let Ok(Some(status)) = child.try_wait() {
+            let log_file = command.log_file.clone();
+            return Err(LlamaServerError::Exited(status.to_string(), log_file));
+        }
+        if let Ok(Health::Ready) = runtime.block_on(client.health()) {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(LlamaServerError::NotReady(timeout.as_secs()));
+        }
+        if shutdown.requested() {
+            return Err(LlamaServerError::Interrupted);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Asks llama-server to exit and kills it if it takes too long
+fn terminate(child: &mut Child) {
+    request_exit(child);
+
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while Instant::now() < deadline {
+        if !matches!(child.try_wait(), Ok(None)) {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Lets the kernel send SIGTERM to llama-server when KITT dies without stopping it,
+/// e.g. when it is killed with SIGKILL.
+///
+/// The signal is sent when the thread that spawned llama-server exits, that is the main
+/// thread or the supervisor, which only exits after llama-server did.
+#[cfg(target_os = "linux")]
+fn stop_with_parent(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    let parent = std::process::id() as libc::pid_t;
+    // SAFETY: the closure runs between fork and exec and only makes async-signal-safe calls
+    unsafe {
+        command.pre_exec(move || {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // KITT died before the signal was set up
+            if libc::getppid() != parent {
+                return Err(std::io::Error::from_raw_os_error(libc::ESRCH));
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Sends SIGTERM, so llama-server can shut down cleanly
+#[cfg(unix)]
+fn request_exit(child: &Child) {
+    // SAFETY: `kill` only sends a signal, the pid is our child that was not waited for yet
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+#[cfg(not(unix))]
+fn request_exit(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::Path};
+
+    use super::*;
+    use crate::llama::mock_server::MockLlamaServer;
+
+    /// A shell script that stands in for the llama-server binary
+    fn fake_server(dir: &Path, script: &str) -> PathBuf {
+        let path = dir.join("llama-server");
+        std::fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("knight-rider-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(dir: &Path, binary: PathBuf, mock: &MockLlamaServer) -> LlamaServerConfig {
+        let port = mock.url().rsplit(':').next().unwrap().parse().unwrap();
+        LlamaServerConfig {
+            enabled: true,
+            binary,
+            model: "kitt.gguf".into(),
+            args: vec!["-c".into(), "0".into()],
+            // the mock answers `/health` for the fake
+            port: Some(port),
+            log_file: dir.join("llama-server.log"),
+            max_restarts: 2,
+            restart_delay: 0.05,
+        }
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn starts_server_with_arguments_and_logs_its_output() {
+        let dir = test_dir("llama-server-start");
+        let mock = MockLlamaServer::start();
+        let args_file = dir.join("args");
+        let script = format!(
+            "echo \"$@\" > {}\necho 'loading model'\nexec sleep 30",
+            args_file.display()
+        );
+        let config = config(&dir, fake_server(&dir, &script), &mock);
+
+        let mut server =
+            LlamaServer::start(&config, Duration::from_secs(5), &Shutdown::default()).unwrap();
+        assert_eq!(server.url(), mock.url());
+        assert!(wait_for(|| args_file.exists()));
+        let port = config.port.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&args_file).unwrap().trim(),
+            format!("-m kitt.gguf -c 0 --host 127.0.0.1 --port {port}")
+        );
+
+        let start = Instant::now();
+        server.stop();
+        assert!(
+            start.elapsed() < SHUTDOWN_TIMEOUT,
+            "sleep was not terminated"
+        );
+        let log = std::fs::read_to_string(&config.log_file).unwrap();
+        assert!(log.contains("loading model"), "{log}");
+    }
+
+    #[test]
+    fn restarts_crashed_server_until_max_restarts() {
+        let dir = test_dir("llama-server-restart");
+        let mock = MockLlamaServer::start();
+        let starts_file = dir.join("starts");
+        let script = format!("echo start >> {}\nsleep 0.2\nexit 1", starts_file.display());
+        let config = config(&dir, fake_server(&dir, &script), &mock);
+
+        let _server =
+            LlamaServer::start(&config, Duration::from_secs(5), &Shutdown::default()).unwrap();
+        let num_starts = || {
+            std::fs::read_to_string(&starts_file)
+                .map(|starts| starts.lines().count())
+                .unwrap_or(0)
+        };
+        assert!(wait_for(|| num_starts() == 3), "{} starts", num_starts());
+        // it gave up after two restarts
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(num_starts(), 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn server_is_stopped_when_kitt_dies() {
+        let dir = test_dir("llama-server-orphan");
+        let command = ServerCommand {
+            binary: fake_server(&dir, "exec sleep 30"),
+            args: Vec::new(),
+            log_file: dir.join("llama-server.log"),
+        };
+        // the thread that spawned it exits like KITT would, without stopping it
+        let child = std::thread::scope(|scope| scope.spawn(|| command.spawn()).join().unwrap());
+        let child = std::cell::RefCell::new(child.unwrap());
+        assert!(wait_for(|| matches!(
+            child.borrow_mut().try_wait(),
+            Ok(Some(_))
+        )));
+    }
+
+    #[test]
+    fn fails_when_server_exits_while_starting() {
+        let dir = test_dir("llama-server-exit");
+        let script = "echo 'error: failed to load model' >&2\nexit 1";
+        let config = LlamaServerConfig {
+            binary: fake_server(&dir, script),
+            port: None,
+            log_file: dir.join("llama-server.log"),
+            ..Default::default()
+        };
+
+        let result = LlamaServer::start(&config, Duration::from_secs(5), &Shutdown::default());
+        assert!(
+            matches!(result, Err(LlamaServerError::Exited(..))),
+            "{:?}",
+            result.err()
+        );
+        let log = std::fs::read_to_string(&config.log_file).unwrap();
+        assert!(log.contains("failed to load model"), "{log}");
+    }
+
+    #[test]
+    fn fails_when_binary_does_not_exist() {
+        let dir = test_dir("llama-server-missing");
+        let config = LlamaServerConfig {
+            binary: dir.join("no-such-llama-server"),
+            log_file: dir.join("llama-server.log"),
+            ..Default::default()
+        };
+        let result = LlamaServer::start(&config, Duration::from_secs(1), &Shutdown::default());
+        assert!(matches!(result, Err(LlamaServerError::Spawn { .. })));
+    }
+}
@@ -1,96 +1,200 @@
-use std::error::Error;
+use std::{process::ExitCode, time::Duration};
 
+use clap::Parser;
 use llama::BlockingLlama;
 
 use crate::{
-    speech_to_text::{SpeechToText, Vad},
+    cli::{Cli, Command, RunArgs},
+    config::Config,
+    error::KnightRiderError,
+    llama_server::LlamaServer,
+    session::SessionError,
+    shutdown::Shutdown,
     system_audio::{AudioConfig, SystemAudio},
-    text_to_speech::TextToSpeech,
+    voice_loop::VoiceLoop,
 };
 
+mod answer_limit;
+mod audio_backend;
+mod cli;
+mod config;
+mod echo_canceller;
+mod error;
+mod intents;
 mod llama;
+mod llama_server;
+mod offline;
+mod sample_notifier;
+mod session;
+mod shutdown;
+mod speech_pipeline;
 mod speech_to_text;
 mod system_audio;
+mod system_tools;
+mod text_normalizer;
 mod text_to_speech;
-
-fn main() -> Result<(), Box<dyn Error>> {
-    // If you want to select your device type, comment out the following
-    system_audio::list_device_names();
-
-    // Choose which models you want to use
-    let mut vad = Vad::new()?;
-    let mut stt = SpeechToText::new_moonshine()?;
-    let mut tts = TextToSpeech::new_matcha(0);
-
-    let audio_config = AudioConfig {
-        input_device: None,  // None = default
-        output_device: None, // None = default
-        system_sample_rate: 48000,
-        num_frames: 512,
-        vad_sample_rate: vad.sample_rate(),
-        tts_sample_rate: tts.sample_rate(),
-    };
-
-    let mut system_audio = SystemAudio::new(audio_config)?;
-
-    // Start Llama Client
-    let mut llama = match BlockingLlama::new() {
-        Ok(llama) => llama,
-        Err(_) => {
-            eprintln!("Make sure `llama-server` is running and start again!");
-            return Ok(());
+mod timestamp;
+mod voice_loop;
+mod wake_word;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    // Ctrl+C and `kill` let KITT say goodbye, a second Ctrl+C stops it at once
+    let shutdown = match Shutdown::register() {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            eprintln!("Error: Could not handle signals: {e}");
+            return ExitCode::FAILURE;
         }
     };
 
-    // Say something so we know the system is ready
-    println!("K.I.T.T. is ready for your requests..");
-    let generated_speech = tts.create("All systems ready!");
-    system_audio.send_audio(&generated_speech);
+    match run_command(cli, &shutdown) {
+        Ok(()) => shutdown.exit_code(),
+        // e.g. stopped while waiting for llama-server, that is no failure of its own
+        Err(e) if shutdown.requested() => {
+            eprintln!("Error: {e}");
+            shutdown.exit_code()
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            e.exit_code()
+        }
+    }
+}
 
-    // Main AI Loop
-    loop {
-        if system_audio.num_samples_available() >= vad.window_size() {
-            let input_audio = system_audio.receive_audio(vad.window_size());
+fn run_command(cli: Cli, shutdown: &Shutdown) -> Result<(), KnightRiderError> {
+    // Models, devices and parameters are set in `knight-rider.toml`,
+    // listing the devices works without it, so a broken config can be fixed
+    let config = || Config::resolve(cli.config.as_deref());
 
-            vad.process_audio(input_audio);
+    match cli.command.unwrap_or(Command::Run(RunArgs::default())) {
+        Command::Devices => {
+            system_audio::list_devices()?;
+            Ok(())
+        }
+        Command::Check => check(&config()?),
+        Command::File(args) => {
+            let mut config = config()?.validated()?;
+            let _llama_server = start_llama_server(&mut config, shutdown)?;
+            let (output, transcript) = offline::default_output_paths(&args.input);
+            offline::run_file(
+                &config,
+                &args.input,
+                &args.output.unwrap_or(output),
+                &args.transcript.unwrap_or(transcript),
+                shutdown,
+            )
+        }
+        Command::Export(args) => {
+            let config = config()?;
+            let Some(path) = args
+                .session
+                .or_else(|| session::latest(&config.session.directory))
+            else {
+                return Err(SessionError::NotFound(config.session.directory).into());
+            };
+            let transcript = session::transcript(&session::load(&path)?);
+            match args.output {
+                Some(output) => std::fs::write(&output, transcript)
+                    .map_err(|e| KnightRiderError::file(output, e))?,
+                None => print!("{transcript}"),
+            }
+            Ok(())
+        }
+        Command::Run(args) => {
+            let mut config = config()?;
+            args.apply(&mut config);
+            let mut config = config.validated()?;
+            let _llama_server = start_llama_server(&mut config, shutdown)?;
+            run(config, shutdown)
+        }
+    }
+}
 
-            while vad.speech_detected() {
-                // do not accept new speech input while processing
-                system_audio.set_ready_to_receive(false);
+/// Validates everything that is needed to run, without opening audio devices.
+///
+/// Fails with the first problem, so the exit code tells what to fix first.
+fn check(config: &Config) -> Result<(), KnightRiderError> {
+    let problems = config.problems();
+    if problems.is_empty() {
+        println!("ok    config and model files");
+    }
+    for problem in &problems {
+        println!("error {problem}");
+    }
 
-                let transcript = stt.transcribe(&vad.speech_segment());
+    let llama = if !problems.is_empty() {
+        // the client is built from the config, e.g. a negative retry delay would panic
+        println!("skip  llama-server, fix the config first");
+        Ok(())
+    } else if config.llama_server.enabled {
+        // starting it would load the whole model, its binary and model file are checked above
+        println!("skip  llama-server, KITT starts it when it runs");
+        Ok(())
+    } else {
+        match BlockingLlama::new(&config.llm) {
+            Ok(_) => {
+                println!("ok    llama-server at {}", config.llm.url);
+                Ok(())
+            }
+            Err(e) => {
+                println!("error llama-server at {}: {e}", config.llm.url);
+                Err(KnightRiderError::llama(&config.llm.url, e))
+            }
+        }
+    };
 
-                if !transcript.is_empty() {
-                    println!("User: {}", &transcript);
+    match problems.into_iter().next() {
+        Some(problem) => Err(problem.into()),
+        None => llama,
+    }
+}
 
-                    let mut answer = match llama.chat(&transcript) {
-                        Ok(anwser) => anwser,
-                        Err(_) => {
-                            eprintln!("Error: Llama failed to produce an answer...");
-                            "Oh no, I could not produce an answer, there must be an issue with the connection."
-                                .to_string()
-                        }
-                    };
+/// Starts llama-server if KITT manages it and points the client at it.
+///
+/// It runs until the returned server is dropped.
+fn start_llama_server(
+    config: &mut Config,
+    shutdown: &Shutdown,
+) -> Result<Option<LlamaServer>, KnightRiderError> {
+    if !config.llama_server.enabled {
+        return Ok(None);
+    }
+    let startup_timeout = Duration::from_secs(config.llm.startup_timeout);
+    let server = LlamaServer::start(&config.llama_server, startup_timeout, shutdown)?;
+    config.llm.url = server.url().to_string();
+    Ok(Some(server))
+}
 
-                    println!("KITT: {}", &answer);
+/// Starts KITT on the sound card
+fn run(config: Config, shutdown: &Shutdown) -> Result<(), KnightRiderError> {
+    // Start Llama Client
+    let mut llama =
+        BlockingLlama::new(&config.llm).map_err(|e| KnightRiderError::llama(&config.llm.url, e))?;
+
+    // Continue where the last session stopped and save the new messages
+    let (session_log, messages) = session::start(&config.session)?;
+    llama.restore(messages);
+    if let Some(session_log) = session_log {
+        llama.set_session_log(session_log);
+    }
 
-                    // Limit the prompt so it does not take too long to generate the speech
-                    if answer.len() > 200 {
-                        println!("\nWarning: Answer too long, cutting off end...");
-                        answer = answer[0..200].to_string();
-                    }
+    let mut voice_loop = VoiceLoop::new(&config, llama)?.with_shutdown(shutdown.clone());
 
-                    // Remove asterixes from answer, so it doesn't say it all the time
-                    answer = answer.replace('*', "");
+    let audio_config = AudioConfig {
+        input_device: config.audio.input_device.clone(),
+        output_device: config.audio.output_device.clone(),
+        system_sample_rate: config.audio.sample_rate,
+        num_frames: config.audio.num_frames,
+        vad_sample_rate: voice_loop.input_sample_rate(),
+        tts_sample_rate: voice_loop.output_sample_rate(),
+        barge_in: config.audio.barge_in,
+        echo_filter_length: config.audio.echo_filter_length,
+        echo_double_talk_ratio: config.audio.echo_double_talk_ratio,
+    };
 
-                    let generated_speech = tts.create(&answer);
+    let mut system_audio = SystemAudio::new(audio_config)?;
 
-                    system_audio.send_audio(&generated_speech);
-                }
-                vad.delete_speech_segment();
-            }
-            // now new input speech can be accepted
-            system_audio.set_ready_to_receive(true);
-        }
-    }
+    voice_loop.run(&mut system_audio)?;
+    Ok(())
 }
@@ -0,0 +1,236 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use rubato::{FftFixedIn, Resampler};
+use serde_json::{json, Value};
+
+use crate::{
+    audio_backend::WavFileAudio,
+    config::Config,
+    error::KnightRiderError,
+    llama::BlockingLlama,
+    shutdown::Shutdown,
+    voice_loop::{Turn, VoiceLoop},
+};
+
+/// Runs the voice loop on a WAV file instead of the sound card:
+/// VAD → speech to text → LLM → text to speech.
+///
+/// The spoken answers are written to `output`, the transcript with the timings
+/// of every stage is written as JSON to `transcript`. After a shutdown request
+/// only the answers so far are written.
+pub fn run_file(
+    config: &Config,
+    input: &Path,
+    output: &Path,
+    transcript: &Path,
+    shutdown: &Shutdown,
+) -> Result<(), KnightRiderError> {
+    let llama =
+        BlockingLlama::new(&config.llm).map_err(|e| KnightRiderError::llama(&config.llm.url, e))?;
+    let voice_loop = VoiceLoop::new(config, llama)?.with_shutdown(shutdown.clone());
+    run_voice_loop(voice_loop, input, output, transcript)
+}
+
+fn run_voice_loop(
+    mut voice_loop: VoiceLoop,
+    input: &Path,
+    output: &Path,
+    transcript: &Path,
+) -> Result<(), KnightRiderError> {
+    let total_start = Instant::now();
+
+    let mut audio = WavFileAudio::new(
+        input,
+        output,
+        voice_loop.input_sample_rate(),
+        voice_loop.output_sample_rate(),
+    )
+    .map_err(|e| KnightRiderError::file(input, e))?;
+    voice_loop.run(&mut audio)?;
+    audio
+        .write_output()
+        .map_err(|e| KnightRiderError::file(output, e))?;
+
+    let turns: Vec<Value> = voice_loop.turns().iter().map(turn_json).collect();
+    let transcript_json = json!({
+        "input": input,
+        "output": output,
+        "turns": turns,
+        "timings_ms": {
+            "total": total_start.elapsed().as_secs_f64() * 1000.0,
+        },
+    });
+    let transcript_json = serde_json::to_string_pretty(&transcript_json)
+        .map_err(|e| KnightRiderError::file(transcript, e))?;
+    std::fs::write(transcript, transcript_json)
+        .map_err(|e| KnightRiderError::file(transcript, e))?;
+
+    println!(
+        "Wrote {} answers to {} and {}",
+        turns.len(),
+        output.display(),
+        transcript.display()
+    );
+    Ok(())
+}
+
+/// A turn of the transcript, the answer is what KITT said
+fn turn_json(turn: &Turn) -> Value {
+    let answer = turn.answer.as_ref();
+    json!({
+        "user": turn.question.content,
+        "assistant": answer.map(|answer| &answer.content),
+        "interrupted": answer.is_some_and(|answer| answer.interrupted),
+        "truncated": answer.is_some_and(|answer| answer.truncated),
+        "speech_seconds": turn.question_duration.as_secs_f64(),
+        "answer_seconds": turn.answer_duration.as_secs_f64(),
+        "timings_ms": {
+            "stt": turn.question.metadata.stt_ms,
+            "first_token": answer.and_then(|answer| answer.metadata.first_token_ms),
+            "llm": answer.and_then(|answer| answer.metadata.llm_ms),
+            "tts": turn.tts_time.as_secs_f64() * 1000.0,
+        },
+    })
+}
+
+/// Reads a WAV file and mixes it down to mono
+pub fn read_wav(path: &Path) -> Result<(Vec<f32>, u32), Box<dyn Error>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    let num_channels = spec.channels as usize;
+    let mono = samples
+        .chunks(num_channels)
+        .map(|frame| frame.iter().sum::<f32>() / num_channels as f32)
+        .collect();
+
+    Ok((mono, spec.sample_rate))
+}
+
+/// Writes mono audio as 32 bit float WAV file
+pub fn write_wav(path: &Path, audio: &[f32], sample_rate: u32) -> Result<(), Box<dyn Error>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for sample in audio {
+        writer.write_sample(*sample)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Resamples a whole recording at once
+pub fn resample(audio: &[f32], from: u32, to: u32) -> Result<Vec<f32>, Box<dyn Error>> {
+    if from == to {
+        return Ok(audio.to_vec());
+    }
+
+    let chunk_size = 1024;
+    let mut resampler = FftFixedIn::<f32>::new(from as usize, to as usize, chunk_size, 1, 1)?;
+    let delay = resampler.output_delay();
+    let num_output_samples = (audio.len() as u64 * to as u64 / from as u64) as usize;
+
+    let mut output = Vec::with_capacity(num_output_samples + delay);
+    for chunk in audio.chunks(chunk_size) {
+        let mut chunk = chunk.to_vec();
+        chunk.resize(chunk_size, 0.0);
+        let resampled = resampler.process(&[chunk], None)?;
+        output.extend_from_slice(&resampled[0]);
+    }
+
+    // feed silence, so the delayed output of the last samples gets out of the resampler
+    let silence = vec![0.0; chunk_size];
+    while output.len() < num_output_samples + delay {
+        let resampled = resampler.process(&[&silence], None)?;
+        output.extend_from_slice(&resampled[0]);
+    }
+
+    Ok(output
+        .into_iter()
+        .skip(delay)
+        .take(num_output_samples)
+        .collect())
+}
+
+/// Default paths next to the input file
+pub fn default_output_paths(input: &Path) -> (PathBuf, PathBuf) {
+    (
+        input.with_extension("reply.wav"),
+        input.with_extension("transcript.json"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama::mock_server::{MockLlamaServer, MockResponse};
+
+    #[test]
+    fn answers_the_questions_of_a_recording() {
+        let server = MockLlamaServer::start();
+        server.push_response(MockResponse::Stream(vec![
+            "Turbo boost".into(),
+            " is ready.".into(),
+        ]));
+        let mut config = Config::default();
+        config.llm.url = server.url().to_string();
+        config.llm.startup_timeout = 0;
+        config.llm.health_interval = 0;
+        let voice_loop = VoiceLoop::with_test_models(&config, &["What is your top speed?"]);
+
+        let dir = std::env::temp_dir().join(format!("knight-rider-offline-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("question.wav");
+        let (output, transcript) = default_output_paths(&input);
+
+        // a second of a tone between silence, recorded at another rate than the VAD's
+        let sample_rate = 48000;
+        let recording: Vec<f32> = (0..3 * sample_rate)
+            .map(|n| match n / sample_rate {
+                1 => {
+                    0.1 * (2.0 * std::f32::consts::PI * 440.0 * n as f32 / sample_rate as f32).sin()
+                }
+                _ => 0.0,
+            })
+            .collect();
+        write_wav(&input, &recording, sample_rate as u32).unwrap();
+
+        run_voice_loop(voice_loop, &input, &output, &transcript).unwrap();
+
+        assert_eq!(server.requests().len(), 1);
+
+        // the test TTS model beeps 10 samples per character
+        let (reply, _) = read_wav(&output).unwrap();
+        let spoken = "All systems ready!".len() + "Turbo boost is ready.".len();
+        assert_eq!(reply.len(), spoken * 10);
+
+        let transcript: Value =
+            serde_json::from_str(&std::fs::read_to_string(&transcript).unwrap()).unwrap();
+        let turns = transcript["turns"].as_array().unwrap();
+        assert_eq!(turns.len(), 1);
+        assert_eq!(turns[0]["user"], "What is your top speed?");
+        assert_eq!(turns[0]["assistant"], "Turbo boost is ready.");
+        assert_eq!(turns[0]["interrupted"], false);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Wakes up the voice loop when the audio callback recorded enough samples,
+//! so it can sleep in between instead of polling the ringbuffer all the time.
+//!
+//! The callback side only does atomic loads and `Thread::unpark`, which neither
+//! allocates nor locks, so it is fine for the real-time thread.
+
+use std::{
+    sync::{
+        atomic::{fence, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+pub struct SampleNotifier {
+    // number of samples the waiting thread needs, 0 if it is not waiting
+    wanted: AtomicUsize,
+    // only one thread reads the input, it is registered by its first wait
+    waiter: OnceLock<Thread>,
+}
+
+impl SampleNotifier {
+    /// Called by the audio callback after it pushed samples, `num_available`
+    /// is the number of samples in the ringbuffer now
+    pub fn notify(&self, num_available: usize) {
+        // pairs with the fence in `wait`, either the waiter sees the new samples
+        // or we see that it is waiting
+        fence(Ordering::SeqCst);
+        let wanted = self.wanted.load(Ordering::Relaxed);
+        if wanted > 0 && num_available >= wanted {
+            if let Some(waiter) = self.waiter.get() {
+                waiter.unpark();
+            }
+        }
+    }
+
+    /// Sleeps until `num_available()` returns at least `num_samples`.
+    ///
+    /// Returns false if that did not happen within `timeout`.
+    pub fn wait(
+        &self,
+        num_samples: usize,
+        timeout: Duration,
+        num_available: impl Fn() -> usize,
+    ) -> bool {
+        if num_available() >= num_samples {
+            return true;
+        }
+        let waiter = self.waiter.get_or_init(thread::current);
+        let is_waiter = waiter.id() == thread::current().id();
+
+        let deadline = Instant::now() + timeout;
+        let ready = loop {
+            self.wanted.store(num_samples, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            // the callback may have pushed the samples before it saw `wanted`
+            if num_available() >= num_samples {
+                break true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break false;
+            }
+            if is_waiter {
+                thread::park_timeout(remaining);
+            } else {
+                // another thread is registered, poll instead of missing the wake-up
+                thread::sleep(remaining.min(Duration::from_millis(5)));
+            }
+        };
+        self.wanted.store(0, Ordering::Relaxed);
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    };
+
+    use super::*;
+
+    #[test]
+    fn waiter_wakes_up_when_enough_samples_arrived() {
+        let notifier = Arc::new(SampleNotifier::default());
+        let num_samples = Arc::new(AtomicUsize::new(0));
+        let num_available = || num_samples.load(Ordering::Relaxed);
+
+        assert!(!notifier.wait(512, Duration::from_millis(20), num_available));
+
+        let producer = {
+            let (notifier, num_samples) = (notifier.clone(), num_samples.clone());
+            thread::spawn(move || {
+                for _ in 0..4 {
+                    thread::sleep(Duration::from_millis(20));
+                    let num_available = num_samples.fetch_add(128, Ordering::Relaxed) + 128;
+                    notifier.notify(num_available);
+                }
+            })
+        };
+        let start = Instant::now();
+        assert!(notifier.wait(512, Duration::from_secs(5), num_available));
+        // woken up by the last push, not by the timeout
+        assert!(start.elapsed() < Duration::from_secs(1));
+        producer.join().unwrap();
+    }
+
+    /// CPU time the current thread used so far
+    #[cfg(unix)]
+    fn thread_cpu_time() -> Duration {
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: `time` is a valid timespec to write into
+        unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+
+    /// Compares the CPU usage of the voice loop while nobody speaks, when it polls the
+    /// ringbuffer and when it waits for the notification.
+    ///
+    /// `cargo test --release idle_cpu -- --ignored --nocapture`
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "benchmark, takes a few seconds"]
+    fn idle_cpu_benchmark() {
+        // 512 samples at 16 kHz every 32 ms, like the resampled sound card input
+        const WINDOW: usize = 512;
+        const PERIOD: Duration = Duration::from_millis(32);
+        const DURATION: Duration = Duration::from_secs(2);
+
+        let measure = |event_driven: bool| {
+            let notifier = Arc::new(SampleNotifier::default());
+            let num_samples = Arc::new(AtomicUsize::new(0));
+            let done = Arc::new(AtomicBool::new(false));
+            let callback = {
+                let (notifier, num_samples, done) =
+                    (notifier.clone(), num_samples.clone(), done.clone());
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        thread::sleep(PERIOD);
+                        let num_available =
+                            num_samples.fetch_add(WINDOW, Ordering::Relaxed) + WINDOW;
+                        notifier.notify(num_available);
+                    }
+                })
+            };
+
+            let num_available = || num_samples.load(Ordering::Relaxed);
+            let cpu_start = thread_cpu_time();
+            let start = Instant::now();
+            let mut num_windows = 0_usize;
+            while start.elapsed() < DURATION {
+                let ready = if event_driven {
+                    notifier.wait(WINDOW, Duration::from_millis(100), num_available)
+                } else {
+                    num_available() >= WINDOW
+                };
+                if ready {
+                    num_samples.fetch_sub(WINDOW, Ordering::Relaxed);
+                    num_windows += 1;
+                }
+            }
+            let cpu = (thread_cpu_time() - cpu_start).as_secs_f64() / DURATION.as_secs_f64();
+            done.store(true, Ordering::Relaxed);
+            callback.join().unwrap();
+            (cpu * 100.0, num_windows)
+        };
+
+        let (polling_cpu, polling_windows) = measure(false);
+        let (waiting_cpu, waiting_windows) = measure(true);
+        println!("polling: {polling_cpu:5.1}% CPU, {polling_windows} windows");
+        println!("waiting: {waiting_cpu:5.1}% CPU, {waiting_windows} windows");
+        assert!(waiting_cpu < 5.0, "{waiting_cpu}% CPU while waiting");
+        assert!(waiting_windows.abs_diff(polling_windows) <= 2);
+    }
+}
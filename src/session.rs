@@ -0,0 +1,327 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::SessionConfig,
+    llama::ChatMessage,
+    timestamp::{format_timestamp, unix_millis},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    #[error("Could not access session `{path}`: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid message in session `{path}` line {line}: {source}")]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("No session found in `{0}`")]
+    NotFound(PathBuf),
+}
+
+/// Appends the messages of a conversation to a JSON Lines file, one message per line.
+///
+/// An interrupted or truncated answer is appended again with `"interrupted": true` or
+/// `"truncated": true`, it replaces the answer before it when the session is loaded.
+#[derive(Debug)]
+pub struct SessionLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl SessionLog {
+    /// Starts a new session file in `directory`, named after the current time
+    pub fn create(directory: &Path) -> Result<Self, SessionError> {
+        std::fs::create_dir_all(directory).map_err(|source| SessionError::Io {
+            path: directory.to_path_buf(),
+            source,
+        })?;
+        let now = unix_millis();
+        let name = format!(
+            "{}-{:03}",
+            format_timestamp(now).replace(' ', "_").replace(':', "-"),
+            now % 1000
+        );
+        // a session started in the same millisecond gets a file of its own as well
+        let mut path = directory.join(format!("{name}.jsonl"));
+        let mut n = 1;
+        while path.exists() {
+            path = directory.join(format!("{name}_{n}.jsonl"));
+            n += 1;
+        }
+        Self::open(&path)
+    }
+
+    /// Continues an existing session file
+    pub fn open(path: &Path) -> Result<Self, SessionError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| SessionError::Io {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&mut self, messages: &[ChatMessage]) -> Result<(), SessionError> {
+        let mut lines = String::new();
+        for message in messages {
+            // serializing a message with string fields can not fail
+            lines.push_str(&serde_json::to_string(message).unwrap_or_default());
+            lines.push('\n');
+        }
+        // one write per turn, so a killed process does not leave half a turn behind
+        self.file
+            .write_all(lines.as_bytes())
+            .map_err(|source| SessionError::Io {
+                path: self.path.clone(),
+                source,
+            })
+    }
+
+    /// Makes sure everything is on the disk, e.g. before the Pi is switched off
+    pub fn sync(&self) -> Result<(), SessionError> {
+        self.file.sync_all().map_err(|source| SessionError::Io {
+            path: self.path.clone(),
+            source,
+        })
+    }
+}
+
+/// Reads all messages of a session file
+pub fn load(path: &Path) -> Result<Vec<ChatMessage>, SessionError> {
+    let content = std::fs::read_to_string(path).map_err(|source| SessionError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    let mut messages: Vec<ChatMessage> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: ChatMessage =
+            serde_json::from_str(line).map_err(|source| SessionError::Parse {
+                path: path.to_path_buf(),
+                line: i + 1,
+                source,
+            })?;
+
+        match messages.last_mut() {
+            Some(last)
+                if (message.interrupted || message.truncated) && last.role == "assistant" =>
+            {
+                *last = message
+            }
+            _ => messages.push(message),
+        }
+    }
+    Ok(messages)
+}
+
+/// The most recent session file in `directory`
+pub fn latest(directory: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(directory)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "jsonl")
+        })
+        // the file names are timestamps, so the newest one sorts last
+        .max()
+}
+
+/// Opens the session log for the voice loop, and the messages to resume with
+pub fn start(
+    config: &SessionConfig,
+) -> Result<(Option<SessionLog>, Vec<ChatMessage>), SessionError> {
+    let resumed = if config.resume {
+        match latest(&config.directory) {
+            Some(path) => {
+                let messages = load(&path)?;
+                println!(
+                    "Resuming session {} with {} messages",
+                    path.display(),
+                    messages.len()
+                );
+                Some((path, messages))
+            }
+            None => {
+                println!("No session to resume in {}", config.directory.display());
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let log = match (&resumed, config.save) {
+        (_, false) => None,
+        (Some((path, _)), true) => Some(SessionLog::open(path)?),
+        (None, true) => Some(SessionLog::create(&config.directory)?),
+    };
+    if let Some(log) = &log {
+        println!("Saving the conversation to {}", log.path().display());
+    }
+    let messages = resumed.map(|(_, messages)| messages).unwrap_or_default();
+    Ok((log, messages))
+}
+
+/// A readable transcript of a session
+pub fn transcript(messages: &[ChatMessage]) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        let speaker = match message.role.as_str() {
+            "user" => "User",
+            "assistant" => "KITT",
+            "system" => "System",
+            "tool" => "Tool",
+            other => other,
+        };
+        let time = message
+            .timestamp
+            .map(|timestamp| format!("[{}] ", format_timestamp(timestamp)))
+            .unwrap_or_default();
+        let cut_off = if message.interrupted {
+            "... [interrupted]"
+        } else if message.truncated {
+            " [truncated]"
+        } else {
+            ""
+        };
+        transcript.push_str(&format!("{time}{speaker}: {}{cut_off}\n", message.content));
+
+        let metadata = &message.metadata;
+        let mut details = Vec::new();
+        for tool_call in &message.tool_calls {
+            details.push(format!("calls {}({})", tool_call.name, tool_call.arguments));
+        }
+        if let Some(stt_model) = &metadata.stt_model {
+            details.push(stt_model.clone());
+        }
+        if let Some(stt_ms) = metadata.stt_ms {
+            details.push(format!("transcribed in {stt_ms} ms"));
+        }
+        if let Some(first_token_ms) = metadata.first_token_ms {
+            details.push(format!("first token after {first_token_ms} ms"));
+        }
+        if let Some(llm_ms) = metadata.llm_ms {
+            details.push(format!("answered in {llm_ms} ms"));
+        }
+        if !details.is_empty() {
+            transcript.push_str(&format!("    ({})\n", details.join(", ")));
+        }
+    }
+    transcript
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama::MessageMetadata;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("knight-rider-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn saved_session_can_be_resumed() {
+        let dir = temp_dir("session");
+        let mut log = SessionLog::create(&dir).unwrap();
+
+        let mut question = ChatMessage::user("How is the weather?");
+        question.timestamp = Some(1_000);
+        question.metadata.stt_model = Some("moonshine".into());
+        question.metadata.stt_ms = Some(230);
+        let answer = ChatMessage::assistant("It is sunny and warm today.");
+        log.append(&[question, answer.clone()]).unwrap();
+
+        let mut interrupted = answer;
+        interrupted.content = "It is sunny".into();
+        interrupted.interrupted = true;
+        log.append(&[interrupted]).unwrap();
+
+        assert_eq!(latest(&dir).as_deref(), Some(log.path()));
+
+        let messages = load(log.path()).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].timestamp, Some(1_000));
+        assert_eq!(
+            messages[0].metadata,
+            MessageMetadata {
+                stt_model: Some("moonshine".into()),
+                stt_ms: Some(230),
+                ..Default::default()
+            }
+        );
+        assert_eq!(messages[1].content, "It is sunny");
+        assert!(messages[1].interrupted);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sessions_started_at_once_get_their_own_files() {
+        let dir = temp_dir("sessions");
+        let first = SessionLog::create(&dir).unwrap();
+        let second = SessionLog::create(&dir).unwrap();
+
+        assert_ne!(first.path(), second.path());
+        assert_eq!(latest(&dir).as_deref(), Some(second.path()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_broken_lines() {
+        let dir = temp_dir("broken");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("broken.jsonl");
+        std::fs::write(&path, "{\"role\":\"user\",\"content\":\"Hi\"}\n{not json\n").unwrap();
+
+        let result = load(&path);
+        assert!(matches!(result, Err(SessionError::Parse { line: 2, .. })));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transcript_is_readable() {
+        let mut question = ChatMessage::user("Where are we?");
+        question.timestamp = Some(1_792_241_999_000);
+        question.metadata.stt_model = Some("whisper".into());
+        question.metadata.stt_ms = Some(310);
+        let mut answer = ChatMessage::assistant("On the highway");
+        answer.interrupted = true;
+        answer.metadata.first_token_ms = Some(150);
+        answer.metadata.llm_ms = Some(900);
+
+        assert_eq!(
+            transcript(&[question, answer]),
+            "[2026-10-17 12:59:59] User: Where are we?\n\
+             \x20   (whisper, transcribed in 310 ms)\n\
+             KITT: On the highway... [interrupted]\n\
+             \x20   (first token after 150 ms, answered in 900 ms)\n"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Stops KITT cleanly on Ctrl+C (SIGINT) or `kill` (SIGTERM).
+//!
+//! The first signal asks KITT to say goodbye and stop, a second one exits at once.
+
+use std::{
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    flag,
+};
+
+/// How often [`Shutdown::wait`] checks the flag
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tells if KITT was asked to stop, clones share the state
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    signal: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    /// Handles SIGINT and SIGTERM from now on
+    pub fn register() -> std::io::Result<Self> {
+        let shutdown = Self::default();
+        for signal in [SIGINT, SIGTERM] {
+            // registered first, so it only exits if the flag was set by an earlier signal
+            flag::register_conditional_shutdown(signal, 128 + signal, shutdown.requested.clone())?;
+            flag::register_usize(signal, shutdown.signal.clone(), signal as usize)?;
+            flag::register(signal, shutdown.requested.clone())?;
+        }
+        Ok(shutdown)
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Returns once a shutdown is requested, to race it against a request that takes a while
+    pub async fn wait(&self) {
+        while !self.requested() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Does what the signal handlers do
+    #[cfg(test)]
+    pub fn request(&self, signal: i32) {
+        self.signal.store(signal as usize, Ordering::Relaxed);
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    /// 128 plus the signal number after a signal, like a shell reports it, otherwise success
+    pub fn exit_code(&self) -> ExitCode {
+        match self.signal.load(Ordering::Relaxed) {
+            0 => ExitCode::SUCCESS,
+            signal => ExitCode::from(128 + signal as u8),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_requests_shutdown() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.requested());
+        assert_eq!(shutdown.exit_code(), ExitCode::SUCCESS);
+
+        shutdown.request(SIGTERM);
+        assert!(shutdown.requested());
+        assert!(shutdown.clone().requested());
+        assert_eq!(shutdown.exit_code(), ExitCode::from(143));
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::text_to_speech::{TextToSpeech, FALLBACK_PHRASE};
+
+/// Abbreviations that end with a dot but do not end a sentence
+const ABBREVIATIONS: [&str; 8] = ["mr", "mrs", "ms", "dr", "st", "vs", "e.g", "i.e"];
+
+/// Splits streamed LLM text into sentences that can be synthesized one by one.
+#[derive(Debug, Default)]
+pub struct SentenceSplitter {
+    buffer: String,
+}
+
+impl SentenceSplitter {
+    /// Adds the next piece of generated text and returns all sentences that are complete now.
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.buffer.push_str(text);
+
+        let mut sentences = Vec::new();
+        while let Some(end) = self.sentence_end() {
+            let rest = self.buffer.split_off(end);
+            let sentence = std::mem::replace(&mut self.buffer, rest);
+            let sentence = sentence.trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+        }
+        sentences
+    }
+
+    /// Returns the remaining text once the LLM is done generating.
+    pub fn finish(&mut self) -> Option<String> {
+        let sentence = std::mem::take(&mut self.buffer);
+        let sentence = sentence.trim();
+        (!sentence.is_empty()).then(|| sentence.to_string())
+    }
+
+    /// Byte index after the first sentence in the buffer, if it is complete.
+    ///
+    /// A sentence ends at a newline or at `.`, `!`, `?` or `;` followed by whitespace,
+    /// so decimal numbers like "3.5" are not split before the next character is known.
+    fn sentence_end(&self) -> Option<usize> {
+        let mut chars = self.buffer.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c == '\n' {
+                return Some(i + 1);
+            }
+            if !matches!(c, '.' | '!' | '?' | ';') {
+                continue;
+            }
+            match chars.peek() {
+                Some((_, next)) if next.is_whitespace() => {
+                    if c == '.' && self.is_abbreviation(i) {
+                        continue;
+                    }
+                    return Some(i + 1);
+                }
+                _ => continue,
+            }
+        }
+        None
+    }
+
+    fn is_abbreviation(&self, dot: usize) -> bool {
+        let word = self.buffer[..dot]
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        ABBREVIATIONS.contains(&word.as_str())
+    }
+}
+
+/// A sentence together with its generated speech
+pub struct SynthesizedSentence {
+    pub text: String,
+    pub audio: Vec<f32>,
+    /// How long the TTS model took
+    pub synthesis_time: Duration,
+}
+
+/// What the worker thread has to do next
+enum SpeechRequest {
+    /// Synthesize a sentence of a generation
+    Speak(u64, String),
+    /// Use another speaker id for the following sentences
+    SetVoice(i32),
+}
+
+/// Synthesizes sentences on a worker thread, so the next sentence can be generated
+/// by the LLM while the previous one is synthesized and played.
+pub struct SpeechPipeline {
+    sentence_sender: Option<Sender<SpeechRequest>>,
+    audio_receiver: Receiver<(u64, SynthesizedSentence)>,
+    // sentences from older generations were cancelled and are skipped
+    generation: Arc<AtomicU64>,
+    num_pending: usize,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SpeechPipeline {
+    pub fn new(mut tts: TextToSpeech) -> Self {
+        let (sentence_sender, sentence_receiver) = mpsc::channel::<SpeechRequest>();
+        let (audio_sender, audio_receiver) = mpsc::channel();
+
+        let generation = Arc::new(AtomicU64::new(0));
+        let worker_generation = generation.clone();
+
+        let worker = std::thread::spawn(move || {
+            for request in sentence_receiver {
+                let (generation, text) = match request {
+                    SpeechRequest::Speak(generation, text) => (generation, text),
+                    SpeechRequest::SetVoice(voice) => {
+                        tts.set_voice(voice);
+                        continue;
+                    }
+                };
+                if generation != worker_generation.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let start = Instant::now();
+                let (text, audio) = match tts.create(&text) {
+                    Ok(audio) => (text, audio),
+                    Err(e) => {
+                        eprintln!("Error: {e}, could not say {text:?}");
+                        let audio = tts.create(FALLBACK_PHRASE).unwrap_or_else(|e| {
+                            eprintln!("Error: {e}, could not say the fallback phrase either");
+                            Vec::new()
+                        });
+                        (FALLBACK_PHRASE.to_string(), audio)
+                    }
+                };
+                if audio_sender
+                    .send((
+                        generation,
+                        SynthesizedSentence {
+                            text,
+                            audio,
+                            synthesis_time: start.elapsed(),
+                        },
+                    ))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            sentence_sender: Some(sentence_sender),
+            audio_receiver,
+            generation,
+            num_pending: 0,
+            worker: Some(worker),
+        }
+    }
+
+    /// Queues a sentence for synthesis
+    pub fn speak(&mut self, sentence: impl Into<String>) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        if let Some(sender) = &self.sentence_sender {
+            if sender
+                .send(SpeechRequest::Speak(generation, sentence.into()))
+                .is_ok()
+            {
+                self.num_pending += 1;
+            }
+        }
+    }
+
+    /// Speaks the following sentences with another voice of the TTS model
+    pub fn set_voice(&mut self, voice: i32) {
+        if let Some(sender) = &self.sentence_sender {
+            let _ = sender.send(SpeechRequest::SetVoice(voice));
+        }
+    }
+
+    /// Drops all sentences that are queued or currently synthesized
+    pub fn cancel(&mut self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.num_pending = 0;
+    }
+
+    /// True if all queued sentences were received
+    pub fn is_done(&self) -> bool {
+        self.num_pending == 0
+    }
+
+    /// Returns the next synthesized sentence if it is ready, without blocking
+    pub fn try_receive_audio(&mut self) -> Option<SynthesizedSentence> {
+        loop {
+            let (generation, sentence) = match self.audio_receiver.try_recv() {
+                Ok(received) => received,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => return self.worker_stopped(),
+            };
+            if let Some(sentence) = self.accept(generation, sentence) {
+                return Some(sentence);
+            }
+        }
+    }
+
+    /// Waits for the next synthesized sentence, returns `None` if nothing is pending
+    pub fn receive_audio(&mut self) -> Option<SynthesizedSentence> {
+        while self.num_pending > 0 {
+            let Ok((generation, sentence)) = self.audio_receiver.recv() else {
+                return self.worker_stopped();
+            };
+            if let Some(sentence) = self.accept(generation, sentence) {
+                return Some(sentence);
+            }
+        }
+        None
+    }
+
+    /// Like [`SpeechPipeline::receive_audio`], but gives up after `timeout`
+    pub fn receive_audio_timeout(&mut self, timeout: Duration) -> Option<SynthesizedSentence> {
+        let deadline = Instant::now() + timeout;
+        while self.num_pending > 0 {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let (generation, sentence) = match self.audio_receiver.recv_timeout(timeout) {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) => return None,
+                Err(RecvTimeoutError::Disconnected) => return self.worker_stopped(),
+            };
+            if let Some(sentence) = self.accept(generation, sentence) {
+                return Some(sentence);
+            }
+        }
+        None
+    }
+
+    /// The worker thread panicked, nothing that is pending will ever arrive
+    fn worker_stopped(&mut self) -> Option<SynthesizedSentence> {
+        if self.num_pending > 0 {
+            eprintln!(
+                "Error: speech synthesis stopped, dropping {} sentences",
+                self.num_pending
+            );
+            self.num_pending = 0;
+        }
+        None
+    }
+
+    fn accept(
+        &mut self,
+        generation: u64,
+        sentence: SynthesizedSentence,
+    ) -> Option<SynthesizedSentence> {
+        if generation != self.generation.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.num_pending -= 1;
+        Some(sentence)
+    }
+}
+
+impl Drop for SpeechPipeline {
+    fn drop(&mut self) {
+        // closing the channel ends the worker loop
+        self.sentence_sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Keeps track of which sentences of an answer were sent to the speakers,
+/// so we know how much of it the user heard when they interrupted.
+#[derive(Debug, Default)]
+pub struct SpokenAnswer {
+    sentences: Vec<(String, usize)>,
+}
+
+impl SpokenAnswer {
+    pub fn push(&mut self, text: impl Into<String>, num_samples: usize) {
+        self.sentences.push((text.into(), num_samples));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sentences.is_empty()
+    }
+
+    /// The text that was played, if playback stopped with `num_remaining` samples left.
+    ///
+    /// The sentence that was playing is cut proportionally at a word boundary.
+    pub fn heard_text(&self, num_remaining: usize) -> String {
+        let num_samples: usize = self.sentences.iter().map(|(_, n)| n).sum();
+        let mut num_played = num_samples.saturating_sub(num_remaining);
+
+        let mut heard = Vec::new();
+        for (text, n) in &self.sentences {
+            if num_played >= *n {
+                heard.push(text.clone());
+                num_played -= n;
+                continue;
+            }
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let num_words = words.len() * num_played / (*n).max(1);
+            if num_words > 0 {
+                heard.push(words[..num_words].join(" "));
+            }
+            break;
+        }
+        heard.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Streams `text` in chunks of `chunk_size` chars and collects all sentences
+    fn split(text: &str, chunk_size: usize) -> Vec<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut splitter = SentenceSplitter::default();
+        let mut sentences = Vec::new();
+        for chunk in chars.chunks(chunk_size) {
+            sentences.extend(splitter.push(&chunk.iter().collect::<String>()));
+        }
+        sentences.extend(splitter.finish());
+        sentences
+    }
+
+    fn check(cases: &[(&str, &[&str])]) {
+        for (input, expected) in cases {
+            // the LLM streams a few characters at a time, the result must not depend on it
+            for chunk_size in [1, 3, usize::MAX] {
+                assert_eq!(
+                    split(input, chunk_size.min(input.len().max(1))),
+                    *expected,
+                    "input: {input:?}, chunk size: {chunk_size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn splits_at_sentence_ends() {
+        check(&[
+            (
+                "Hello Michael. How are you? I'm fine!",
+                &["Hello Michael.", "How are you?", "I'm fine!"],
+            ),
+            ("First; second.", &["First;", "second."]),
+            ("One line\nand another", &["One line", "and another"]),
+            ("Turbo boost.  Engaged.", &["Turbo boost.", "Engaged."]),
+            ("", &[]),
+            ("   ", &[]),
+        ]);
+    }
+
+    #[test]
+    fn keeps_abbreviations() {
+        check(&[
+            (
+                "Dr. Bonnie Barstow called. Mr. Miles too.",
+                &["Dr. Bonnie Barstow called.", "Mr. Miles too."],
+            ),
+            (
+                "Use a tool, e.g. the scanner. Or not.",
+                &["Use a tool, e.g. the scanner.", "Or not."],
+            ),
+            (
+                "It's KITT vs. KARR. Again.",
+                &["It's KITT vs. KARR.", "Again."],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn keeps_decimals_and_ellipses() {
+        check(&[
+            (
+                "Top speed is 3.5 seconds. Go!",
+                &["Top speed is 3.5 seconds.", "Go!"],
+            ),
+            (
+                "Version 1.2.3 is installed.",
+                &["Version 1.2.3 is installed."],
+            ),
+            ("Wait... what?! Really?", &["Wait...", "what?!", "Really?"]),
+        ]);
+    }
+
+    #[test]
+    fn flushes_the_rest_when_finished() {
+        check(&[
+            ("No punctuation at the end", &["No punctuation at the end"]),
+            ("Done. Almost done", &["Done.", "Almost done"]),
+            // the last dot is only known to end a sentence when the stream ends
+            ("The price is 3.", &["The price is 3."]),
+        ]);
+
+        let mut splitter = SentenceSplitter::default();
+        assert!(splitter.push("Scanning").is_empty());
+        assert_eq!(splitter.push(" now. More"), ["Scanning now."]);
+        assert_eq!(splitter.finish().as_deref(), Some("More"));
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn pipeline_is_done_when_the_worker_stopped() {
+        let (audio_sender, audio_receiver) = mpsc::channel();
+        let mut speech = SpeechPipeline {
+            sentence_sender: None,
+            audio_receiver,
+            generation: Arc::default(),
+            num_pending: 2,
+            worker: None,
+        };
+        audio_sender
+            .send((
+                0,
+                SynthesizedSentence {
+                    text: "Hello.".into(),
+                    audio: vec![0.0; 4],
+                    synthesis_time: Duration::ZERO,
+                },
+            ))
+            .unwrap();
+        // like a panic in the TTS model
+        drop(audio_sender);
+
+        assert_eq!(speech.receive_audio().unwrap().text, "Hello.");
+        assert!(!speech.is_done());
+        assert!(speech.receive_audio().is_none());
+        assert!(speech.is_done());
+
+        speech.num_pending = 1;
+        assert!(speech
+            .receive_audio_timeout(Duration::from_secs(5))
+            .is_none());
+        assert!(speech.is_done());
+        speech.num_pending = 1;
+        assert!(speech.try_receive_audio().is_none());
+        assert!(speech.is_done());
+    }
+}
@@ -1,4 +1,7 @@
-use std::error::Error;
+use crate::{
+    config::{model_path, MoonshineModel, SttBackend, SttConfig, VadConfig, WhisperModel},
+    error::ModelError,
+};
 
 use sherpa_rs::{
     moonshine::{MoonshineConfig, MoonshineRecognizer},
@@ -7,47 +10,101 @@ use sherpa_rs::{
 };
 
 pub struct Vad {
-    vad: SileroVad,
+    vad: VadModel,
     window_size: usize,
     sample_rate: u32,
 }
 
+enum VadModel {
+    Silero(SileroVad),
+    #[cfg(test)]
+    Energy(EnergyVad),
+}
+
 impl Vad {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new(config: &VadConfig) -> Result<Self, ModelError> {
+        ModelError::check_files("VAD", [config.model.as_path()])?;
         let sample_rate = 16000;
         let window_size = 512;
         let vad_config = SileroVadConfig {
-            model: "silero_vad.onnx".into(),
-            threshold: 0.25,
-            min_silence_duration: 0.5,
-            min_speech_duration: 0.25,
-            max_speech_duration: 5.0,
+            model: model_path(&config.model),
+            threshold: config.threshold,
+            min_silence_duration: config.min_silence_duration,
+            min_speech_duration: config.min_speech_duration,
+            max_speech_duration: config.max_speech_duration,
             sample_rate,
             window_size: window_size as i32,
             ..Default::default()
         };
-        let vad = SileroVad::new(vad_config, 10.0)?;
+        let vad =
+            SileroVad::new(vad_config, 10.0).map_err(ModelError::load("VAD", &config.model))?;
         Ok(Self {
-            vad,
+            vad: VadModel::Silero(vad),
             window_size,
             sample_rate,
         })
     }
 
+    /// Detects loud audio as speech, for tests without the silero model
+    #[cfg(test)]
+    pub fn energy() -> Self {
+        Self {
+            vad: VadModel::Energy(EnergyVad::default()),
+            window_size: 512,
+            sample_rate: 16000,
+        }
+    }
+
     pub fn process_audio(&mut self, audio: Vec<f32>) {
-        self.vad.accept_waveform(audio);
+        match &mut self.vad {
+            VadModel::Silero(vad) => vad.accept_waveform(audio),
+            #[cfg(test)]
+            VadModel::Energy(vad) => vad.accept_waveform(audio),
+        }
+    }
+
+    /// True while the user is speaking, before the segment is complete
+    pub fn is_speaking(&mut self) -> bool {
+        match &mut self.vad {
+            VadModel::Silero(vad) => vad.is_speech(),
+            #[cfg(test)]
+            VadModel::Energy(vad) => !vad.current.is_empty(),
+        }
     }
 
     pub fn speech_detected(&mut self) -> bool {
-        !self.vad.is_empty()
+        match &mut self.vad {
+            VadModel::Silero(vad) => !vad.is_empty(),
+            #[cfg(test)]
+            VadModel::Energy(vad) => !vad.segments.is_empty(),
+        }
     }
 
     pub fn speech_segment(&mut self) -> Vec<f32> {
-        self.vad.front().samples
+        match &mut self.vad {
+            VadModel::Silero(vad) => vad.front().samples,
+            #[cfg(test)]
+            VadModel::Energy(vad) => vad.segments.front().cloned().unwrap_or_default(),
+        }
     }
 
     pub fn delete_speech_segment(&mut self) {
-        self.vad.pop();
+        match &mut self.vad {
+            VadModel::Silero(vad) => vad.pop(),
+            #[cfg(test)]
+            VadModel::Energy(vad) => {
+                vad.segments.pop_front();
+            }
+        }
+    }
+
+    /// Ends the current speech segment, e.g. at the end of an audio file
+    pub fn flush(&mut self) {
+        match &mut self.vad {
+            VadModel::Silero(vad) => vad.flush(),
+            #[cfg(test)]
+            VadModel::Energy(vad) => vad.flush(),
+        }
     }
 
     pub fn sample_rate(&self) -> u32 {
@@ -59,43 +116,114 @@ impl Vad {
     }
 }
 
+/// Speech segments are windows louder than a threshold, ended by a few quiet windows
+#[cfg(test)]
+#[derive(Default)]
+struct EnergyVad {
+    current: Vec<f32>,
+    num_quiet_windows: usize,
+    segments: std::collections::VecDeque<Vec<f32>>,
+}
+
+#[cfg(test)]
+impl EnergyVad {
+    /// Mean square of a window with speech
+    const THRESHOLD: f32 = 1e-4;
+    /// Quiet windows that end a segment, 256 ms with 512 samples at 16 kHz
+    const MIN_QUIET_WINDOWS: usize = 8;
+
+    fn accept_waveform(&mut self, window: Vec<f32>) {
+        let power = window.iter().map(|x| x * x).sum::<f32>() / window.len().max(1) as f32;
+        if power > Self::THRESHOLD {
+            self.current.extend(window);
+            self.num_quiet_windows = 0;
+        } else if !self.current.is_empty() {
+            self.num_quiet_windows += 1;
+            if self.num_quiet_windows >= Self::MIN_QUIET_WINDOWS {
+                self.flush();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.current.is_empty() {
+            self.segments.push_back(std::mem::take(&mut self.current));
+        }
+        self.num_quiet_windows = 0;
+    }
+}
+
 #[allow(unused)]
 pub enum SpeechToText {
     Moonshine(MoonshineRecognizer),
     Whisper(WhisperRecognizer),
+    /// Returns the given transcripts in order, whatever was said, for tests without a model
+    #[cfg(test)]
+    Scripted(std::collections::VecDeque<String>),
 }
 
 #[allow(unused)]
 impl SpeechToText {
-    pub fn new_moonshine() -> Result<Self, Box<dyn Error>> {
+    pub fn new(config: &SttConfig) -> Result<Self, ModelError> {
+        match config.backend {
+            SttBackend::Moonshine => Self::new_moonshine(&config.moonshine),
+            SttBackend::Whisper => Self::new_whisper(&config.whisper),
+        }
+    }
+
+    pub fn new_moonshine(model: &MoonshineModel) -> Result<Self, ModelError> {
+        ModelError::check_files(
+            "moonshine",
+            [
+                model.preprocessor.as_path(),
+                &model.encoder,
+                &model.uncached_decoder,
+                &model.cached_decoder,
+                &model.tokens,
+            ],
+        )?;
         // Speech To Text
         let config = MoonshineConfig {
-            preprocessor: "./sherpa-onnx-moonshine-tiny-en-int8/preprocess.onnx".into(),
-            encoder: "./sherpa-onnx-moonshine-tiny-en-int8/encode.int8.onnx".into(),
-            uncached_decoder: "./sherpa-onnx-moonshine-tiny-en-int8/uncached_decode.int8.onnx"
-                .into(),
-            cached_decoder: "./sherpa-onnx-moonshine-tiny-en-int8/cached_decode.int8.onnx".into(),
-            tokens: "./sherpa-onnx-moonshine-tiny-en-int8/tokens.txt".into(),
+            preprocessor: model_path(&model.preprocessor),
+            encoder: model_path(&model.encoder),
+            uncached_decoder: model_path(&model.uncached_decoder),
+            cached_decoder: model_path(&model.cached_decoder),
+            tokens: model_path(&model.tokens),
             num_threads: None,
             ..Default::default()
         };
-        let stt = MoonshineRecognizer::new(config)?;
+        let stt = MoonshineRecognizer::new(config)
+            .map_err(ModelError::load("moonshine", &model.encoder))?;
         Ok(SpeechToText::Moonshine(stt))
     }
 
-    pub fn new_whisper(sample_rate: u32) -> Result<Self, Box<dyn Error>> {
+    pub fn new_whisper(model: &WhisperModel) -> Result<Self, ModelError> {
+        ModelError::check_files(
+            "whisper",
+            [model.encoder.as_path(), &model.decoder, &model.tokens],
+        )?;
         // Speech To Text
         let config = WhisperConfig {
-            decoder: "sherpa-onnx-whisper-tiny/tiny-decoder.onnx".into(),
-            encoder: "sherpa-onnx-whisper-tiny/tiny-encoder.onnx".into(),
-            tokens: "sherpa-onnx-whisper-tiny/tiny-tokens.txt".into(),
-            language: "en".into(),
+            decoder: model_path(&model.decoder),
+            encoder: model_path(&model.encoder),
+            tokens: model_path(&model.tokens),
+            language: model.language.clone(),
             ..Default::default()
         };
-        let stt = WhisperRecognizer::new(config)?;
+        let stt =
+            WhisperRecognizer::new(config).map_err(ModelError::load("whisper", &model.encoder))?;
         Ok(SpeechToText::Whisper(stt))
     }
 
+    pub fn model_name(&self) -> &'static str {
+        match self {
+            SpeechToText::Moonshine(_) => "moonshine",
+            SpeechToText::Whisper(_) => "whisper",
+            #[cfg(test)]
+            SpeechToText::Scripted(_) => "scripted",
+        }
+    }
+
     pub fn transcribe(&mut self, audio: &[f32]) -> String {
         match self {
             SpeechToText::Moonshine(moonshine_recognizer) => {
@@ -104,6 +232,8 @@ impl SpeechToText {
             SpeechToText::Whisper(whisper_recognizer) => {
                 whisper_recognizer.transcribe(16000, audio).text
             }
+            #[cfg(test)]
+            SpeechToText::Scripted(transcripts) => transcripts.pop_front().unwrap_or_default(),
         }
     }
 }
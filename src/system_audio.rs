@@ -1,14 +1,24 @@
-use std::sync::{atomic::AtomicBool, Arc};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use ringbuf::traits::{Consumer as _, Observer};
 use ringbuf::traits::{Producer as _, Split};
 use ringbuf::{storage::Heap, wrap::caching::Caching, HeapRb, SharedRb};
 use rtaudio::{
-    Api, Buffers, DeviceParams, RtAudioError, SampleFormat, StreamHandle, StreamInfo,
+    Api, Buffers, DeviceInfo, DeviceParams, RtAudioError, SampleFormat, StreamHandle, StreamInfo,
     StreamOptions, StreamStatus,
 };
 use rubato::{FftFixedIn, FftFixedOut, ResampleError, Resampler, ResamplerConstructionError};
 
+use crate::{
+    audio_backend::AudioBackend, echo_canceller::EchoCanceller, sample_notifier::SampleNotifier,
+};
+
 type Producer = Caching<Arc<SharedRb<Heap<f32>>>, true, false>;
 type Consumer = Caching<Arc<SharedRb<Heap<f32>>>, false, true>;
 
@@ -16,10 +26,12 @@ type Consumer = Caching<Arc<SharedRb<Heap<f32>>>, false, true>;
 pub enum SystemAudioError {
     #[error("RtAudio error: {0}")]
     RtAudio(#[from] RtAudioError),
-    #[error("Output Device not found")]
-    OutputDeviceNotFound,
-    #[error("Output Device not found")]
-    InputDeviceNotFound,
+    #[error("Output device `{0}` not found")]
+    OutputDeviceNotFound(String),
+    #[error("Input device `{0}` not found")]
+    InputDeviceNotFound(String),
+    #[error("Audio stream stopped: {0}")]
+    Stream(String),
     #[error("Resample construction error: {0}")]
     ResamplerConstruction(#[from] ResamplerConstructionError),
     #[error("Resample error: {0}")]
@@ -33,6 +45,12 @@ pub struct AudioConfig {
     pub num_frames: usize,
     pub vad_sample_rate: u32,
     pub tts_sample_rate: u32,
+    /// Keep recording while KITT is speaking, so the user can interrupt it
+    pub barge_in: bool,
+    /// Number of taps of the echo canceller at the VAD sample rate, `None` disables it
+    pub echo_filter_length: Option<usize>,
+    /// Microphone to speaker power ratio above which the echo canceller assumes double talk
+    pub echo_double_talk_ratio: f32,
 }
 
 pub struct SystemAudio {
@@ -40,6 +58,16 @@ pub struct SystemAudio {
     input_consumer: Consumer,
     output_producer: Producer,
     ready_to_receive: Arc<AtomicBool>,
+    flush_output: Arc<AtomicBool>,
+    // the bits of an f32, there is no atomic float
+    output_gain: Arc<AtomicU32>,
+    // set by RtAudio when the stream stops, e.g. because the sound card was unplugged
+    stream_error: Arc<Mutex<Option<String>>>,
+    // wakes up the voice loop when new input arrived
+    input_notifier: Arc<SampleNotifier>,
+    // KITTs voice as it was played, aligned with the input
+    echo_reference_consumer: Consumer,
+    echo_canceller: Option<EchoCanceller>,
 }
 
 impl SystemAudio {
@@ -52,7 +80,8 @@ impl SystemAudio {
             let output_device = host
                 .iter_output_devices()
                 .find(|d| d.name == device_name.as_str());
-            output_device.ok_or(SystemAudioError::OutputDeviceNotFound)?
+            output_device
+                .ok_or_else(|| SystemAudioError::OutputDeviceNotFound(device_name.clone()))?
         } else {
             host.default_output_device()?
         };
@@ -62,7 +91,8 @@ impl SystemAudio {
             let input_device = host
                 .iter_input_devices()
                 .find(|d| d.name == device_name.as_str());
-            input_device.ok_or(SystemAudioError::InputDeviceNotFound)?
+            input_device
+                .ok_or_else(|| SystemAudioError::InputDeviceNotFound(device_name.clone()))?
         } else {
             host.default_input_device()?
         };
@@ -77,6 +107,21 @@ impl SystemAudio {
         )?;
         let mut resampled_input = input_resampler.output_buffer_allocate(true);
 
+        // Removes KITTs voice from the input, so it can keep listening while speaking.
+        // It is too expensive for the audio callback, so the played output is resampled
+        // like the input and the echo is cancelled when the input is received.
+        let echo_canceller = config.echo_filter_length.map(|filter_length| {
+            EchoCanceller::new(filter_length).with_double_talk_ratio(config.echo_double_talk_ratio)
+        });
+        let mut echo_reference_resampler = FftFixedIn::new(
+            config.system_sample_rate as usize,
+            config.vad_sample_rate as usize,
+            config.num_frames,
+            1,
+            1,
+        )?;
+        let mut resampled_echo_reference = echo_reference_resampler.output_buffer_allocate(true);
+
         // Construct the resampler that resamples the generated speech to the system sample rate
         let mut output_resampler = FftFixedOut::new(
             config.tts_sample_rate as usize,
@@ -91,10 +136,18 @@ impl SystemAudio {
         let rb = HeapRb::<f32>::new(600 * config.num_frames);
         let (mut input_producer, input_consumer) = rb.split();
 
+        // The same size as the input, so both always have the same number of samples
+        let rb = HeapRb::<f32>::new(600 * config.num_frames);
+        let (mut echo_reference_producer, echo_reference_consumer) = rb.split();
+        let cancel_echo = echo_canceller.is_some();
+
         // The ringbuffer that sends the audio from the background ai process back to the system
         let rb = HeapRb::<f32>::new(600 * config.num_frames);
         let (output_producer, mut output_consumer) = rb.split();
 
+        let stream_error = Arc::new(Mutex::new(None));
+        let stream_error_clone = stream_error.clone();
+
         let mut stream_handle = host
             .open_stream(
                 Some(DeviceParams {
@@ -111,7 +164,10 @@ impl SystemAudio {
                 config.system_sample_rate,
                 config.num_frames as u32,
                 StreamOptions::default(),
-                |error| eprintln!("Error in RtAudio Stream: {}", error),
+                move |error| {
+                    eprintln!("Error in RtAudio Stream: {error}");
+                    *stream_error_clone.lock().unwrap() = Some(error.to_string());
+                },
             )
             .map_err(|e| e.1)?;
 
@@ -120,15 +176,33 @@ impl SystemAudio {
 
         let ready_to_receive_clone = ready_to_receive.clone();
 
+        // A variable so the ai process can stop KITTs voice immediately
+        let flush_output = Arc::new(AtomicBool::new(false));
+
+        let flush_output_clone = flush_output.clone();
+
+        // A variable so the ai process can change the volume of KITTs voice
+        let output_gain = Arc::new(AtomicU32::new(1.0f32.to_bits()));
+
+        let output_gain_clone = output_gain.clone();
+        let barge_in = config.barge_in;
+
+        // So the ai process can sleep until there is enough input
+        let input_notifier = Arc::new(SampleNotifier::default());
+
+        let input_notifier_clone = input_notifier.clone();
+
         stream_handle.start(
             move |buffers: Buffers<'_>, _info: &StreamInfo, _status: StreamStatus| {
                 if let Buffers::Float32 { output, input } = buffers {
+                    if flush_output_clone.swap(false, Ordering::Relaxed) {
+                        output_consumer.clear();
+                    }
+
                     let available_samples = output_consumer.occupied_len();
 
-                    let ready_to_receive =
-                        ready_to_receive_clone.load(std::sync::atomic::Ordering::Relaxed);
+                    let ready_to_receive = ready_to_receive_clone.load(Ordering::Relaxed);
 
-                    // either KITT or you are speaking
                     if available_samples > 0 {
                         // receive KITTs voice
                         let resampler_input_frames_next =
@@ -140,13 +214,21 @@ impl SystemAudio {
                             output_speech[0][num_popped..].fill(0.0);
                         }
                         if output_resampler
-                            .process_into_buffer(&output_speech, &mut [output], None)
+                            .process_into_buffer(&output_speech, &mut [&mut *output], None)
                             .is_err()
                         {
                             eprintln!("Output resampling did not suceed, output nothing.")
                         }
-                    } else if ready_to_receive {
+                        let gain = f32::from_bits(output_gain_clone.load(Ordering::Relaxed));
+                        for sample in output.iter_mut() {
+                            *sample *= gain;
+                        }
+                    } else {
                         output.fill(0.0);
+                    }
+
+                    // without barge-in either KITT or you are speaking
+                    if barge_in || (available_samples == 0 && ready_to_receive) {
                         // send your voice
                         let result = input_resampler.process_into_buffer(
                             &[&input],
@@ -154,7 +236,22 @@ impl SystemAudio {
                             None,
                         );
                         if let Ok((_, num_samples_generated)) = result {
+                            if cancel_echo {
+                                // what was played while this input was recorded
+                                let result = echo_reference_resampler.process_into_buffer(
+                                    &[&*output],
+                                    &mut resampled_echo_reference,
+                                    None,
+                                );
+                                if result.is_err() {
+                                    resampled_echo_reference[0].fill(0.0);
+                                }
+                                echo_reference_producer.push_slice(
+                                    &resampled_echo_reference[0][..num_samples_generated],
+                                );
+                            }
                             input_producer.push_slice(&resampled_input[0][..num_samples_generated]);
+                            input_notifier_clone.notify(input_producer.occupied_len());
                         } else {
                             eprintln!("Input resampling did not suceed, send nothing to KITT.")
                         }
@@ -168,24 +265,65 @@ impl SystemAudio {
             input_consumer,
             output_producer,
             ready_to_receive,
+            flush_output,
+            output_gain,
+            stream_error,
+            input_notifier,
+            echo_reference_consumer,
+            echo_canceller,
         })
     }
+}
 
-    pub fn num_samples_available(&self) -> usize {
+impl AudioBackend for SystemAudio {
+    fn num_samples_available(&self) -> usize {
         self.input_consumer.occupied_len()
     }
 
-    pub fn receive_audio(&mut self, num_samples: usize) -> Vec<f32> {
-        self.input_consumer.pop_iter().take(num_samples).collect()
+    /// Sleeps until the audio callback recorded enough, instead of keeping a core busy
+    fn wait_for_samples(&self, num_samples: usize, timeout: Duration) -> bool {
+        self.input_notifier
+            .wait(num_samples, timeout, || self.input_consumer.occupied_len())
+    }
+
+    fn receive_audio(&mut self, num_samples: usize) -> Vec<f32> {
+        let mut input: Vec<f32> = self.input_consumer.pop_iter().take(num_samples).collect();
+        if let Some(echo_canceller) = &mut self.echo_canceller {
+            let echo_reference: Vec<f32> = self
+                .echo_reference_consumer
+                .pop_iter()
+                .take(input.len())
+                .collect();
+            echo_canceller.process(&echo_reference, &mut input[..echo_reference.len()]);
+        }
+        input
     }
 
-    pub fn send_audio(&mut self, data: &[f32]) {
+    fn send_audio(&mut self, data: &[f32]) {
         self.output_producer.push_slice(data);
     }
 
-    pub fn set_ready_to_receive(&self, ready: bool) {
-        self.ready_to_receive
-            .store(ready, std::sync::atomic::Ordering::Relaxed);
+    fn set_ready_to_receive(&self, ready: bool) {
+        self.ready_to_receive.store(ready, Ordering::Relaxed);
+    }
+
+    /// Number of samples of KITTs voice that were not played yet
+    fn num_samples_queued(&self) -> usize {
+        self.output_producer.occupied_len()
+    }
+
+    /// Stops KITTs voice by dropping everything that was not played yet
+    fn flush_output(&self) {
+        self.flush_output.store(true, Ordering::Relaxed);
+    }
+
+    fn set_output_gain(&self, gain: f32) {
+        self.output_gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    fn take_error(&self) -> Option<SystemAudioError> {
+        let error = self.stream_error.lock().unwrap().take();
+        error.map(SystemAudioError::Stream)
     }
 }
 
@@ -195,23 +333,37 @@ impl Drop for SystemAudio {
     }
 }
 
-#[allow(unused)]
-pub fn list_device_names() {
-    match rtaudio::Host::new(Api::Unspecified) {
-        Ok(rt) => {
-            println!("Available Output Devices:");
-            for device_info in rt.iter_output_devices() {
-                println!("\"{}\"", device_info.name);
-            }
-            println!();
-            println!("Available Input Devices:");
-            for device_info in rt.iter_input_devices() {
-                println!("\"{}\"", device_info.name);
-            }
-        }
-        Err(e) => {
-            eprintln!("{}", e);
-        }
+/// Prints all devices with their ids, channel counts and supported sample rates
+pub fn list_devices() -> Result<(), SystemAudioError> {
+    let host = rtaudio::Host::new(Api::Unspecified)?;
+
+    println!("Available Output Devices:");
+    for device in host.iter_output_devices() {
+        print_device(&device, device.output_channels, device.is_default_output);
+    }
+    println!();
+    println!("Available Input Devices:");
+    for device in host.iter_input_devices() {
+        print_device(&device, device.input_channels, device.is_default_input);
     }
     println!();
+    Ok(())
+}
+
+fn print_device(device: &DeviceInfo, num_channels: impl std::fmt::Display, is_default: bool) {
+    let sample_rates = device
+        .sample_rates
+        .iter()
+        .map(|sample_rate| sample_rate.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!(
+        "{:?} \"{}\"{}\n    channels: {}, sample rates: {} (preferred {})",
+        device.id,
+        device.name,
+        if is_default { " (default)" } else { "" },
+        num_channels,
+        sample_rates,
+        device.preferred_sample_rate
+    );
 }
@@ -0,0 +1,104 @@
+//! Tools KITT can call to answer questions about itself
+
+use crate::{
+    llama::{ToolDefinition, ToolRegistry},
+    timestamp::{format_timestamp, unix_millis, weekday},
+};
+
+const UPTIME_PATH: &str = "/proc/uptime";
+const TEMPERATURE_PATH: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// Current time, uptime and CPU temperature
+pub fn registry() -> ToolRegistry {
+    ToolRegistry::default()
+        .with_tool(
+            ToolDefinition::new("current_time", "Get the current date and time in UTC"),
+            |_| Ok(current_time()),
+        )
+        .with_tool(
+            ToolDefinition::new(
+                "system_uptime",
+                "Get how long KITT has been running since the last start",
+            ),
+            |_| read(UPTIME_PATH).and_then(|uptime| format_uptime(&uptime)),
+        )
+        .with_tool(
+            ToolDefinition::new("cpu_temperature", "Get the temperature of KITT's processor"),
+            |_| read(TEMPERATURE_PATH).and_then(|temperature| format_temperature(&temperature)),
+        )
+}
+
+fn current_time() -> String {
+    let now = unix_millis();
+    format!("{}, {} UTC", weekday(now), format_timestamp(now))
+}
+
+fn read(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("could not read {path}: {e}"))
+}
+
+/// `/proc/uptime` has the seconds since boot, followed by the idle seconds
+fn format_uptime(uptime: &str) -> Result<String, String> {
+    let seconds: f64 = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|seconds| seconds.parse().ok())
+        .ok_or("unknown uptime format")?;
+
+    let minutes = (seconds / 60.0) as u64;
+    let parts: Vec<String> = [
+        (minutes / 1440, "day"),
+        (minutes / 60 % 24, "hour"),
+        (minutes % 60, "minute"),
+    ]
+    .into_iter()
+    .filter(|&(value, _)| value > 0)
+    .map(|(value, unit)| match value {
+        1 => format!("1 {unit}"),
+        _ => format!("{value} {unit}s"),
+    })
+    .collect();
+
+    if parts.is_empty() {
+        Ok("less than a minute".to_string())
+    } else {
+        Ok(parts.join(", "))
+    }
+}
+
+/// The thermal zone has the temperature in millidegrees Celsius
+fn format_temperature(temperature: &str) -> Result<String, String> {
+    let millidegrees: i64 = temperature
+        .trim()
+        .parse()
+        .map_err(|_| "unknown temperature format")?;
+    Ok(format!(
+        "{:.1} degrees Celsius",
+        millidegrees as f64 / 1000.0
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_uptime() {
+        assert_eq!(format_uptime("35.2 120.9\n").unwrap(), "less than a minute");
+        assert_eq!(
+            format_uptime("3720.00 7000.00").unwrap(),
+            "1 hour, 2 minutes"
+        );
+        assert_eq!(format_uptime("180000 0").unwrap(), "2 days, 2 hours");
+        assert!(format_uptime("").is_err());
+    }
+
+    #[test]
+    fn formats_temperature() {
+        assert_eq!(
+            format_temperature("48312\n").unwrap(),
+            "48.3 degrees Celsius"
+        );
+        assert!(format_temperature("hot").is_err());
+    }
+}
@@ -0,0 +1,911 @@
+//! Turns LLM answers into text the TTS models can pronounce.
+//!
+//! Markdown and emojis are removed, numbers, dates, times, currencies, units and
+//! abbreviations are written out as words and URLs are shortened to their domain.
+
+const ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+const TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+const SCALES: [(u64, &str); 4] = [
+    (1_000_000_000_000, "trillion"),
+    (1_000_000_000, "billion"),
+    (1_000_000, "million"),
+    (1_000, "thousand"),
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Abbreviations without their last dot, and what to say instead
+const ABBREVIATIONS: [(&str, &str); 11] = [
+    ("mr", "Mister"),
+    ("mrs", "Missus"),
+    ("ms", "Miss"),
+    ("dr", "Doctor"),
+    ("jr", "Junior"),
+    ("sr", "Senior"),
+    ("e.g", "for example"),
+    ("i.e", "that is"),
+    ("etc", "et cetera"),
+    ("vs", "versus"),
+    ("approx", "approximately"),
+];
+
+/// Units after a number, with their singular and plural.
+///
+/// They are matched case-sensitively, so "5G" is not five grams.
+const UNITS: [(&str, &str, &str); 35] = [
+    ("km/h", "kilometer per hour", "kilometers per hour"),
+    ("kph", "kilometer per hour", "kilometers per hour"),
+    ("mph", "mile per hour", "miles per hour"),
+    ("km", "kilometer", "kilometers"),
+    ("m", "meter", "meters"),
+    ("cm", "centimeter", "centimeters"),
+    ("mm", "millimeter", "millimeters"),
+    ("mi", "mile", "miles"),
+    ("ft", "foot", "feet"),
+    ("kg", "kilogram", "kilograms"),
+    ("g", "gram", "grams"),
+    ("lb", "pound", "pounds"),
+    ("lbs", "pound", "pounds"),
+    ("l", "liter", "liters"),
+    ("L", "liter", "liters"),
+    ("ml", "milliliter", "milliliters"),
+    ("h", "hour", "hours"),
+    ("hr", "hour", "hours"),
+    ("hrs", "hour", "hours"),
+    ("min", "minute", "minutes"),
+    ("s", "second", "seconds"),
+    ("sec", "second", "seconds"),
+    ("ms", "millisecond", "milliseconds"),
+    ("W", "watt", "watts"),
+    ("kW", "kilowatt", "kilowatts"),
+    ("kWh", "kilowatt hour", "kilowatt hours"),
+    ("hp", "horsepower", "horsepower"),
+    ("V", "volt", "volts"),
+    ("Hz", "hertz", "hertz"),
+    ("MHz", "megahertz", "megahertz"),
+    ("GHz", "gigahertz", "gigahertz"),
+    ("KB", "kilobyte", "kilobytes"),
+    ("MB", "megabyte", "megabytes"),
+    ("GB", "gigabyte", "gigabytes"),
+    ("TB", "terabyte", "terabytes"),
+];
+
+/// Top level domains that make a word a web address, e.g. "example.com"
+const DOMAINS: [&str; 10] = [
+    "com", "org", "net", "io", "ai", "dev", "edu", "gov", "de", "uk",
+];
+
+const LEADING_PUNCTUATION: &[char] = &['(', '"', '\'', '“', '‘', '['];
+const TRAILING_PUNCTUATION: &[char] =
+    &['.', ',', '!', '?', ';', ':', ')', '"', '\'', '”', '’', ']'];
+
+/// Keeps track of markdown that spans several sentences, like code blocks.
+///
+/// Use one normalizer per answer and feed it the sentences in order.
+#[derive(Debug, Default)]
+pub struct TextNormalizer {
+    in_code_block: bool,
+}
+
+impl TextNormalizer {
+    /// Normalizes the next piece of an answer, code blocks are not spoken at all
+    pub fn normalize(&mut self, text: &str) -> String {
+        let mut words = Vec::new();
+        for line in text.lines() {
+            if line.trim_start().starts_with("```") {
+                self.in_code_block = !self.in_code_block;
+                continue;
+            }
+            if self.in_code_block {
+                continue;
+            }
+            let line = strip_markdown(line);
+            let line: String = line.chars().filter(|&c| !is_emoji(c)).collect();
+            words.extend(expand(&line));
+        }
+        join_words(&words)
+    }
+}
+
+/// Normalizes a complete text
+pub fn normalize(text: &str) -> String {
+    TextNormalizer::default().normalize(text)
+}
+
+/// Removes headers, quotes, list markers, emphasis and links of a markdown line
+fn strip_markdown(line: &str) -> String {
+    let mut line = line.trim();
+
+    // horizontal rules
+    if line.len() >= 3 && line.chars().all(|c| matches!(c, '-' | '*' | '_' | ' ')) {
+        return String::new();
+    }
+
+    while let Some(rest) = line.strip_prefix('>') {
+        line = rest.trim_start();
+    }
+    let num_hashes = line.len() - line.trim_start_matches('#').len();
+    if (1..=6).contains(&num_hashes) && line[num_hashes..].starts_with(' ') {
+        line = line[num_hashes..].trim_start();
+    }
+    if let Some(rest) = ["- ", "* ", "+ ", "• "]
+        .iter()
+        .find_map(|bullet| line.strip_prefix(bullet))
+    {
+        line = rest.trim_start();
+    }
+    let num_digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if num_digits > 0
+        && (line[num_digits..].starts_with(". ") || line[num_digits..].starts_with(") "))
+    {
+        line = line[num_digits + 2..].trim_start();
+    }
+
+    replace_links(line)
+        .chars()
+        .filter(|c| !matches!(c, '*' | '`' | '~'))
+        .map(|c| if matches!(c, '_' | '|') { ' ' } else { c })
+        .collect()
+}
+
+/// `[text](url)` and `![text](url)` become `text`
+fn replace_links(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        let Some(middle) = rest[start..].find("](").map(|i| start + i) else {
+            break;
+        };
+        let Some(end) = rest[middle..].find(')').map(|i| middle + i) else {
+            break;
+        };
+        let before = &rest[..start];
+        result.push_str(before.strip_suffix('!').unwrap_or(before));
+        result.push_str(&rest[start + 1..middle]);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1F000..=0x1FAFF
+            | 0x2600..=0x27BF
+            | 0x2B00..=0x2BFF
+            | 0xFE00..=0xFE0F
+            | 0x200D
+            | 0x20E3
+            | 0xE0020..=0xE007F
+    )
+}
+
+fn join_words(words: &[String]) -> String {
+    let mut text = String::new();
+    for word in words.iter().filter(|word| !word.is_empty()) {
+        // punctuation of a word that was removed, like an emoji
+        let is_punctuation = word.starts_with(['.', ',', '!', '?', ';', ':']);
+        if !text.is_empty() && !is_punctuation {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    text
+}
+
+/// Expands every word of a line, some expansions use the next word as well
+fn expand(line: &str) -> Vec<String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let (num_tokens, word) = expand_token(&tokens, i);
+        words.push(word);
+        i += num_tokens;
+    }
+    words
+}
+
+/// Splits `("$5.00").` into `("`, `$5.00`, `").`
+fn split_punctuation(token: &str) -> (&str, &str, &str) {
+    let rest = token.trim_start_matches(LEADING_PUNCTUATION);
+    let leading = &token[..token.len() - rest.len()];
+    let core = rest.trim_end_matches(TRAILING_PUNCTUATION);
+    (leading, core, &rest[core.len()..])
+}
+
+/// Returns the number of tokens that were used and their expansion
+fn expand_token(tokens: &[&str], i: usize) -> (usize, String) {
+    let (leading, core, mut trailing) = split_punctuation(tokens[i]);
+    let next = tokens.get(i + 1).map(|token| split_punctuation(token));
+    let previous = i.checked_sub(1).map(|i| split_punctuation(tokens[i]).1);
+    let is_last = i + 1 == tokens.len();
+
+    let mut num_tokens = 1;
+    let expanded = if let Some(words) = abbreviation(core, trailing, next.map(|(_, core, _)| core))
+    {
+        // the dot belongs to the abbreviation, unless it also ends the sentence
+        if !is_last {
+            trailing = &trailing[1..];
+        }
+        words.to_string()
+    } else if let Some((words, uses_next)) = expand_core(core, previous, next) {
+        if uses_next {
+            num_tokens = 2;
+            trailing = next.map(|(_, _, trailing)| trailing).unwrap_or_default();
+        }
+        words
+    } else {
+        core.to_string()
+    };
+
+    // "p.m." at the end of a sentence already has its dot
+    if expanded.ends_with('.') {
+        trailing = trailing.strip_prefix('.').unwrap_or(trailing);
+    }
+    (num_tokens, format!("{leading}{expanded}{trailing}"))
+}
+
+fn abbreviation(core: &str, trailing: &str, next: Option<&str>) -> Option<&'static str> {
+    if !trailing.starts_with('.') {
+        return None;
+    }
+    let next_starts_with = |f: fn(char) -> bool| next.is_some_and(|next| next.starts_with(f));
+    match core.to_lowercase().as_str() {
+        "no" if next_starts_with(|c| c.is_ascii_digit()) => Some("number"),
+        "st" if next_starts_with(char::is_uppercase) => Some("Saint"),
+        "st" => Some("Street"),
+        lowercase => ABBREVIATIONS
+            .iter()
+            .find(|(abbreviation, _)| *abbreviation == lowercase)
+            .map(|(_, words)| *words),
+    }
+}
+
+/// Expands a word without its punctuation, `true` if the next word was used too
+fn expand_core(
+    core: &str,
+    previous: Option<&str>,
+    next: Option<(&str, &str, &str)>,
+) -> Option<(String, bool)> {
+    // only a next word that directly follows can be part of the expansion
+    let next = next
+        .filter(|(leading, _, _)| leading.is_empty())
+        .map(|(_, core, _)| core);
+    let next_lowercase = next.map(|next| next.to_lowercase());
+    let next_lowercase = next_lowercase.as_deref();
+
+    if core.starts_with("http://") || core.starts_with("https://") || core.starts_with("www.") {
+        return Some((speak_url(core), false));
+    }
+    if let Some((user, domain)) = core.split_once('@') {
+        if !user.is_empty() && domain.contains('.') {
+            return Some((format!("{user} at {}", domain.replace('.', " dot ")), false));
+        }
+    }
+    if is_domain(core) {
+        return Some((speak_url(core), false));
+    }
+    match core {
+        "&" => return Some(("and".into(), false)),
+        "+" => return Some(("plus".into(), false)),
+        "=" => return Some(("equals".into(), false)),
+        _ => {}
+    }
+    if let Some(number) = core.strip_prefix('#') {
+        let words = number_words(number).map(|words| format!("number {words}"));
+        return Some((words.unwrap_or_else(|| number.to_string()), false));
+    }
+
+    if let Some(words) = currency(core, next_lowercase) {
+        return Some(words);
+    }
+    if let Some(words) = time(core, next_lowercase) {
+        return Some(words);
+    }
+    if let Some(words) = iso_date(core) {
+        return Some((words, false));
+    }
+
+    let (number, rest) = split_number(core)?;
+    if matches!(rest, "s" | "'s") {
+        if let Some(words) = decade_words(number) {
+            return Some((words, false));
+        }
+    }
+    let is_one = number == "1";
+    let words = match rest {
+        "" => {
+            if let Some(words) = next.and_then(|unit| unit_words(unit, is_one)) {
+                return Some((format!("{} {words}", number_words(number)?), true));
+            }
+            if let Some(words) = next.and_then(|next| degrees(next, is_one)) {
+                return Some((format!("{} {words}", number_words(number)?), true));
+            }
+            if previous.is_some_and(is_month) {
+                day_words(number)?
+            } else {
+                plain_number_words(number)?
+            }
+        }
+        "%" => format!("{} percent", number_words(number)?),
+        "st" | "nd" | "rd" | "th" => ordinal(&number_words(number)?),
+        rest => {
+            if let Some(words) = unit_words(rest, is_one)
+                .map(str::to_string)
+                .or_else(|| degrees(rest, is_one))
+            {
+                format!("{} {words}", number_words(number)?)
+            } else if let Some(rest) = rest.strip_prefix('-') {
+                // ranges like "10-20" or compounds like "24-hour"
+                match plain_number_words(rest) {
+                    Some(end) => format!("{} to {end}", plain_number_words(number)?),
+                    None => format!("{} {rest}", number_words(number)?),
+                }
+            } else {
+                return None;
+            }
+        }
+    };
+    Some((words, false))
+}
+
+/// "https://www.example.com/path" is spoken as "example dot com"
+fn speak_url(url: &str) -> String {
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+    let url = url.strip_prefix("www.").unwrap_or(url);
+    let domain = url.split(['/', '?', '#']).next().unwrap_or_default();
+    domain.split('.').collect::<Vec<_>>().join(" dot ")
+}
+
+fn is_domain(word: &str) -> bool {
+    let Some((name, domain)) = word.rsplit_once('.') else {
+        return false;
+    };
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        && DOMAINS.contains(&domain.to_lowercase().as_str())
+}
+
+fn is_month(word: &str) -> bool {
+    let word = word.trim_end_matches('.');
+    word.len() >= 3
+        && MONTHS.iter().any(|month| {
+            month.len() >= word.len() && month[..word.len()].eq_ignore_ascii_case(word)
+        })
+}
+
+/// Splits "5km" into "5" and "km", the number may have a sign, thousands separators and decimals
+fn split_number(word: &str) -> Option<(&str, &str)> {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut end = word.len() - digits.len();
+    let bytes = word.as_bytes();
+    while end < word.len() {
+        let c = bytes[end];
+        let is_separator = matches!(c, b',' | b'.')
+            && bytes.get(end + 1).is_some_and(|next| next.is_ascii_digit());
+        if !(c.is_ascii_digit() || is_separator) {
+            break;
+        }
+        end += 1;
+    }
+    Some(word.split_at(end))
+}
+
+fn parse_integer(text: &str) -> Option<u64> {
+    let mut groups = text.split(',');
+    let first = groups.next()?;
+    // thousands separators are only valid every three digits
+    let valid = !first.is_empty()
+        && (first.len() <= 3 || !text.contains(','))
+        && groups.all(|group| group.len() == 3);
+    if !valid || !text.chars().all(|c| c.is_ascii_digit() || c == ',') {
+        return None;
+    }
+    text.replace(',', "").parse().ok()
+}
+
+/// "-1,234.5" is "minus one thousand two hundred thirty-four point five"
+fn number_words(text: &str) -> Option<String> {
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(text) => ("minus ", text),
+        None => ("", text),
+    };
+    let (integer, fraction) = match text.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (text, None),
+    };
+    let mut words = format!("{sign}{}", cardinal(parse_integer(integer)?));
+    if let Some(fraction) = fraction {
+        if fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        words.push_str(" point");
+        for digit in fraction.bytes() {
+            words.push(' ');
+            words.push_str(ONES[(digit - b'0') as usize]);
+        }
+    }
+    Some(words)
+}
+
+/// Like [`number_words`], but four digit numbers are read like years, "1982" is "nineteen eighty-two"
+fn plain_number_words(text: &str) -> Option<String> {
+    match text.parse::<u64>() {
+        Ok(year) if text.len() == 4 && (1100..2000).contains(&year) => Some(year_words(year)),
+        Ok(year) if text.len() == 4 && (2010..2100).contains(&year) => Some(year_words(year)),
+        _ => number_words(text),
+    }
+}
+
+/// "1980s" is "nineteen eighties" and "80s" is "eighties", other numbers are no decades
+fn decade_words(number: &str) -> Option<String> {
+    let is_decade = matches!(number.len(), 2 | 4)
+        && number.ends_with('0')
+        && !number.starts_with('0')
+        && number.bytes().all(|digit| digit.is_ascii_digit());
+    if !is_decade {
+        return None;
+    }
+    let words = plain_number_words(number)?;
+    Some(match words.strip_suffix('y') {
+        Some(stem) => format!("{stem}ies"),
+        None => format!("{words}s"),
+    })
+}
+
+fn cardinal(n: u64) -> String {
+    if n < 20 {
+        return ONES[n as usize].to_string();
+    }
+    if n < 100 {
+        let tens = TENS[(n / 10) as usize];
+        return match n % 10 {
+            0 => tens.to_string(),
+            ones => format!("{tens}-{}", ONES[ones as usize]),
+        };
+    }
+    if n < 1000 {
+        let hundreds = format!("{} hundred", ONES[(n / 100) as usize]);
+        return match n % 100 {
+            0 => hundreds,
+            rest => format!("{hundreds} {}", cardinal(rest)),
+        };
+    }
+    let (scale, name) = SCALES
+        .iter()
+        .find(|(scale, _)| n >= *scale)
+        .copied()
+        .unwrap_or(SCALES[SCALES.len() - 1]);
+    let words = format!("{} {name}", cardinal(n / scale));
+    match n % scale {
+        0 => words,
+        rest => format!("{words} {}", cardinal(rest)),
+    }
+}
+
+fn year_words(year: u64) -> String {
+    let (century, rest) = (year / 100, year % 100);
+    match rest {
+        0 => format!("{} hundred", cardinal(century)),
+        1..=9 => format!("{} oh {}", cardinal(century), cardinal(rest)),
+        _ => format!("{} {}", cardinal(century), cardinal(rest)),
+    }
+}
+
+/// "twenty-one" becomes "twenty-first"
+fn ordinal(words: &str) -> String {
+    let split = words.rfind([' ', '-']).map_or(0, |i| i + 1);
+    let (start, last) = words.split_at(split);
+    let last = match last {
+        "one" => "first".to_string(),
+        "two" => "second".to_string(),
+        "three" => "third".to_string(),
+        "five" => "fifth".to_string(),
+        "eight" => "eighth".to_string(),
+        "nine" => "ninth".to_string(),
+        "twelve" => "twelfth".to_string(),
+        last if last.ends_with('y') => format!("{}ieth", &last[..last.len() - 1]),
+        last => format!("{last}th"),
+    };
+    format!("{start}{last}")
+}
+
+/// A day of the month, "17" after "October" is "seventeenth"
+fn day_words(text: &str) -> Option<String> {
+    match text.parse::<u64>() {
+        Ok(day @ 1..=31) => Some(ordinal(&cardinal(day))),
+        _ => plain_number_words(text),
+    }
+}
+
+fn unit_words(unit: &str, is_one: bool) -> Option<&'static str> {
+    UNITS
+        .iter()
+        .find(|(name, _, _)| *name == unit)
+        .map(|(_, singular, plural)| if is_one { *singular } else { *plural })
+}
+
+fn degrees(unit: &str, is_one: bool) -> Option<String> {
+    let degrees = if is_one { "degree" } else { "degrees" };
+    match unit {
+        "°" => Some(degrees.to_string()),
+        "°C" => Some(format!("{degrees} Celsius")),
+        "°F" => Some(format!("{degrees} Fahrenheit")),
+        _ => None,
+    }
+}
+
+/// "$3.50" is "three dollars and fifty cents", "€2 million" is "two million euros"
+fn currency(core: &str, next: Option<&str>) -> Option<(String, bool)> {
+    let mut chars = core.chars();
+    let (symbol, amount) = match (chars.next()?, chars.next_back()?) {
+        (symbol @ ('$' | '€' | '£' | '¥'), _) => (symbol, &core[symbol.len_utf8()..]),
+        (_, symbol @ ('$' | '€' | '£' | '¥')) => {
+            (symbol, &core[..core.len() - symbol.len_utf8()])
+        }
+        _ => return None,
+    };
+    let (unit, units, cent, cents) = match symbol {
+        '$' => ("dollar", "dollars", "cent", "cents"),
+        '€' => ("euro", "euros", "cent", "cents"),
+        '£' => ("pound", "pounds", "penny", "pence"),
+        _ => ("yen", "yen", "", ""),
+    };
+
+    if let Some(scale) = next.filter(|next| matches!(*next, "thousand" | "million" | "billion")) {
+        return Some((format!("{} {scale} {units}", number_words(amount)?), true));
+    }
+
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let whole = parse_integer(whole)?;
+    let num_cents = match fraction.len() {
+        0 => 0,
+        1 | 2 if !cent.is_empty() => format!("{fraction:0<2}").parse::<u64>().ok()?,
+        _ => return Some((format!("{} {units}", number_words(amount)?), false)),
+    };
+
+    let mut parts = Vec::new();
+    if whole > 0 || num_cents == 0 {
+        parts.push(format!(
+            "{} {}",
+            cardinal(whole),
+            if whole == 1 { unit } else { units }
+        ));
+    }
+    if num_cents > 0 {
+        parts.push(format!(
+            "{} {}",
+            cardinal(num_cents),
+            if num_cents == 1 { cent } else { cents }
+        ));
+    }
+    Some((parts.join(" and "), false))
+}
+
+/// "14:30" is "fourteen thirty", "3pm" and "3:00 p.m." are "three p.m."
+fn time(core: &str, next: Option<&str>) -> Option<(String, bool)> {
+    let lowercase = core.to_lowercase();
+    let (clock, mut suffix) = match lowercase.find(|c: char| !(c.is_ascii_digit() || c == ':')) {
+        Some(i) => (&lowercase[..i], Some(&lowercase[i..])),
+        None => (lowercase.as_str(), None),
+    };
+    let mut uses_next = false;
+    if suffix.is_none() {
+        if let Some(next) = next.filter(|next| is_am_pm(next)) {
+            suffix = Some(next);
+            uses_next = true;
+        }
+    }
+    let suffix = match suffix {
+        Some(suffix) if is_am_pm(suffix) => Some(if suffix.starts_with('a') {
+            "a.m."
+        } else {
+            "p.m."
+        }),
+        Some(_) => return None,
+        None => None,
+    };
+
+    let (hour, minute) = match clock.split_once(':') {
+        Some((hour, minute)) if minute.len() == 2 => (hour, minute),
+        // a number without minutes is only a time with a.m. or p.m.
+        None if suffix.is_some() => (clock, "00"),
+        _ => return None,
+    };
+    if !(1..=2).contains(&hour.len()) {
+        return None;
+    }
+    let (hour, minute): (u64, u64) = (hour.parse().ok()?, minute.parse().ok()?);
+    if hour > 23 || minute > 59 || (suffix.is_some() && !(1..=12).contains(&hour)) {
+        return None;
+    }
+
+    let mut words = cardinal(hour);
+    match (minute, suffix) {
+        (0, Some(_)) => {}
+        (0, None) => words.push_str(" o'clock"),
+        (1..=9, _) => words.push_str(&format!(" oh {}", cardinal(minute))),
+        _ => words.push_str(&format!(" {}", cardinal(minute))),
+    }
+    if let Some(suffix) = suffix {
+        words.push(' ');
+        words.push_str(suffix);
+    }
+    Some((words, uses_next))
+}
+
+fn is_am_pm(word: &str) -> bool {
+    matches!(
+        word.to_lowercase().as_str(),
+        "am" | "pm" | "a.m" | "p.m" | "a.m." | "p.m."
+    )
+}
+
+/// "2026-10-17" is "October seventeenth, twenty twenty-six"
+fn iso_date(core: &str) -> Option<String> {
+    let mut parts = core.split('-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let month = MONTHS.get(month.parse::<usize>().ok()?.checked_sub(1)?)?;
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    Some(format!(
+        "{month} {}, {}",
+        ordinal(&cardinal(day)),
+        plain_number_words(year)?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(cases: &[(&str, &str)]) {
+        for (input, expected) in cases {
+            assert_eq!(normalize(input), *expected, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn strips_markdown() {
+        check(&[
+            ("**Turbo boost** is *ready*.", "Turbo boost is ready."),
+            ("## Status report", "Status report"),
+            ("- Scanner online", "Scanner online"),
+            ("* Engine hot", "Engine hot"),
+            ("2. Eject seat", "Eject seat"),
+            ("> Michael, be careful.", "Michael, be careful."),
+            ("Run `reboot` now.", "Run reboot now."),
+            (
+                "See [the manual](https://example.com/manual).",
+                "See the manual.",
+            ),
+            ("![KITT](kitt.png) is here.", "KITT is here."),
+            ("---", ""),
+            ("| Speed | 88 |", "Speed eighty-eight"),
+            ("~~Wrong~~ right", "Wrong right"),
+        ]);
+    }
+
+    #[test]
+    fn skips_code_blocks() {
+        check(&[(
+            "Try this:\n```rust\nfn main() {}\n```\nDone.",
+            "Try this: Done.",
+        )]);
+
+        // the voice loop normalizes sentence by sentence
+        let mut normalizer = TextNormalizer::default();
+        assert_eq!(normalizer.normalize("```"), "");
+        assert_eq!(normalizer.normalize("let speed = 88;"), "");
+        assert_eq!(normalizer.normalize("```"), "");
+        assert_eq!(normalizer.normalize("That is all."), "That is all.");
+    }
+
+    #[test]
+    fn removes_emojis() {
+        check(&[
+            ("Hello Michael 😀!", "Hello Michael!"),
+            ("Fasten your seatbelt 🚗💨", "Fasten your seatbelt"),
+            ("Good job 👍🏽.", "Good job."),
+            ("Mission complete ✅", "Mission complete"),
+            ("Family 👨‍👩‍👧 time", "Family time"),
+        ]);
+    }
+
+    #[test]
+    fn expands_numbers() {
+        check(&[
+            ("I have 3 ideas.", "I have three ideas."),
+            ("It is 42.", "It is forty-two."),
+            (
+                "That is 115 percent.",
+                "That is one hundred fifteen percent.",
+            ),
+            (
+                "About 1,250,000 cars.",
+                "About one million two hundred fifty thousand cars.",
+            ),
+            ("Pi is 3.14.", "Pi is three point one four."),
+            ("It is -5 outside.", "It is minus five outside."),
+            ("Built in 1982.", "Built in nineteen eighty-two."),
+            ("Music of the 1980s.", "Music of the nineteen eighties."),
+            ("Cars of the 1900s.", "Cars of the nineteen hundreds."),
+            ("Back in the 80's.", "Back in the eighties."),
+            ("Since 1905.", "Since nineteen oh five."),
+            ("In 2026 we drive.", "In twenty twenty-six we drive."),
+            ("In 2005 we drove.", "In two thousand five we drove."),
+            ("Exactly 10000 miles.", "Exactly ten thousand miles."),
+            ("You are 1st.", "You are first."),
+            (
+                "The 22nd and 33rd floor.",
+                "The twenty-second and thirty-third floor.",
+            ),
+            ("Only 50% left.", "Only fifty percent left."),
+            ("Wait 10-20 minutes.", "Wait ten to twenty minutes."),
+            ("A 24-hour drive.", "A twenty-four hour drive."),
+            ("You are #1.", "You are number one."),
+            ("Room No. 5.", "Room number five."),
+        ]);
+    }
+
+    #[test]
+    fn expands_dates_and_times() {
+        check(&[
+            (
+                "Today is 2026-10-17.",
+                "Today is October seventeenth, twenty twenty-six.",
+            ),
+            (
+                "On October 17 we leave.",
+                "On October seventeenth we leave.",
+            ),
+            (
+                "Since Jan. 3, 2024.",
+                "Since Jan. third, twenty twenty-four.",
+            ),
+            ("It is 14:30.", "It is fourteen thirty."),
+            ("It is 9:05.", "It is nine oh five."),
+            ("At 10:00 we start.", "At ten o'clock we start."),
+            ("Meet me at 3pm.", "Meet me at three p.m."),
+            (
+                "Meet me at 3:45 PM today.",
+                "Meet me at three forty-five p.m. today.",
+            ),
+            ("Wake me at 7 a.m.", "Wake me at seven a.m."),
+            ("The score was 25:70.", "The score was 25:70."),
+        ]);
+    }
+
+    #[test]
+    fn expands_currencies() {
+        check(&[
+            ("It costs $5.", "It costs five dollars."),
+            ("Only $1!", "Only one dollar!"),
+            (
+                "That is $3.50 please.",
+                "That is three dollars and fifty cents please.",
+            ),
+            ("Just $0.99.", "Just ninety-nine cents."),
+            ("It was €20 (cheap).", "It was twenty euros (cheap)."),
+            ("It costs 15€.", "It costs fifteen euros."),
+            (
+                "Rent is £1,200.",
+                "Rent is one thousand two hundred pounds.",
+            ),
+            (
+                "Worth $2.5 million.",
+                "Worth two point five million dollars.",
+            ),
+            ("Sushi for ¥800.", "Sushi for eight hundred yen."),
+        ]);
+    }
+
+    #[test]
+    fn expands_units() {
+        check(&[
+            ("Drive at 88 mph.", "Drive at eighty-eight miles per hour."),
+            (
+                "Top speed 300km/h.",
+                "Top speed three hundred kilometers per hour.",
+            ),
+            ("Only 1 km left.", "Only one kilometer left."),
+            ("It weighs 2.5kg.", "It weighs two point five kilograms."),
+            ("We have 4 GB left.", "We have four gigabytes left."),
+            (
+                "It is 21°C inside.",
+                "It is twenty-one degrees Celsius inside.",
+            ),
+            (
+                "It is 70 °F outside.",
+                "It is seventy degrees Fahrenheit outside.",
+            ),
+            ("Wait 5 min.", "Wait five minutes."),
+            ("It took 5s.", "It took five seconds."),
+            ("Add 5 g of salt.", "Add five grams of salt."),
+            ("A 3 GHz chip.", "A three gigahertz chip."),
+            ("Switch to 5G now.", "Switch to 5G now."),
+            ("We have 4 G left.", "We have four G left."),
+        ]);
+    }
+
+    #[test]
+    fn expands_abbreviations() {
+        check(&[
+            ("Mr. Knight is here.", "Mister Knight is here."),
+            ("Call Dr. Miles.", "Call Doctor Miles."),
+            ("Tools, e.g. a wrench.", "Tools, for example a wrench."),
+            ("Cars, bikes, etc.", "Cars, bikes, et cetera."),
+            ("KITT vs. KARR.", "KITT versus KARR."),
+            ("Go to St. Louis.", "Go to Saint Louis."),
+            ("It is on Main St. today.", "It is on Main Street today."),
+            ("Michael & Devon", "Michael and Devon"),
+        ]);
+    }
+
+    #[test]
+    fn shortens_urls() {
+        check(&[
+            (
+                "Visit https://www.knightindustries.com/kitt?id=2.",
+                "Visit knightindustries dot com.",
+            ),
+            (
+                "See www.example.org for more.",
+                "See example dot org for more.",
+            ),
+            ("Go to example.com.", "Go to example dot com."),
+            (
+                "Mail devon@foundation.org today.",
+                "Mail devon at foundation dot org today.",
+            ),
+        ]);
+    }
+}
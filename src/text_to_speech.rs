@@ -1,109 +1,181 @@
+use crate::{
+    config::{model_path, KittenModel, KokoroModel, MatchaModel, TtsBackend, TtsConfig},
+    error::ModelError,
+};
+
 use sherpa_rs::tts::{
-    KittenTts, KittenTtsConfig, KokoroTts, KokoroTtsConfig, MatchaTts, MatchaTtsConfig,
+    KittenTts, KittenTtsConfig, KokoroTts, KokoroTtsConfig, MatchaTts, MatchaTtsConfig, TtsAudio,
 };
 
-#[allow(unused)]
-pub enum TextToSpeech {
-    Matcha {
-        tts: MatchaTts,
-        voice_id: i32,
-        sample_rate: u32,
-    },
-    Kitten {
-        tts: KittenTts,
-        voice_id: i32,
-        sample_rate: u32,
-    },
-    Kokoro {
-        tts: KokoroTts,
-        voice_id: i32,
-        sample_rate: u32,
-    },
+/// Said instead of a sentence that could not be synthesized
+pub const FALLBACK_PHRASE: &str = "Sorry, I could not say that.";
+
+/// The models don't tell their sample rate, so it is taken from this test sentence
+const PROBE_TEXT: &str = "Hi.";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TextToSpeechError {
+    #[error(transparent)]
+    Model(#[from] ModelError),
+    #[error("Speech synthesis failed: {0}")]
+    Synthesis(String),
+    #[error("The TTS model reported an invalid sample rate of 0 Hz")]
+    InvalidSampleRate,
+    #[error("The TTS model generated audio with {actual} Hz instead of {expected} Hz")]
+    SampleRateChanged { expected: u32, actual: u32 },
+}
+
+enum Model {
+    Matcha(MatchaTts),
+    Kitten(KittenTts),
+    Kokoro(KokoroTts),
+    /// A beep per character, for tests without a model
+    #[cfg(test)]
+    Beep,
+}
+
+pub struct TextToSpeech {
+    model: Model,
+    voice_id: i32,
+    num_voices: i32,
+    sample_rate: u32,
 }
 
-#[allow(unused)]
 impl TextToSpeech {
-    pub fn new_matcha(voice_id: i32) -> Self {
+    /// Loads the model of the configured backend and synthesizes a test sentence
+    /// to find out its sample rate
+    pub fn new(config: &TtsConfig) -> Result<Self, TextToSpeechError> {
+        let model = match config.backend {
+            TtsBackend::Matcha => Self::load_matcha(&config.matcha),
+            TtsBackend::Kitten => Self::load_kitten(&config.kitten),
+            TtsBackend::Kokoro => Self::load_kokoro(&config.kokoro),
+        }?;
+        let mut tts = Self {
+            model,
+            voice_id: config.voice,
+            // the models do not tell how many speakers they have
+            num_voices: config.num_voices(),
+            sample_rate: 0,
+        };
+        tts.sample_rate = tts.synthesize(PROBE_TEXT)?.sample_rate;
+        if tts.sample_rate == 0 {
+            return Err(TextToSpeechError::InvalidSampleRate);
+        }
+        Ok(tts)
+    }
+
+    /// Speaks 10 samples per character at 16 kHz
+    #[cfg(test)]
+    pub fn beep() -> Self {
+        Self {
+            model: Model::Beep,
+            voice_id: 0,
+            num_voices: 1,
+            sample_rate: 16000,
+        }
+    }
+
+    fn load_matcha(model: &MatchaModel) -> Result<Model, ModelError> {
+        ModelError::check_files(
+            "matcha",
+            [
+                model.acoustic_model.as_path(),
+                &model.vocoder,
+                &model.tokens,
+                &model.data_dir,
+            ],
+        )?;
         let config = MatchaTtsConfig {
-            acoustic_model: "./matcha-icefall-en_US-ljspeech/model-steps-3.onnx".into(),
-            vocoder: "./hifigan_v2.onnx".into(),
-            tokens: "./matcha-icefall-en_US-ljspeech/tokens.txt".into(),
-            data_dir: "./matcha-icefall-en_US-ljspeech/espeak-ng-data".into(),
+            acoustic_model: model_path(&model.acoustic_model),
+            vocoder: model_path(&model.vocoder),
+            tokens: model_path(&model.tokens),
+            data_dir: model_path(&model.data_dir),
             ..Default::default()
         };
-        TextToSpeech::Matcha {
-            tts: MatchaTts::new(config),
-            voice_id,
-            sample_rate: 22050,
-        }
+        Ok(Model::Matcha(MatchaTts::new(config)))
     }
 
-    pub fn new_kitten(voice_id: i32) -> Self {
+    fn load_kitten(model: &KittenModel) -> Result<Model, ModelError> {
+        ModelError::check_files(
+            "kitten",
+            [
+                model.model.as_path(),
+                &model.voices,
+                &model.tokens,
+                &model.data_dir,
+            ],
+        )?;
         let config = KittenTtsConfig {
-            model: "./kitten-nano-en-v0_2-fp16/model.fp16.onnx".to_string(),
-            voices: "./kitten-nano-en-v0_2-fp16/voices.bin".into(),
-            tokens: "./kitten-nano-en-v0_2-fp16/tokens.txt".into(),
-            data_dir: "./kitten-nano-en-v0_2-fp16/espeak-ng-data".into(),
+            model: model_path(&model.model),
+            voices: model_path(&model.voices),
+            tokens: model_path(&model.tokens),
+            data_dir: model_path(&model.data_dir),
             length_scale: 1.0,
             ..Default::default()
         };
-        TextToSpeech::Kitten {
-            tts: KittenTts::new(config),
-            voice_id,
-            sample_rate: 24000,
-        }
+        Ok(Model::Kitten(KittenTts::new(config)))
     }
 
-    pub fn new_kokoro(voice_id: i32) -> Self {
+    fn load_kokoro(model: &KokoroModel) -> Result<Model, ModelError> {
+        ModelError::check_files(
+            "kokoro",
+            [
+                model.model.as_path(),
+                &model.voices,
+                &model.tokens,
+                &model.data_dir,
+            ],
+        )?;
         let config = KokoroTtsConfig {
-            model: "./kokoro-en-v0_19/model.onnx".to_string(),
-            voices: "./kokoro-en-v0_19/voices.bin".into(),
-            tokens: "./kokoro-en-v0_19/tokens.txt".into(),
-            data_dir: "./kokoro-en-v0_19/espeak-ng-data".into(),
+            model: model_path(&model.model),
+            voices: model_path(&model.voices),
+            tokens: model_path(&model.tokens),
+            data_dir: model_path(&model.data_dir),
             length_scale: 1.0,
             ..Default::default()
         };
-        TextToSpeech::Kokoro {
-            tts: KokoroTts::new(config),
-            voice_id,
-            sample_rate: 24000,
+        Ok(Model::Kokoro(KokoroTts::new(config)))
+    }
+
+    /// Speech for the text, with the sample rate of [`TextToSpeech::sample_rate`]
+    pub fn create(&mut self, text: &str) -> Result<Vec<f32>, TextToSpeechError> {
+        let audio = self.synthesize(text)?;
+        if audio.sample_rate != self.sample_rate {
+            return Err(TextToSpeechError::SampleRateChanged {
+                expected: self.sample_rate,
+                actual: audio.sample_rate,
+            });
         }
+        Ok(audio.samples)
     }
 
-    pub fn create(&mut self, text: &str) -> Vec<f32> {
-        let (result, sr) = match self {
-            TextToSpeech::Matcha {
-                tts,
-                voice_id,
-                sample_rate,
-            } => (tts.create(text, *voice_id as i32, 1.0), *sample_rate),
-            TextToSpeech::Kitten {
-                tts,
-                voice_id,
-                sample_rate,
-            } => (tts.create(text, *voice_id as i32, 1.0), *sample_rate),
-            TextToSpeech::Kokoro {
-                tts,
-                voice_id,
-                sample_rate,
-            } => (tts.create(text, *voice_id as i32, 1.0), *sample_rate),
+    fn synthesize(&mut self, text: &str) -> Result<TtsAudio, TextToSpeechError> {
+        let result = match &mut self.model {
+            Model::Matcha(tts) => tts.create(text, self.voice_id, 1.0),
+            Model::Kitten(tts) => tts.create(text, self.voice_id, 1.0),
+            Model::Kokoro(tts) => tts.create(text, self.voice_id, 1.0),
+            #[cfg(test)]
+            Model::Beep => {
+                return Ok(TtsAudio {
+                    samples: vec![0.5; 10 * text.chars().count()],
+                    sample_rate: 16000,
+                    duration: 0,
+                })
+            }
         };
-        if let Ok(audio) = result {
-            assert_eq!(
-                audio.sample_rate, sr,
-                "Model was set up with wrong sample rate!"
-            );
-            audio.samples
-        } else {
-            Vec::new()
-        }
+        result.map_err(|e| TextToSpeechError::Synthesis(e.to_string()))
+    }
+
+    /// Number of speakers of the model, the voice ids go from 0 to this minus 1
+    pub fn num_voices(&self) -> i32 {
+        self.num_voices
+    }
+
+    pub fn set_voice(&mut self, voice: i32) {
+        self.voice_id = voice;
     }
 
     pub fn sample_rate(&self) -> u32 {
-        match self {
-            TextToSpeech::Matcha { sample_rate, .. } => *sample_rate,
-            TextToSpeech::Kitten { sample_rate, .. } => *sample_rate,
-            TextToSpeech::Kokoro { sample_rate, .. } => *sample_rate,
-        }
+        self.sample_rate
     }
 }
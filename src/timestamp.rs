@@ -0,0 +1,77 @@
+//! Time stamps without a date library, always in UTC
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 1970-01-01 was a Thursday
+const WEEKDAYS: [&str; 7] = [
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+];
+
+const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// Milliseconds since the unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Formats milliseconds since the unix epoch as `YYYY-MM-DD HH:MM:SS` in UTC
+pub fn format_timestamp(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+
+    // civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Name of the day of the week of milliseconds since the unix epoch, in UTC
+pub fn weekday(millis: u64) -> &'static str {
+    WEEKDAYS[(millis / MILLIS_PER_DAY % 7) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps_in_utc() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_792_241_999_999), "2026-10-17 12:59:59");
+    }
+
+    #[test]
+    fn knows_the_weekday() {
+        assert_eq!(weekday(0), "Thursday");
+        assert_eq!(weekday(951_782_400_000), "Tuesday");
+        assert_eq!(weekday(1_792_241_999_999), "Saturday");
+    }
+}
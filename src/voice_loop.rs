@@ -0,0 +1,722 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::ControlFlow,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    answer_limit::{AnswerLimit, AnswerLimiter},
+    audio_backend::AudioBackend,
+    config::Config,
+    error::KnightRiderError,
+    intents::{Intent, IntentMatcher},
+    llama::{BlockingLlama, ChatMessage, HealthEvent, MessageMetadata},
+    shutdown::Shutdown,
+    speech_pipeline::{SentenceSplitter, SpeechPipeline, SpokenAnswer},
+    speech_to_text::{SpeechToText, Vad},
+    system_audio::SystemAudioError,
+    text_normalizer::{self, TextNormalizer},
+    text_to_speech::TextToSpeech,
+    wake_word::{WakeWord, WakeWordGate},
+};
+
+/// Volume change of "louder" and "quieter"
+const VOLUME_STEP: f32 = 0.2;
+/// KITT stays audible, "be quiet" is for silence
+const MIN_OUTPUT_GAIN: f32 = 0.1;
+/// Louder than this and the voice clips
+const MAX_OUTPUT_GAIN: f32 = 2.0;
+/// Longest time to wait for the last answer and the goodbye to be played
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+/// The loop wakes up this often without input, to notice health changes and signals
+const WAKE_UP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A question and the answer the user heard, for the transcript of a recording
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub question: ChatMessage,
+    /// None if the LLM failed to answer
+    pub answer: Option<ChatMessage>,
+    /// Length of the recorded question
+    pub question_duration: Duration,
+    /// Length of the spoken answer
+    pub answer_duration: Duration,
+    /// Time the TTS model took for the answer
+    pub tts_time: Duration,
+}
+
+/// The voice loop: listen, transcribe, ask the LLM and speak the answer
+pub struct VoiceLoop {
+    vad: Vad,
+    stt: SpeechToText,
+    wake_word: Option<WakeWordGate>,
+    // Synthesize speech in the background while the LLM is still generating
+    speech: SpeechPipeline,
+    llama: BlockingLlama,
+    tts_sample_rate: u32,
+    barge_in: bool,
+    answer_limit: AnswerLimit,
+    rephrase_long_answers: bool,
+    // What KITT said last, in case the user interrupts it
+    last_answer: SpokenAnswer,
+    // Voice commands that are handled without the LLM
+    intents: Option<IntentMatcher>,
+    personas: BTreeMap<String, String>,
+    // KITT only listens for commands until the user unmutes it
+    muted: bool,
+    output_gain: f32,
+    voice: i32,
+    num_voices: i32,
+    // Stop when this is requested, after saying goodbye
+    shutdown: Shutdown,
+    goodbye: String,
+    finish_answer: bool,
+    turns: Vec<Turn>,
+}
+
+impl VoiceLoop {
+    pub fn new(config: &Config, llama: BlockingLlama) -> Result<Self, KnightRiderError> {
+        let vad = Vad::new(&config.vad)?;
+        let stt = SpeechToText::new(&config.stt)?;
+        let tts = TextToSpeech::new(&config.tts)?;
+        let wake_word = if config.wake_word.enabled {
+            Some(WakeWordGate::new(
+                WakeWord::new(&config.wake_word)?,
+                config.wake_word.follow_up_window(),
+            ))
+        } else {
+            None
+        };
+        Ok(Self::with_models(config, llama, vad, stt, tts, wake_word))
+    }
+
+    /// Uses models that are already loaded, the model settings of `config` are ignored
+    fn with_models(
+        config: &Config,
+        llama: BlockingLlama,
+        vad: Vad,
+        stt: SpeechToText,
+        tts: TextToSpeech,
+        wake_word: Option<WakeWordGate>,
+    ) -> Self {
+        let mut personas = config.commands.personas.clone();
+        personas.insert("kitt".into(), config.llm.persona.clone());
+        let intents = config
+            .commands
+            .enabled
+            .then(|| IntentMatcher::new(personas.keys().map(String::as_str)));
+
+        Self {
+            vad,
+            stt,
+            wake_word,
+            tts_sample_rate: tts.sample_rate(),
+            num_voices: tts.num_voices(),
+            speech: SpeechPipeline::new(tts),
+            llama,
+            barge_in: config.audio.barge_in,
+            answer_limit: config.tts.answer_limit(),
+            rephrase_long_answers: config.tts.rephrase_long_answers,
+            last_answer: SpokenAnswer::default(),
+            intents,
+            personas,
+            muted: false,
+            output_gain: 1.0,
+            voice: config.tts.voice,
+            shutdown: Shutdown::default(),
+            goodbye: config.shutdown.goodbye.clone(),
+            finish_answer: config.shutdown.finish_answer,
+            turns: Vec::new(),
+        }
+    }
+
+    /// A voice loop with the test models, it hears `transcripts` one after the other
+    #[cfg(test)]
+    pub fn with_test_models(config: &Config, transcripts: &[&str]) -> Self {
+        let llama = BlockingLlama::new(&config.llm).unwrap();
+        let stt = SpeechToText::Scripted(transcripts.iter().map(|t| t.to_string()).collect());
+        Self::with_models(
+            config,
+            llama,
+            Vad::energy(),
+            stt,
+            TextToSpeech::beep(),
+            None,
+        )
+    }
+
+    /// Stops the loop when `shutdown` is requested
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        // an answer that is finished anyway is not worth stopping for
+        if !self.finish_answer {
+            self.llama.set_shutdown(shutdown.clone());
+        }
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Sample rate the audio backend has to deliver the input in
+    pub fn input_sample_rate(&self) -> u32 {
+        self.vad.sample_rate()
+    }
+
+    /// Sample rate of the audio that is sent to the audio backend
+    pub fn output_sample_rate(&self) -> u32 {
+        self.tts_sample_rate
+    }
+
+    /// Every question that was sent to the LLM so far, with its answer
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    /// Runs until the input of the audio backend is finished, which is never for a sound card,
+    /// until a shutdown is requested or until the audio backend fails
+    pub fn run(&mut self, audio: &mut impl AudioBackend) -> Result<(), SystemAudioError> {
+        audio.set_output_gain(self.output_gain);
+
+        // Say something so we know the system is ready
+        println!("K.I.T.T. is ready for your requests..");
+        self.say(audio, "All systems ready!");
+
+        // Main AI Loop
+        loop {
+            if let Some(error) = audio.take_error() {
+                return Err(error);
+            }
+            if let Some(event) = self.llama.health_event() {
+                self.announce_health(audio, event);
+            }
+            if self.shutdown.requested() {
+                self.shut_down(audio);
+                return Ok(());
+            }
+            // sleep until the next window is recorded
+            let window_ready = audio.wait_for_samples(self.vad.window_size(), WAKE_UP_INTERVAL);
+            let input_finished = !window_ready && audio.input_finished();
+
+            if input_finished {
+                // the rest of the input does not fill a whole window
+                let mut input_audio = audio.receive_audio(self.vad.window_size());
+                if !input_audio.is_empty() {
+                    input_audio.resize(self.vad.window_size(), 0.0);
+                    self.vad.process_audio(input_audio);
+                }
+                // the last words of a file are not followed by silence
+                self.vad.flush();
+            } else if window_ready {
+                let input_audio = audio.receive_audio(self.vad.window_size());
+
+                self.vad.process_audio(input_audio);
+
+                // the user starts talking while KITT is still playing the last answer
+                if self.barge_in
+                    && !self.last_answer.is_empty()
+                    && audio.is_playing()
+                    && self.vad.is_speaking()
+                {
+                    self.interrupt(audio);
+                }
+            } else {
+                continue;
+            }
+
+            while self.vad.speech_detected() {
+                // do not accept new speech input while processing
+                if !self.barge_in {
+                    audio.set_ready_to_receive(false);
+                }
+
+                let speech_segment = self.vad.speech_segment();
+                let speech_duration = Duration::from_secs_f64(
+                    speech_segment.len() as f64 / self.vad.sample_rate() as f64,
+                );
+
+                // ignore speech that is not meant for KITT
+                if self
+                    .wake_word
+                    .as_mut()
+                    .is_none_or(|wake_word| wake_word.accept(&speech_segment))
+                {
+                    let start = Instant::now();
+                    let transcript = self.stt.transcribe(&speech_segment);
+                    let metadata = MessageMetadata {
+                        stt_model: Some(self.stt.model_name().to_string()),
+                        stt_ms: Some(start.elapsed().as_millis() as u64),
+                        ..Default::default()
+                    };
+
+                    let intent = self
+                        .intents
+                        .as_ref()
+                        .and_then(|intents| intents.match_intent(&transcript));
+                    match intent {
+                        _ if transcript.is_empty() => {}
+                        Some(intent) => self.execute(audio, &transcript, intent),
+                        None if self.muted => println!("User (muted): {transcript}"),
+                        None => self.answer(
+                            audio,
+                            ChatMessage::user(transcript).with_metadata(metadata),
+                            speech_duration,
+                        ),
+                    }
+                }
+                self.vad.delete_speech_segment();
+            }
+            // now new input speech can be accepted
+            audio.set_ready_to_receive(true);
+
+            if input_finished {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Streams the answer of the LLM and speaks every sentence as soon as it is generated
+    fn answer(
+        &mut self,
+        audio: &mut impl AudioBackend,
+        question: ChatMessage,
+        question_duration: Duration,
+    ) {
+        println!("User: {}", question.content);
+        let mut turn = Turn {
+            question: question.clone(),
+            answer: None,
+            question_duration,
+            answer_duration: Duration::ZERO,
+            tts_time: Duration::ZERO,
+        };
+        let mut num_answer_samples = 0;
+
+        let Self {
+            vad,
+            speech,
+            llama,
+            barge_in,
+            answer_limit,
+            rephrase_long_answers,
+            last_answer,
+            shutdown,
+            finish_answer,
+            ..
+        } = self;
+        let (barge_in, answer_limit, rephrase, finish_answer) = (
+            *barge_in,
+            *answer_limit,
+            *rephrase_long_answers,
+            *finish_answer,
+        );
+
+        let mut splitter = SentenceSplitter::default();
+        let mut normalizer = TextNormalizer::default();
+        let mut limiter = AnswerLimiter::new(answer_limit);
+        // the sentences that fit the limit, when rephrasing they are only spoken if all of them fit
+        let mut fitting = Vec::new();
+        let mut interrupted = false;
+        let mut truncated = false;
+        *last_answer = SpokenAnswer::default();
+
+        // Speak every sentence as soon as it is generated, until the answer is too long
+        let mut speak_sentence = |sentence: String, speech: &mut SpeechPipeline| {
+            // spell out numbers and drop markdown, so it doesn't say "asterisk" all the time
+            let sentence = normalizer.normalize(&sentence);
+            if sentence.is_empty() {
+                return ControlFlow::Continue(());
+            }
+            if let Some(sentence) = limiter.limit(&sentence) {
+                if !rephrase {
+                    speech.speak(sentence.as_str());
+                }
+                fitting.push(sentence);
+            }
+            if limiter.is_truncated() {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        };
+
+        // print the answer while it is generated
+        print!("KITT: ");
+        let result = llama.chat_stream(question, |delta| {
+            print!("{delta}");
+            let _ = std::io::stdout().flush();
+
+            for sentence in splitter.push(delta) {
+                if speak_sentence(sentence, speech).is_break() {
+                    truncated = true;
+                    // no need to generate what is not spoken, unless the whole answer is
+                    // needed to rephrase it
+                    if !rephrase {
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            while let Some(generated_speech) = speech.try_receive_audio() {
+                audio.send_audio(&generated_speech.audio);
+                num_answer_samples += generated_speech.audio.len();
+                turn.tts_time += generated_speech.synthesis_time;
+                last_answer.push(generated_speech.text, generated_speech.audio.len());
+            }
+
+            if barge_in && user_is_speaking(audio, vad) {
+                interrupted = true;
+                return ControlFlow::Break(());
+            }
+            if shutdown.requested() && !finish_answer {
+                return ControlFlow::Break(());
+            }
+            ControlFlow::Continue(())
+        });
+        println!();
+
+        // KITT is stopped, it finishes the answer or says nothing more at all
+        let stopping = shutdown.requested();
+        if stopping && !finish_answer {
+            interrupted = true;
+        }
+
+        match result {
+            Ok(_) if interrupted || truncated => {}
+            Ok(_) => {
+                if let Some(sentence) = splitter.finish() {
+                    truncated = speak_sentence(sentence, speech).is_break();
+                }
+            }
+            Err(_) => {
+                eprintln!("Error: Llama failed to produce an answer...");
+                speech.speak(
+                    "Oh no, I could not produce an answer, there must be an issue with the connection.",
+                );
+            }
+        }
+        if truncated {
+            println!("Warning: Answer too long, cutting off end...");
+        }
+
+        if rephrase && !interrupted {
+            if truncated && !stopping {
+                match llama.shorten_last_answer(answer_limit.max_chars()) {
+                    Ok(shorter) => {
+                        println!("KITT (shorter): {shorter}");
+                        fitting = vec![AnswerLimiter::new(answer_limit).limit_text(&shorter)];
+                    }
+                    Err(_) => eprintln!("Error: Llama failed to shorten the answer..."),
+                }
+            }
+            for sentence in &fitting {
+                speech.speak(sentence.as_str());
+            }
+        }
+        // the history has what the user hears, so the LLM knows it was cut off
+        if truncated {
+            llama.truncate_last_answer(&fitting.join(" "));
+        }
+
+        // queue the rest of the answer as soon as it is synthesized
+        while !interrupted {
+            let generated_speech = if barge_in {
+                speech.receive_audio_timeout(Duration::from_millis(10))
+            } else {
+                speech.receive_audio()
+            };
+            match generated_speech {
+                Some(generated_speech) => {
+                    audio.send_audio(&generated_speech.audio);
+                    num_answer_samples += generated_speech.audio.len();
+                    turn.tts_time += generated_speech.synthesis_time;
+                    last_answer.push(generated_speech.text, generated_speech.audio.len());
+                }
+                None if speech.is_done() => break,
+                None => interrupted = user_is_speaking(audio, vad),
+            }
+        }
+
+        if result.is_ok() {
+            turn.answer = llama.last_answer().cloned();
+        }
+        turn.answer_duration =
+            Duration::from_secs_f64(num_answer_samples as f64 / self.tts_sample_rate as f64);
+        self.turns.push(turn);
+
+        if interrupted {
+            self.interrupt(audio);
+        }
+
+        // the user can ask a follow-up question without the wake word
+        if let Some(wake_word) = &mut self.wake_word {
+            wake_word.keep_awake();
+        }
+    }
+
+    /// Carries out a voice command and confirms it
+    fn execute(&mut self, audio: &mut impl AudioBackend, transcript: &str, intent: Intent) {
+        println!("User: {transcript}");
+
+        let reply = match intent {
+            Intent::VolumeUp => self.set_volume(audio, self.output_gain + VOLUME_STEP),
+            Intent::VolumeDown => self.set_volume(audio, self.output_gain - VOLUME_STEP),
+            Intent::SetVolume(percent) => self.set_volume(audio, percent as f32 / 100.0),
+            Intent::NextVoice if self.num_voices == 1 => "I only have this one voice.".into(),
+            Intent::NextVoice => self.set_voice((self.voice + 1) % self.num_voices),
+            Intent::SetVoice(voice) if (0..self.num_voices).contains(&voice) => {
+                self.set_voice(voice)
+            }
+            Intent::SetVoice(_) => format!("I only have voices 0 to {}.", self.num_voices - 1),
+            Intent::SwitchPersona(name) => match self.personas.get(&name) {
+                Some(persona) => {
+                    self.llama.set_persona(persona);
+                    format!("Switched to the {name} persona.")
+                }
+                None => format!("I do not know the {name} persona."),
+            },
+            Intent::Mute => {
+                self.muted = true;
+                "I will be quiet until you say start listening.".into()
+            }
+            Intent::Unmute => {
+                self.muted = false;
+                "I am listening again.".into()
+            }
+            Intent::ClearHistory => {
+                self.llama.clear_history();
+                "I forgot everything we talked about.".into()
+            }
+        };
+
+        println!("KITT: {reply}");
+        // the command is not part of the conversation, there is no answer to interrupt
+        self.last_answer = SpokenAnswer::default();
+        self.say(audio, &reply);
+
+        if let Some(wake_word) = &mut self.wake_word {
+            wake_word.keep_awake();
+        }
+    }
+
+    /// Tells the user that llama-server went away or came back
+    fn announce_health(&mut self, audio: &mut impl AudioBackend, event: HealthEvent) {
+        let message = match event {
+            HealthEvent::Unavailable => "Warning, I lost the connection to my language model.",
+            HealthEvent::Recovered => "My language model is back online.",
+        };
+        println!("KITT: {message}");
+        if !self.muted {
+            self.last_answer = SpokenAnswer::default();
+            self.say(audio, message);
+        }
+    }
+
+    /// Stops listening, says goodbye and saves the conversation.
+    ///
+    /// Returns once everything was played, so the sound card is not stopped mid-word.
+    fn shut_down(&mut self, audio: &mut impl AudioBackend) {
+        println!("Shutting down..");
+        audio.set_ready_to_receive(false);
+        if !self.finish_answer && audio.is_playing() && !self.last_answer.is_empty() {
+            self.interrupt(audio);
+        }
+        self.last_answer = SpokenAnswer::default();
+
+        if !self.goodbye.is_empty() {
+            let goodbye = self.goodbye.clone();
+            println!("KITT: {goodbye}");
+            self.say(audio, &goodbye);
+        }
+        let start = Instant::now();
+        while audio.is_playing() && start.elapsed() < SHUTDOWN_TIMEOUT {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        if let Err(e) = self.llama.sync_session() {
+            eprintln!("Error: {e}");
+        }
+    }
+
+    fn set_volume(&mut self, audio: &impl AudioBackend, gain: f32) -> String {
+        self.output_gain = gain.clamp(MIN_OUTPUT_GAIN, MAX_OUTPUT_GAIN);
+        audio.set_output_gain(self.output_gain);
+        format!("Volume {} percent.", (self.output_gain * 100.0).round())
+    }
+
+    fn set_voice(&mut self, voice: i32) -> String {
+        self.voice = voice;
+        self.speech.set_voice(voice);
+        format!("This is voice {voice}.")
+    }
+
+    /// Speaks a short text completely before going on
+    fn say(&mut self, audio: &mut impl AudioBackend, text: &str) {
+        self.speech.speak(text_normalizer::normalize(text));
+        while let Some(generated_speech) = self.speech.receive_audio() {
+            audio.send_audio(&generated_speech.audio);
+        }
+    }
+
+    /// Stops KITT mid-sentence and remembers how much of the answer the user heard
+    fn interrupt(&mut self, audio: &impl AudioBackend) {
+        let heard = self.last_answer.heard_text(audio.num_samples_queued());
+        audio.flush_output();
+        self.speech.cancel();
+        self.llama.interrupt_last_answer(&heard);
+        // the transcript has what the user heard as well
+        if let Some(answer) = self.turns.last_mut().and_then(|turn| turn.answer.as_mut()) {
+            if let Some(heard) = self.llama.last_answer() {
+                answer.clone_from(heard);
+            }
+        }
+        self.last_answer = SpokenAnswer::default();
+        println!("(interrupted)");
+    }
+}
+
+/// Feeds all available input into the VAD and checks if the user started talking
+fn user_is_speaking(audio: &mut impl AudioBackend, vad: &mut Vad) -> bool {
+    while audio.num_samples_available() >= vad.window_size() {
+        let input_audio = audio.receive_audio(vad.window_size());
+        vad.process_audio(input_audio);
+    }
+    vad.is_speaking()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio_backend::LoopbackAudio,
+        llama::mock_server::{MockLlamaServer, MockResponse},
+    };
+
+    /// Samples of the test TTS model per character
+    const BEEP_PER_CHAR: usize = 10;
+
+    fn config(server: &MockLlamaServer) -> Config {
+        let mut config = Config::default();
+        config.llm.url = server.url().to_string();
+        config.llm.startup_timeout = 0;
+        config.llm.health_interval = 0;
+        config
+    }
+
+    fn voice_loop(server: &MockLlamaServer, transcripts: &[&str]) -> VoiceLoop {
+        VoiceLoop::with_test_models(&config(server), transcripts)
+    }
+
+    /// A second of "speech" for every question, with silence in between
+    fn record_questions(audio: &mut LoopbackAudio, num_questions: usize) {
+        let silence = vec![0.0; 16000];
+        let speech: Vec<f32> = (0..16000)
+            .map(|n| if n % 2 == 0 { 0.1 } else { -0.1 })
+            .collect();
+        audio.push_input(&silence);
+        for _ in 0..num_questions {
+            audio.push_input(&speech);
+            audio.push_input(&silence);
+        }
+        audio.close_input();
+    }
+
+    fn last_question(request: &serde_json::Value) -> &str {
+        let messages = request["messages"].as_array().unwrap();
+        messages.last().unwrap()["content"].as_str().unwrap()
+    }
+
+    #[test]
+    fn answers_a_question_through_the_audio_backend() {
+        let server = MockLlamaServer::start();
+        server.push_response(MockResponse::Stream(vec![
+            "Turbo boost".into(),
+            " is ready.".into(),
+        ]));
+
+        let mut voice_loop = voice_loop(&server, &["What is your top speed?"]);
+        let mut audio = LoopbackAudio::default();
+        record_questions(&mut audio, 1);
+        voice_loop.run(&mut audio).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(last_question(&requests[0]), "What is your top speed?");
+
+        let spoken = "All systems ready!".len() + "Turbo boost is ready.".len();
+        assert_eq!(audio.take_output().len(), spoken * BEEP_PER_CHAR);
+    }
+
+    #[test]
+    fn long_answer_is_rephrased_after_it_is_complete() {
+        let server = MockLlamaServer::start();
+        server.push_response(MockResponse::Stream(vec![
+            "I am the Knight Industries Two Thousand.".into(),
+            " My friends call me KITT.".into(),
+        ]));
+        server.push_response(MockResponse::Chat("I am KITT.".into()));
+
+        let mut config = config(&server);
+        config.tts.max_answer_chars = 30;
+        config.tts.rephrase_long_answers = true;
+        let mut voice_loop = VoiceLoop::with_test_models(&config, &["Who are you?"]);
+        let mut audio = LoopbackAudio::default();
+        record_questions(&mut audio, 1);
+        voice_loop.run(&mut audio).unwrap();
+
+        // the LLM shortens the whole answer, not the part before the limit
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1]["messages"][2]["content"],
+            "I am the Knight Industries Two Thousand. My friends call me KITT."
+        );
+
+        let answer = voice_loop.turns()[0].answer.as_ref().unwrap();
+        assert_eq!(answer.content, "I am KITT.");
+        assert!(answer.truncated);
+        assert!(!answer.interrupted);
+        let spoken = "All systems ready!".len() + "I am KITT.".len();
+        assert_eq!(audio.take_output().len(), spoken * BEEP_PER_CHAR);
+    }
+
+    #[test]
+    fn finishes_the_answer_when_stopped_in_the_middle() {
+        let server = MockLlamaServer::start();
+        server.push_response(MockResponse::SlowStream(
+            Duration::from_millis(300),
+            vec![
+                "Turbo boost is ready.".into(),
+                " The scanner".into(),
+                " is online.".into(),
+            ],
+        ));
+
+        let shutdown = Shutdown::default();
+        let mut voice_loop =
+            voice_loop(&server, &["Status report?"]).with_shutdown(shutdown.clone());
+        let mut audio = LoopbackAudio::default();
+        record_questions(&mut audio, 1);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while server.requests().is_empty() {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                // between the first and the second delta
+                std::thread::sleep(Duration::from_millis(450));
+                shutdown.request(libc::SIGTERM);
+            });
+            voice_loop.run(&mut audio).unwrap();
+        });
+
+        let answer = voice_loop.turns()[0].answer.as_ref().unwrap();
+        assert_eq!(
+            answer.content,
+            "Turbo boost is ready. The scanner is online."
+        );
+        assert!(!answer.interrupted);
+        let spoken = "All systems ready!".len()
+            + "Turbo boost is ready.".len()
+            + "The scanner is online.".len()
+            + "Goodbye, Michael.".len();
+        assert_eq!(audio.take_output().len(), spoken * BEEP_PER_CHAR);
+    }
+}
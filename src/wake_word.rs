@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+
+use sherpa_rs::keyword_spot::{KeywordSpot, KeywordSpotConfig};
+
+use crate::{
+    config::{model_path, WakeWordConfig},
+    error::ModelError,
+};
+
+/// Finds the wake word in a speech segment
+pub trait KeywordDetector {
+    /// Returns the spotted keyword, if the segment contains one
+    fn detect(&mut self, speech_segment: &[f32]) -> Option<String>;
+}
+
+/// Spots the wake word ("KITT" by default) in a speech segment.
+///
+/// The keywords file contains one tokenized keyword per line, it can be created from plain text
+/// with `sherpa-onnx-cli text2token` (see README).
+pub struct WakeWord {
+    spotter: KeywordSpot,
+    sample_rate: u32,
+}
+
+impl WakeWord {
+    pub fn new(config: &WakeWordConfig) -> Result<Self, ModelError> {
+        ModelError::check_files(
+            "wake word",
+            [
+                config.encoder.as_path(),
+                &config.decoder,
+                &config.joiner,
+                &config.tokens,
+                &config.keywords,
+            ],
+        )?;
+        let sample_rate = 16000;
+        let spot_config = KeywordSpotConfig {
+            zipformer_encoder: model_path(&config.encoder),
+            zipformer_decoder: model_path(&config.decoder),
+            zipformer_joiner: model_path(&config.joiner),
+            tokens: model_path(&config.tokens),
+            keywords: model_path(&config.keywords),
+            max_active_paths: 4,
+            num_trailing_blanks: 1,
+            keywords_score: 1.0,
+            keywords_threshold: 0.25,
+            sample_rate: sample_rate as i32,
+            feature_dim: 80,
+            ..Default::default()
+        };
+        let spotter = KeywordSpot::new(spot_config)
+            .map_err(ModelError::load("wake word", &config.encoder))?;
+        Ok(Self {
+            spotter,
+            sample_rate,
+        })
+    }
+}
+
+impl KeywordDetector for WakeWord {
+    fn detect(&mut self, speech_segment: &[f32]) -> Option<String> {
+        // the spotter is streaming, some silence at the end makes sure the keyword is emitted
+        let mut audio = speech_segment.to_vec();
+        audio.resize(audio.len() + self.sample_rate as usize / 2, 0.0);
+
+        match self.spotter.extract_keyword(audio, self.sample_rate) {
+            Ok(keyword) => keyword,
+            Err(e) => {
+                eprintln!("Keyword spotting failed: {e}");
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeState {
+    /// Waiting for the wake word, speech is ignored
+    Idle,
+    /// The wake word was heard, speech is sent to KITT until the follow-up window is over
+    Awake { until: Instant },
+}
+
+/// Decides which speech segments are sent to the speech to text model.
+///
+/// Idle → awake after the wake word → listening for the follow-up window → idle.
+pub struct WakeWordGate {
+    wake_word: Box<dyn KeywordDetector>,
+    follow_up_window: Duration,
+    state: WakeState,
+}
+
+impl WakeWordGate {
+    pub fn new(wake_word: impl KeywordDetector + 'static, follow_up_window: Duration) -> Self {
+        Self {
+            wake_word: Box::new(wake_word),
+            follow_up_window,
+            state: WakeState::Idle,
+        }
+    }
+
+    /// Returns true if the speech segment is meant for KITT and should be transcribed
+    pub fn accept(&mut self, speech_segment: &[f32]) -> bool {
+        if let WakeState::Awake { until } = self.state {
+            if Instant::now() <= until {
+                return true;
+            }
+            println!("(KITT stopped listening)");
+            self.state = WakeState::Idle;
+        }
+
+        if let Some(keyword) = self.wake_word.detect(speech_segment) {
+            println!("(Wake word \"{keyword}\" detected)");
+            self.keep_awake();
+            return true;
+        }
+        false
+    }
+
+    /// Starts the follow-up window again, should be called after KITT answered
+    pub fn keep_awake(&mut self) {
+        self.state = WakeState::Awake {
+            until: Instant::now() + self.follow_up_window,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hears the wake word in loud segments
+    struct LoudWakeWord;
+
+    impl KeywordDetector for LoudWakeWord {
+        fn detect(&mut self, speech_segment: &[f32]) -> Option<String> {
+            speech_segment
+                .iter()
+                .any(|sample| sample.abs() > 0.5)
+                .then(|| "KITT".to_string())
+        }
+    }
+
+    const WAKE_WORD: [f32; 4] = [0.0, 0.9, -0.9, 0.0];
+    const QUESTION: [f32; 4] = [0.0, 0.1, -0.1, 0.0];
+
+    #[test]
+    fn accepts_speech_after_the_wake_word() {
+        let mut gate = WakeWordGate::new(LoudWakeWord, Duration::from_secs(10));
+        assert!(!gate.accept(&QUESTION));
+        assert_eq!(gate.state, WakeState::Idle);
+
+        assert!(gate.accept(&WAKE_WORD));
+        assert!(matches!(gate.state, WakeState::Awake { .. }));
+    }
+
+    #[test]
+    fn accepts_follow_up_within_the_window() {
+        let mut gate = WakeWordGate::new(LoudWakeWord, Duration::from_secs(10));
+        assert!(gate.accept(&WAKE_WORD));
+        assert!(gate.accept(&QUESTION));
+        gate.keep_awake();
+        assert!(gate.accept(&QUESTION));
+    }
+
+    #[test]
+    fn goes_back_to_idle_after_the_window() {
+        let mut gate = WakeWordGate::new(LoudWakeWord, Duration::from_millis(50));
+        assert!(gate.accept(&WAKE_WORD));
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert!(!gate.accept(&QUESTION));
+        assert_eq!(gate.state, WakeState::Idle);
+        // the wake word starts it again
+        assert!(gate.accept(&WAKE_WORD));
+    }
+}